@@ -0,0 +1,296 @@
+//! Latency-compensated streaming on top of [Soxr]
+use crate::{
+    datatype::{Datatype, Sample},
+    error_handling::{Error, ErrorType, Result},
+    soxr::Soxr,
+    spec::{IOSpec, QualitySpec},
+};
+
+/// Number of silent input frames on each side of the impulse used to measure latency
+const PROBE_FRAMES: usize = 16384;
+
+/// Wraps a [Soxr] so that its output lines up with its input in time: output sample `N`
+/// corresponds to input time `N / output_rate`.
+///
+/// The leading filter latency is dropped from the start of the output and the matching tail is
+/// emitted when flushing, so a complete stream holds exactly
+/// `round(input_frames * output_rate / input_rate)` frames. What is left of the latency after
+/// trimming whole frames is reported by [AlignedSoxr::fractional_delay] for sub-sample alignment.
+///
+/// Only interleaved datatypes are supported.
+///
+/// ```rust
+/// use libsoxr::{AlignedSoxr, Soxr};
+///
+/// let soxr = Soxr::create(1.0, 2.0, 1, None, None, None).unwrap();
+/// let mut aligned = AlignedSoxr::new(soxr).unwrap();
+///
+/// let source = [0.5f32; 48];
+/// let mut target = [0.0f32; 96];
+/// let (_, done) = aligned.process(Some(&source), &mut target).unwrap();
+/// let (_, flushed) = aligned.process::<f32, _>(None, &mut target[done..]).unwrap();
+/// assert_eq!(96, done + flushed);
+/// ```
+#[derive(Debug)]
+pub struct AlignedSoxr {
+    soxr: Soxr,
+    latency: f64,
+    leading: usize,
+    to_skip: usize,
+    input_frames: u64,
+    output_frames: u64,
+}
+
+impl AlignedSoxr {
+    /// Enables latency compensation for `soxr`. `soxr` should not have processed any data yet.
+    ///
+    /// `Soxr::delay` reports how much output is still held inside the resampler, not where the
+    /// filter places input time zero, so the latency is measured once by pushing an impulse
+    /// through a scratch resampler with the same rates and [QualitySpec].
+    pub fn new(soxr: Soxr) -> Result<AlignedSoxr> {
+        if let Some(io_spec) = soxr.io_spec() {
+            if !io_spec.input_type().is_interleaved() || !io_spec.output_type().is_interleaved() {
+                return Err(Error::new(
+                    Some("AlignedSoxr::new".into()),
+                    ErrorType::CreateError("only interleaved datatypes are supported".into()),
+                ));
+            }
+        }
+
+        let latency = measure_latency(soxr.input_rate(), soxr.output_rate(), soxr.quality_spec())?;
+        let leading = latency.round().max(0.0) as usize;
+        Ok(AlignedSoxr {
+            soxr,
+            latency,
+            leading,
+            to_skip: leading,
+            input_frames: 0,
+            output_frames: 0,
+        })
+    }
+
+    /// Total latency of the resampler in output samples as measured on creation
+    pub fn latency(&self) -> f64 {
+        self.latency
+    }
+
+    /// Latency in output samples that remains after trimming whole frames. Positive values mean
+    /// the output is late by this fraction of a sample, negative values that it is early.
+    pub fn fractional_delay(&self) -> f64 {
+        self.latency - self.leading as f64
+    }
+
+    /// Resamples `Some(buf_in)` into `buf_out` like [Soxr::process], dropping the leading
+    /// latency from the output. Call with `None` as `buf_in` until no more samples are returned
+    /// to receive the tail. The result contains number of input samples used and number of
+    /// output samples placed in `buf_out`. The sample types have to match the datatypes of the
+    /// [IOSpec].
+    pub fn process<I: Sample, O: Sample>(
+        &mut self,
+        buf_in: Option<&[I]>,
+        buf_out: &mut [O],
+    ) -> Result<(usize, usize)> {
+        self.soxr
+            .check_sample_types::<I, O>("AlignedSoxr::process")?;
+        let channels = self.soxr.num_channels() as usize;
+
+        if buf_in.is_some() {
+            let (idone, odone) = self.soxr.process(buf_in, buf_out)?;
+            self.input_frames += idone as u64;
+            let odone = self.discard_leading(buf_out, odone);
+            self.output_frames += odone as u64;
+            return Ok((idone, odone));
+        }
+
+        // flush exactly up to the expected length, padding with silence should libsoxr run dry
+        let remaining = self.expected_frames().saturating_sub(self.output_frames) as usize;
+        let wanted = remaining.min(buf_out.len() / channels);
+        let mut odone = 0;
+        while odone < wanted {
+            let target = &mut buf_out[odone * channels..wanted * channels];
+            let (_, flushed) = self.soxr.process::<I, O>(None, target)?;
+            if flushed == 0 {
+                break;
+            }
+            odone += self.discard_leading(target, flushed);
+        }
+        for sample in buf_out[odone * channels..wanted * channels].iter_mut() {
+            *sample = O::default();
+        }
+        self.output_frames += wanted as u64;
+        Ok((0, wanted))
+    }
+
+    /// Ready for fresh signal, same config.
+    pub fn clear(&mut self) -> Result<()> {
+        self.soxr.clear()?;
+        self.to_skip = self.leading;
+        self.input_frames = 0;
+        self.output_frames = 0;
+        Ok(())
+    }
+
+    /// Returns the wrapped resampler
+    pub fn soxr(&self) -> &Soxr {
+        &self.soxr
+    }
+
+    /// Unwraps the resampler, giving up latency compensation
+    pub fn into_inner(self) -> Soxr {
+        self.soxr
+    }
+
+    fn expected_frames(&self) -> u64 {
+        (self.input_frames as f64 * self.soxr.output_rate() / self.soxr.input_rate()).round() as u64
+    }
+
+    // moves the frames that remain after skipping leading latency to the front of `buf_out`
+    fn discard_leading<O>(&mut self, buf_out: &mut [O], odone: usize) -> usize {
+        let skip = self.to_skip.min(odone);
+        if skip > 0 {
+            let channels = self.soxr.num_channels() as usize;
+            buf_out[..odone * channels].rotate_left(skip * channels);
+            self.to_skip -= skip;
+        }
+        odone - skip
+    }
+}
+
+// Measures latency in output samples by resampling a single impulse. For a linear phase filter the
+// response is symmetric around where the impulse lands, so its centroid gives the latency with
+// sub-sample precision. For other filters it gives the group delay at DC.
+fn measure_latency(
+    input_rate: f64,
+    output_rate: f64,
+    quality_spec: Option<&QualitySpec>,
+) -> Result<f64> {
+    let io_spec = IOSpec::new(Datatype::Float64I, Datatype::Float64I);
+    let mut probe = Soxr::create(
+        input_rate,
+        output_rate,
+        1,
+        Some(&io_spec),
+        quality_spec,
+        None,
+    )?;
+    let ratio = output_rate / input_rate;
+
+    let mut impulse = vec![0.0f64; 2 * PROBE_FRAMES];
+    impulse[PROBE_FRAMES] = 1.0;
+    let mut response = vec![0.0f64; (impulse.len() as f64 * ratio).ceil() as usize + 1];
+
+    let (mut consumed, mut produced) = (0, 0);
+    while consumed < impulse.len() {
+        let (idone, odone) =
+            probe.process(Some(&impulse[consumed..]), &mut response[produced..])?;
+        if idone == 0 && odone == 0 {
+            break;
+        }
+        consumed += idone;
+        produced += odone;
+    }
+    loop {
+        let (_, odone) = probe.process::<f64, _>(None, &mut response[produced..])?;
+        if odone == 0 {
+            break;
+        }
+        produced += odone;
+    }
+
+    let center = PROBE_FRAMES as f64 * ratio;
+    let (moment, area) = response[..produced]
+        .iter()
+        .enumerate()
+        .fold((0.0, 0.0), |(moment, area), (n, y)| {
+            (moment + (n as f64 - center) * y, area + y)
+        });
+    Ok(moment / area)
+}
+
+#[cfg(test)]
+mod aligned_tests {
+    use super::AlignedSoxr;
+    use crate::{datatype::Datatype, spec::IOSpec, Soxr};
+
+    fn resample_in_chunks(aligned: &mut AlignedSoxr, input: &[f32], chunk: usize) -> Vec<f32> {
+        let channels = aligned.soxr().num_channels() as usize;
+        let mut output = Vec::new();
+        let mut buffer = vec![0.0f32; 4096 * channels];
+        for part in input.chunks(chunk * channels) {
+            let mut consumed = 0;
+            while consumed < part.len() {
+                let (idone, odone) = aligned
+                    .process(Some(&part[consumed..]), &mut buffer)
+                    .unwrap();
+                consumed += idone * channels;
+                output.extend_from_slice(&buffer[..odone * channels]);
+            }
+        }
+        loop {
+            let (_, odone) = aligned.process::<f32, _>(None, &mut buffer).unwrap();
+            if odone == 0 {
+                break;
+            }
+            output.extend_from_slice(&buffer[..odone * channels]);
+        }
+        output
+    }
+
+    #[test]
+    fn test_exact_length() {
+        for chunk in [1, 7, 100, 4410, 20000].iter() {
+            let soxr = Soxr::create(44100.0, 48000.0, 2, None, None, None).unwrap();
+            let mut aligned = AlignedSoxr::new(soxr).unwrap();
+            let input = vec![0.25f32; 2 * 10000];
+            let output = resample_in_chunks(&mut aligned, &input, *chunk);
+            assert_eq!(2 * 10884, output.len(), "chunk size {}", chunk);
+        }
+    }
+
+    #[test]
+    fn test_fractional_delay() {
+        let soxr = Soxr::create(44100.0, 48000.0, 1, None, None, None).unwrap();
+        let aligned = AlignedSoxr::new(soxr).unwrap();
+        assert!(aligned.fractional_delay().abs() <= 0.5);
+        assert!(aligned.latency() >= aligned.fractional_delay());
+    }
+
+    #[test]
+    fn test_time_alignment() {
+        let (input_rate, output_rate, freq) = (8000.0, 12000.0, 100.0);
+        let soxr = Soxr::create(input_rate, output_rate, 1, None, None, None).unwrap();
+        let mut aligned = AlignedSoxr::new(soxr).unwrap();
+
+        let input: Vec<f32> = (0..8000)
+            .map(|n| (2.0 * std::f64::consts::PI * freq * n as f64 / input_rate).sin() as f32)
+            .collect();
+        let output = resample_in_chunks(&mut aligned, &input, 256);
+        assert_eq!(12000, output.len());
+
+        // output sample N corresponds to input time N / output_rate
+        for (n, sample) in output.iter().enumerate().skip(1000).take(10000) {
+            let time = (n as f64 - aligned.fractional_delay()) / output_rate;
+            let expected = (2.0 * std::f64::consts::PI * freq * time).sin();
+            assert!((*sample as f64 - expected).abs() < 0.01, "sample {}", n);
+        }
+    }
+
+    #[test]
+    fn test_split_channels_rejected() {
+        let io_spec = IOSpec::new(Datatype::Float32S, Datatype::Float32S);
+        let soxr = Soxr::create(44100.0, 48000.0, 2, Some(&io_spec), None, None).unwrap();
+        assert!(AlignedSoxr::new(soxr).is_err());
+    }
+
+    #[test]
+    fn test_checks_sample_types() {
+        let soxr = Soxr::create(44100.0, 48000.0, 1, None, None, None).unwrap();
+        let mut aligned = AlignedSoxr::new(soxr).unwrap();
+        let mut wrong = [0.0f64; 100];
+        assert!(aligned.process(Some(&[0.5f32; 100]), &mut wrong).is_err());
+        let mut target = [0.0f32; 100];
+        assert!(aligned.process(Some(&[0i16; 100]), &mut target).is_err());
+        assert!(aligned.process::<f32, _>(None, &mut wrong).is_err());
+        assert!(aligned.process(Some(&[0.5f32; 100]), &mut target).is_ok());
+    }
+}
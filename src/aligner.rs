@@ -0,0 +1,494 @@
+//! Aligning several drifting streams to one master timeline
+use crate::{
+    control::{check_max_deviation, BlockFeeder},
+    datatype::Sample,
+    drift::DriftEstimator,
+    error_handling::{Error, ErrorType, Result},
+    variable::VariableRateResampler,
+};
+use std::collections::VecDeque;
+
+/// Number of frames taken from a source queue for each call to its resampler
+const BLOCK_FRAMES: usize = 64;
+/// Default largest relative deviation from the nominal io ratio of a source
+const DEFAULT_MAX_DEVIATION: f64 = 0.01;
+
+/// Resamples several sources that run on their own, drifting clocks onto the timeline of a
+/// reference source, for instance a microphone array spread over several audio interfaces.
+///
+/// Every source is pushed in chunks together with the time its first frame was captured, read
+/// from one clock that all sources share. A [DriftEstimator] per source turns these timestamps
+/// into a mapping between time and source position. The first source added is the reference: it
+/// is resampled at its nominal io ratio, and every output frame gets the capture time of the
+/// reference frame it plays. The io ratios of the other sources are set block by block so that
+/// they play the frames captured at that same time.
+///
+/// Output frames hold the channels of all sources, in the order the sources were added. Until
+/// every source has enough timestamps for an estimate, the output is silence.
+///
+/// ```rust
+/// use libsoxr::{StreamAligner, VariableRateResampler};
+///
+/// let interface = || {
+///     VariableRateResampler::create(48000.0, 48000.0, 1.1, 2, None, None, None).unwrap()
+/// };
+/// let mut aligner = StreamAligner::<f32>::new(48000.0, 64);
+/// let reference = aligner.add_source(interface()).unwrap();
+/// let other = aligner.add_source(interface()).unwrap();
+/// assert_eq!(4, aligner.num_channels());
+///
+/// // 10 ms chunks; the other interface started 2 ms later
+/// for n in 0..10 {
+///     aligner.push(reference, n as f64 * 0.01, &[0.0; 2 * 480]).unwrap();
+///     aligner.push(other, 0.002 + n as f64 * 0.01, &[0.0; 2 * 480]).unwrap();
+/// }
+///
+/// let mut output = [0.0f32; 4 * 256];
+/// aligner.pull(&mut output).unwrap();
+/// assert!(aligner.is_started());
+/// ```
+pub struct StreamAligner<T> {
+    output_rate: f64,
+    window: usize,
+    max_deviation: f64,
+    sources: Vec<Source<T>>,
+    started: bool,
+}
+
+struct Source<T> {
+    resampler: VariableRateResampler,
+    channels: usize,
+    nominal_io_ratio: f64,
+    estimator: DriftEstimator,
+    queue: VecDeque<T>,
+    pushed: u64,
+    // source position of the front of the queue
+    popped: f64,
+    // frames before the start that were not pushed yet, to be dropped when they are
+    to_skip: usize,
+    feeder: BlockFeeder<T>,
+    output: Vec<T>,
+    misalignment: f64,
+    underruns: usize,
+}
+
+impl<T: Sample> Source<T> {
+    // source position of the frame the resampler outputs next
+    fn position(&self) -> f64 {
+        let consumed = self.popped - self.feeder.pending() as f64;
+        consumed - self.resampler.delay() * self.resampler.io_ratio()
+    }
+
+    fn resample(&mut self, frames: usize) -> Result<()> {
+        let channels = self.channels;
+        self.output.resize(frames * channels, T::default());
+        let (queue, popped) = (&mut self.queue, &mut self.popped);
+        let produced = self.feeder.resample(
+            "StreamAligner::pull",
+            &mut self.resampler,
+            &mut self.output,
+            |input| {
+                let count = (queue.len() / channels).min(BLOCK_FRAMES);
+                input.extend(queue.drain(..count * channels));
+                *popped += count as f64;
+            },
+        )?;
+        if produced < frames {
+            self.output[produced * channels..]
+                .iter_mut()
+                .for_each(|sample| *sample = T::default());
+            self.underruns += 1;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Sample> StreamAligner<T> {
+    /// Creates an aligner with output rate `output_rate`, that estimates the clock of each
+    /// source from its last `window` timestamps
+    pub fn new(output_rate: f64, window: usize) -> StreamAligner<T> {
+        StreamAligner {
+            output_rate,
+            window,
+            max_deviation: DEFAULT_MAX_DEVIATION,
+            sources: Vec::new(),
+            started: false,
+        }
+    }
+
+    /// Adds a source that is resampled with `resampler`, which must have the output rate of the
+    /// aligner. Its io ratio is taken as the nominal one. Returns the index of the source; the
+    /// source with index 0 is the reference. Sources can only be added before the output starts.
+    pub fn add_source(&mut self, resampler: VariableRateResampler) -> Result<usize> {
+        resampler
+            .soxr()
+            .check_sample_type::<T>("StreamAligner::add_source")?;
+        if self.started || resampler.output_rate() != self.output_rate {
+            return Err(Error::new(
+                Some("StreamAligner::add_source".into()),
+                ErrorType::ChangeError(
+                    "sources need the output rate of the aligner and must be added before it starts"
+                        .into(),
+                ),
+            ));
+        }
+        let channels = resampler.num_channels() as usize;
+        self.sources.push(Source {
+            channels,
+            nominal_io_ratio: resampler.io_ratio(),
+            estimator: DriftEstimator::new(resampler.input_rate(), self.window),
+            resampler,
+            queue: VecDeque::new(),
+            pushed: 0,
+            popped: 0.0,
+            to_skip: 0,
+            feeder: BlockFeeder::new(channels, BLOCK_FRAMES),
+            output: Vec::new(),
+            misalignment: 0.0,
+            underruns: 0,
+        });
+        Ok(self.sources.len() - 1)
+    }
+
+    /// Sets how far the io ratio of a source may deviate from its nominal value, relative to
+    /// that value. `max_deviation` has to be above 0 and below 1.
+    pub fn set_max_deviation(&mut self, max_deviation: f64) -> Result<()> {
+        check_max_deviation("StreamAligner::set_max_deviation", max_deviation)?;
+        self.max_deviation = max_deviation;
+        Ok(())
+    }
+
+    /// Number of channels of the output, the sum of the channels of all sources
+    pub fn num_channels(&self) -> usize {
+        self.sources.iter().map(|source| source.channels).sum()
+    }
+
+    /// Whether every source had enough timestamps to start the output
+    pub fn is_started(&self) -> bool {
+        self.started
+    }
+
+    /// Adds interleaved frames to `source`, whose first frame was captured at `timestamp`
+    /// seconds on the shared clock
+    pub fn push(&mut self, source: usize, timestamp: f64, frames: &[T]) -> Result<()> {
+        let source = self.source_mut("StreamAligner::push", source)?;
+        let len = frames.len() / source.channels;
+        source.estimator.observe(timestamp, source.pushed);
+        let skip = source.to_skip.min(len);
+        source.to_skip -= skip;
+        source.queue.extend(
+            frames[skip * source.channels..len * source.channels]
+                .iter()
+                .copied(),
+        );
+        source.pushed += len as u64;
+        Ok(())
+    }
+
+    /// Fills `buf_out` with interleaved frames holding the aligned channels of all sources.
+    /// Sources that ran out of input play silence.
+    pub fn pull(&mut self, buf_out: &mut [T]) -> Result<()> {
+        let channels = self.num_channels();
+        if channels == 0 {
+            return Ok(());
+        }
+        let frames = buf_out.len() / channels;
+        if !self.started {
+            self.started = self
+                .sources
+                .iter()
+                .all(|source| source.estimator.estimate().is_some());
+            if !self.started {
+                buf_out.iter_mut().for_each(|sample| *sample = T::default());
+                return Ok(());
+            }
+            self.start();
+        }
+
+        // capture time of the reference frame at the end of this block
+        let reference = &self.sources[0];
+        let reference_end = reference.position() + frames as f64 * reference.nominal_io_ratio;
+        if let Some(end_time) = reference.estimator.time_at(reference_end) {
+            for source in self.sources[1..].iter_mut() {
+                if let Some(target) = source.estimator.position_at(end_time) {
+                    let io_ratio = ((target - source.position()) / frames as f64)
+                        .max(source.nominal_io_ratio * (1.0 - self.max_deviation))
+                        .min(source.nominal_io_ratio * (1.0 + self.max_deviation))
+                        .min(source.resampler.max_io_ratio());
+                    // slew to the new ratio over the frames about to be pulled
+                    source.resampler.set_io_ratio(io_ratio, frames)?;
+                }
+            }
+        }
+
+        for source in self.sources.iter_mut() {
+            source.resample(frames)?;
+        }
+        let mut offset = 0;
+        for source in self.sources.iter() {
+            for (frame, output) in buf_out
+                .chunks_exact_mut(channels)
+                .zip(source.output.chunks_exact(source.channels))
+            {
+                frame[offset..offset + source.channels].copy_from_slice(output);
+            }
+            offset += source.channels;
+        }
+        self.update_misalignment();
+        Ok(())
+    }
+
+    /// Distance in output frames between `source` and the reference: positive when `source`
+    /// plays frames that were captured later than the reference frames played at the same time
+    pub fn misalignment(&self, source: usize) -> Option<f64> {
+        self.sources.get(source).map(|source| source.misalignment)
+    }
+
+    /// Current io ratio of `source`
+    pub fn io_ratio(&self, source: usize) -> Option<f64> {
+        self.sources
+            .get(source)
+            .map(|source| source.resampler.io_ratio())
+    }
+
+    /// Number of pulls for which `source` ran out of input
+    pub fn underruns(&self, source: usize) -> Option<usize> {
+        self.sources.get(source).map(|source| source.underruns)
+    }
+
+    fn source_mut(&mut self, func: &'static str, source: usize) -> Result<&mut Source<T>> {
+        let count = self.sources.len();
+        self.sources.get_mut(source).ok_or_else(|| {
+            Error::new(
+                Some(func.into()),
+                ErrorType::ProcessError(format!("source {} out of {}", source, count)),
+            )
+        })
+    }
+
+    // lines the sources up with the first reference frame, by dropping their earlier frames or
+    // putting silence before them
+    fn start(&mut self) {
+        let start_time = match self.sources[0].estimator.time_at(0.0) {
+            Some(start_time) => start_time,
+            None => return,
+        };
+        for source in self.sources[1..].iter_mut() {
+            let start = match source.estimator.position_at(start_time) {
+                Some(start) => start.floor(),
+                None => continue,
+            };
+            if start > 0.0 {
+                let skip = (start as usize).min(source.queue.len() / source.channels);
+                source.queue.drain(..skip * source.channels);
+                source.to_skip = start as usize - skip;
+                source.popped = start;
+            } else {
+                for _ in 0..(-start) as usize * source.channels {
+                    source.queue.push_front(T::default());
+                }
+                source.popped = start;
+            }
+        }
+    }
+
+    fn update_misalignment(&mut self) {
+        let reference = &self.sources[0];
+        let time = match reference.estimator.time_at(reference.position()) {
+            Some(time) => time,
+            None => return,
+        };
+        for source in self.sources[1..].iter_mut() {
+            if let Some(target) = source.estimator.position_at(time) {
+                source.misalignment = (source.position() - target) / source.nominal_io_ratio;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod aligner_tests {
+    use super::StreamAligner;
+    use crate::VariableRateResampler;
+
+    const RATE: f64 = 8000.0;
+    const CHUNK: usize = 80;
+
+    // the signal every source captures, as a function of the time on the shared clock
+    fn signal(time: f64) -> f32 {
+        (2.0 * std::f64::consts::PI * 50.0 * time).sin() as f32
+    }
+
+    // Chunks captured by a source whose clock runs `ppm` fast and that starts at `start`
+    // seconds, until `seconds`
+    fn chunks(ppm: f64, start: f64, seconds: f64) -> Vec<(f64, Vec<f32>)> {
+        let rate = RATE * (1.0 + ppm * 1e-6);
+        let mut chunks = Vec::new();
+        let mut frame = 0;
+        while start + frame as f64 / rate < seconds {
+            let timestamp = start + frame as f64 / rate;
+            let samples = (frame..frame + CHUNK)
+                .map(|n| signal(start + n as f64 / rate))
+                .collect();
+            chunks.push((timestamp, samples));
+            frame += CHUNK;
+        }
+        chunks
+    }
+
+    fn resampler() -> VariableRateResampler {
+        VariableRateResampler::create(RATE, RATE, 1.1, 1, None, None, None).unwrap()
+    }
+
+    #[test]
+    fn test_aligns_drifting_sources() {
+        let mut aligner = StreamAligner::<f32>::new(RATE, 100);
+        for _ in 0..3 {
+            aligner.add_source(resampler()).unwrap();
+        }
+        let sources = [
+            chunks(0.0, 0.0, 30.0),
+            chunks(300.0, 0.0123, 30.0),
+            chunks(-500.0, -0.0071, 30.0),
+        ];
+
+        let mut output = vec![0.0f32; 3 * CHUNK];
+        let mut worst = 0.0f32;
+        for chunk in 0..sources[0].len() {
+            for (index, source) in sources.iter().enumerate() {
+                if let Some((timestamp, samples)) = source.get(chunk) {
+                    aligner.push(index, *timestamp, samples).unwrap();
+                }
+            }
+            // pull behind by more than the latency of the resamplers, so that no source runs dry
+            if chunk < 8 {
+                continue;
+            }
+            aligner.pull(&mut output).unwrap();
+            if chunk * CHUNK > 10 * RATE as usize {
+                for frame in output.chunks_exact(3) {
+                    worst = worst.max((frame[0] - frame[1]).abs());
+                    worst = worst.max((frame[0] - frame[2]).abs());
+                }
+            }
+        }
+
+        assert!(aligner.is_started());
+        for source in 0..3 {
+            assert_eq!(0, aligner.underruns(source).unwrap());
+        }
+        for source in 1..3 {
+            let misalignment = aligner.misalignment(source).unwrap();
+            assert!(misalignment.abs() < 0.1, "{}: {}", source, misalignment);
+        }
+        assert!((aligner.io_ratio(1).unwrap() - 1.0003).abs() < 20e-6);
+        assert!((aligner.io_ratio(2).unwrap() - 0.9995).abs() < 20e-6);
+        // 0.1 frame of a 50 Hz sine at 8 kHz is an error of at most 0.004
+        assert!(worst < 0.01, "{}", worst);
+    }
+
+    #[test]
+    fn test_io_ratio_is_continuous() {
+        let mut aligner = StreamAligner::<f32>::new(RATE, 100);
+        aligner.add_source(resampler()).unwrap();
+        aligner.add_source(resampler()).unwrap();
+        let sources = [chunks(0.0, 0.0, 20.0), chunks(300.0, 0.0123, 20.0)];
+        let mut output = vec![0.0f32; 2 * CHUNK];
+        for chunk in 0..sources[0].len() {
+            for (index, source) in sources.iter().enumerate() {
+                if let Some((timestamp, samples)) = source.get(chunk) {
+                    aligner.push(index, *timestamp, samples).unwrap();
+                }
+            }
+            if chunk >= 8 {
+                aligner.pull(&mut output).unwrap();
+            }
+        }
+
+        // the io ratio of every output frame, from the input positions the frames map to
+        let soxr = aligner.sources[1].resampler.soxr();
+        let end = soxr.output_position();
+        let ratios: Vec<f64> = (0..end)
+            .filter_map(|n| {
+                Some(
+                    soxr.output_to_input_position(n as f64 + 1.0)?
+                        - soxr.output_to_input_position(n as f64)?,
+                )
+            })
+            .collect();
+        let largest_step = ratios
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]).abs())
+            .fold(0.0, f64::max);
+        // a step change at the block boundaries would jump by several 1e-3
+        assert!(largest_step < 1e-3, "largest step {}", largest_step);
+    }
+
+    #[test]
+    fn test_start_before_frames_arrive() {
+        let mut aligner = StreamAligner::<f32>::new(RATE, 100);
+        aligner.add_source(resampler()).unwrap();
+        aligner.add_source(resampler()).unwrap();
+        // the second source started half a second earlier, but only 0.3 s of it is there at
+        // the start of the output, short of the first frame that lines up with the reference
+        let sources = [chunks(0.0, 0.0, 6.0), chunks(0.0, -0.5, 6.0)];
+        let mut output = vec![0.0f32; 2 * CHUNK];
+        let mut pushed = [0, 0];
+        let mut push = |aligner: &mut StreamAligner<f32>, source: usize, until: usize| {
+            for (timestamp, samples) in sources[source][pushed[source]..until].iter() {
+                aligner.push(source, *timestamp, samples).unwrap();
+            }
+            pushed[source] = until;
+        };
+        push(&mut aligner, 0, 9);
+        push(&mut aligner, 1, 30);
+        aligner.pull(&mut output).unwrap();
+        assert!(aligner.is_started());
+
+        // then the second source catches up, 50 chunks ahead like it should be
+        let mut worst = 0.0f32;
+        for chunk in 9..sources[0].len() {
+            push(&mut aligner, 0, chunk + 1);
+            push(&mut aligner, 1, (chunk + 51).min(sources[1].len()));
+            aligner.pull(&mut output).unwrap();
+            if chunk * CHUNK > 2 * RATE as usize {
+                for frame in output.chunks_exact(2) {
+                    worst = worst.max((frame[0] - frame[1]).abs());
+                }
+            }
+        }
+        let misalignment = aligner.misalignment(1).unwrap();
+        assert!(misalignment.abs() < 0.1, "{}", misalignment);
+        assert!(worst < 0.01, "{}", worst);
+    }
+
+    #[test]
+    fn test_invalid_max_deviation() {
+        let mut aligner = StreamAligner::<f32>::new(RATE, 100);
+        for max_deviation in [0.0, -0.01, 1.0, f64::NAN].iter() {
+            assert!(aligner.set_max_deviation(*max_deviation).is_err());
+        }
+        assert!(aligner.set_max_deviation(0.01).is_ok());
+    }
+
+    #[test]
+    fn test_silence_until_started() {
+        let mut aligner = StreamAligner::<f32>::new(RATE, 100);
+        aligner.add_source(resampler()).unwrap();
+        aligner.add_source(resampler()).unwrap();
+        aligner.push(0, 0.0, &[1.0; CHUNK]).unwrap();
+        let mut output = [1.0f32; 2 * CHUNK];
+        aligner.pull(&mut output).unwrap();
+        assert!(!aligner.is_started());
+        assert!(output.iter().all(|sample| *sample == 0.0));
+    }
+
+    #[test]
+    fn test_invalid_sources() {
+        let mut aligner = StreamAligner::<f32>::new(RATE, 100);
+        let other_rate =
+            VariableRateResampler::create(RATE, 2.0 * RATE, 0.6, 1, None, None, None).unwrap();
+        assert!(aligner.add_source(other_rate).is_err());
+        assert!(aligner.push(0, 0.0, &[0.0; CHUNK]).is_err());
+    }
+}
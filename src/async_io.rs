@@ -0,0 +1,294 @@
+//! Tokio adapters that resample raw PCM byte streams. Requires the `tokio` feature.
+use crate::{datatype::Endianness, error_handling::Result, io::ByteResampler, soxr::Soxr};
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Asynchronous version of [SoxrReader](crate::io::SoxrReader): reads raw PCM bytes from an
+/// inner [AsyncRead] and resamples them. The bytes are interpreted according to the input
+/// [Datatype](crate::datatype::Datatype) of the [IOSpec](crate::spec::IOSpec) of the resampler
+/// and are read back in its output `Datatype`, both in the given byte order.
+///
+/// Frames split over several polls of the inner reader are carried over; an incomplete frame
+/// at the end of the inner stream is dropped. Only interleaved datatypes are supported.
+pub struct AsyncSoxrReader<R> {
+    inner: R,
+    resampler: ByteResampler,
+    output_pos: usize,
+    eof: bool,
+    finished: bool,
+}
+
+impl<R: AsyncRead + Unpin> AsyncSoxrReader<R> {
+    /// Resamples the bytes read from `inner` using `soxr`. Samples in both the inner stream and
+    /// the resampled stream are in `endianness` byte order.
+    pub fn new(inner: R, soxr: Soxr, endianness: Endianness) -> Result<AsyncSoxrReader<R>> {
+        Ok(AsyncSoxrReader {
+            inner,
+            resampler: ByteResampler::new("AsyncSoxrReader::new", soxr, endianness)?,
+            output_pos: 0,
+            eof: false,
+            finished: false,
+        })
+    }
+
+    /// Gets a reference to the inner reader
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Gets a reference to the resampler
+    pub fn soxr(&self) -> &Soxr {
+        &self.resampler.soxr
+    }
+
+    /// Unwraps this `AsyncSoxrReader`, returning the inner reader. Input that has not been
+    /// resampled yet is lost.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn poll_refill(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.eof {
+            self.resampler.output_len = 0;
+            self.output_pos = 0;
+            self.finished = self.resampler.drain()? == 0;
+            return Poll::Ready(Ok(()));
+        }
+
+        let mut space = ReadBuf::new(self.resampler.input_space());
+        ready!(Pin::new(&mut self.inner).poll_read(cx, &mut space))?;
+        let read = space.filled().len();
+
+        self.resampler.output_len = 0;
+        self.output_pos = 0;
+        self.eof = read == 0;
+        self.resampler.input_len += read;
+        Poll::Ready(self.resampler.resample_input())
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for AsyncSoxrReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        while this.output_pos == this.resampler.output_len {
+            if this.finished || buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            ready!(this.poll_refill(cx))?;
+        }
+
+        let output = &this.resampler.output()[this.output_pos..];
+        let read = buf.remaining().min(output.len());
+        buf.put_slice(&output[..read]);
+        this.output_pos += read;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Asynchronous version of [SoxrWriter](crate::io::SoxrWriter): resamples raw PCM bytes written
+/// to it and writes the result to an inner [AsyncWrite]. The bytes written are interpreted
+/// according to the input [Datatype](crate::datatype::Datatype) of the
+/// [IOSpec](crate::spec::IOSpec) of the resampler and are written to the inner writer in its
+/// output `Datatype`, both in the given byte order.
+///
+/// Chunks of any size can be written, also chunks that end halfway through a frame. Flushing
+/// passes the output produced so far on to the inner writer and leaves the stream as it is.
+/// Shutting down ends the stream: the tail that libsoxr holds is drained into the inner writer.
+/// As there is no asynchronous drop, output is lost when the writer is dropped without shutting
+/// down. Only interleaved datatypes are supported.
+pub struct AsyncSoxrWriter<W> {
+    inner: W,
+    resampler: ByteResampler,
+    output_pos: usize,
+    drained: bool,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncSoxrWriter<W> {
+    /// Resamples the bytes written using `soxr` and writes them to `inner`. Samples in both the
+    /// bytes written and the inner stream are in `endianness` byte order.
+    pub fn new(inner: W, soxr: Soxr, endianness: Endianness) -> Result<AsyncSoxrWriter<W>> {
+        Ok(AsyncSoxrWriter {
+            inner,
+            resampler: ByteResampler::new("AsyncSoxrWriter::new", soxr, endianness)?,
+            output_pos: 0,
+            drained: true,
+        })
+    }
+
+    /// Gets a reference to the inner writer
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Gets a reference to the resampler
+    pub fn soxr(&self) -> &Soxr {
+        &self.resampler.soxr
+    }
+
+    /// Unwraps this `AsyncSoxrWriter` without draining the resampler, returning the inner
+    /// writer. Output held by the resampler is lost.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    // writes all resampled output to the inner writer
+    fn poll_write_output(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.output_pos < self.resampler.output_len {
+            let output = &self.resampler.output()[self.output_pos..];
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, output))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.output_pos += written;
+        }
+        self.resampler.output_len = 0;
+        self.output_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    // drains the tail of the resampler into the inner writer and clears the resampler
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            ready!(self.poll_write_output(cx))?;
+            if self.drained {
+                return Poll::Ready(Ok(()));
+            }
+            self.resampler.input_len = 0;
+            if self.resampler.drain()? == 0 {
+                self.resampler.soxr.clear()?;
+                self.drained = true;
+            }
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for AsyncSoxrWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        ready!(this.poll_write_output(cx))?;
+
+        let space = this.resampler.input_space();
+        let written = buf.len().min(space.len());
+        space[..written].copy_from_slice(&buf[..written]);
+        this.resampler.input_len += written;
+        this.drained = false;
+        this.resampler.resample_input()?;
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_write_output(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod async_io_tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::{AsyncSoxrReader, AsyncSoxrWriter};
+    use crate::{
+        datatype::{Datatype, Endianness},
+        io::SoxrReader,
+        spec::IOSpec,
+        Soxr,
+    };
+
+    fn source() -> Vec<u8> {
+        (0..2 * 4410i16)
+            .map(|n| if n % 2 == 0 { 8192i16 } else { -16384 })
+            .flat_map(|s| s.to_le_bytes())
+            .collect()
+    }
+
+    fn soxr() -> Soxr {
+        let io_spec = IOSpec::new(Datatype::Int16I, Datatype::Float32I);
+        Soxr::create(44100.0, 48000.0, 2, Some(&io_spec), None, None).unwrap()
+    }
+
+    fn resample_sync(pcm: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        std::io::Read::read_to_end(
+            &mut SoxrReader::new(pcm, soxr(), Endianness::Little).unwrap(),
+            &mut output,
+        )
+        .unwrap();
+        output
+    }
+
+    #[tokio::test]
+    async fn test_read_from_duplex() {
+        let pcm = source();
+        // a tiny pipe splits frames and samples over many polls
+        let (mut client, server) = duplex(7);
+        let writer = {
+            let pcm = pcm.clone();
+            tokio::spawn(async move {
+                client.write_all(&pcm).await.unwrap();
+            })
+        };
+
+        let mut reader = AsyncSoxrReader::new(server, soxr(), Endianness::Little).unwrap();
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).await.unwrap();
+        writer.await.unwrap();
+
+        assert_eq!(4 * 2 * 4800, output.len());
+        assert_eq!(resample_sync(&pcm), output);
+    }
+
+    #[tokio::test]
+    async fn test_write_to_duplex() {
+        let pcm = source();
+        let (client, mut server) = duplex(64);
+        let reader = tokio::spawn(async move {
+            let mut output = Vec::new();
+            server.read_to_end(&mut output).await.unwrap();
+            output
+        });
+
+        let mut writer = AsyncSoxrWriter::new(client, soxr(), Endianness::Little).unwrap();
+        // odd chunk sizes split frames and samples
+        for chunk in pcm.chunks(5) {
+            writer.write_all(chunk).await.unwrap();
+        }
+        writer.shutdown().await.unwrap();
+        drop(writer);
+
+        let output = reader.await.unwrap();
+        assert_eq!(4 * 2 * 4800, output.len());
+        assert_eq!(resample_sync(&pcm), output);
+    }
+
+    #[tokio::test]
+    async fn test_flush_keeps_stream() {
+        let pcm = source();
+        let mut writer = AsyncSoxrWriter::new(Vec::new(), soxr(), Endianness::Little).unwrap();
+        for chunk in pcm.chunks(5) {
+            writer.write_all(chunk).await.unwrap();
+            writer.flush().await.unwrap();
+        }
+        // the tail stays in the resampler until shutting down
+        assert!(writer.get_ref().len() < 4 * 2 * 4800);
+
+        writer.shutdown().await.unwrap();
+        assert_eq!(resample_sync(&pcm), writer.into_inner());
+    }
+}
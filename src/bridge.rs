@@ -0,0 +1,404 @@
+//! Bridging audio between two clock domains
+use crate::{
+    control::{check_max_deviation, BlockFeeder, FillController},
+    datatype::Sample,
+    error_handling::{Error, ErrorType, Result},
+    variable::VariableRateResampler,
+};
+use std::{
+    cell::UnsafeCell,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+/// Number of frames taken from the ring for each call to the resampler
+const BLOCK_FRAMES: usize = 64;
+/// Time constant in seconds of the low-pass filter on the measured ring fill
+const FILL_SMOOTHING: f64 = 1.0;
+/// Default proportional gain, per second of fill error
+const DEFAULT_KP: f64 = 0.4;
+/// Default integral gain, per second squared of integrated fill error
+const DEFAULT_KI: f64 = 0.04;
+/// Default largest relative deviation from the nominal io ratio
+const DEFAULT_MAX_DEVIATION: f64 = 0.01;
+
+/// Moves audio from a producer running on one clock to a consumer running on another, like
+/// zita-ajbridge. The producer pushes interleaved frames at rate A into a lock-free ring through
+/// a [BridgeProducer], usually from its own thread. The consumer pulls frames at rate B from the
+/// `ClockBridge`, which resamples the ring contents with a [VariableRateResampler].
+///
+/// A PI controller compares the low-pass filtered ring fill with the target fill on every pull
+/// and steers the io ratio around its nominal value `A / B`, so that the fill stays at its
+/// target however far the two clocks drift apart. Until the ring reaches its target fill for
+/// the first time the consumer gets silence.
+///
+/// ```rust
+/// use libsoxr::{ClockBridge, VariableRateResampler};
+///
+/// let resampler =
+///     VariableRateResampler::create(48000.0, 44100.0, 1.1 * 48000.0 / 44100.0, 2, None, None, None)
+///         .unwrap();
+/// let (mut producer, mut bridge) = ClockBridge::<f32>::new(resampler, 4096, 1024).unwrap();
+///
+/// let capture = std::thread::spawn(move || {
+///     // a device callback would push what it captured
+///     producer.push(&[0.0f32; 2 * 2048]);
+/// });
+/// capture.join().unwrap();
+///
+/// let mut playback = [0.0f32; 2 * 256];
+/// bridge.pull(&mut playback).unwrap();
+/// ```
+pub struct ClockBridge<T> {
+    ring: Arc<Ring<T>>,
+    resampler: VariableRateResampler,
+    channels: usize,
+    nominal_io_ratio: f64,
+    controller: FillController,
+    started: bool,
+    underruns: usize,
+    feeder: BlockFeeder<T>,
+}
+
+/// Producer side of a [ClockBridge]
+pub struct BridgeProducer<T> {
+    ring: Arc<Ring<T>>,
+    channels: usize,
+    overruns: usize,
+}
+
+impl<T: Sample + Send> ClockBridge<T> {
+    /// Creates a bridge with a ring of `capacity` frames that is kept at `target_fill` frames.
+    /// The io ratio of `resampler` is taken as the nominal ratio between the producer rate and
+    /// the consumer rate.
+    pub fn new(
+        resampler: VariableRateResampler,
+        capacity: usize,
+        target_fill: usize,
+    ) -> Result<(BridgeProducer<T>, ClockBridge<T>)> {
+        resampler
+            .soxr()
+            .check_sample_type::<T>("ClockBridge::new")?;
+        if target_fill == 0 || target_fill >= capacity {
+            return Err(Error::new(
+                Some("ClockBridge::new".into()),
+                ErrorType::CreateError("target fill must be within the capacity".into()),
+            ));
+        }
+
+        let channels = resampler.num_channels() as usize;
+        let ring = Arc::new(Ring::new(capacity * channels));
+        let producer = BridgeProducer {
+            ring: ring.clone(),
+            channels,
+            overruns: 0,
+        };
+        let controller = FillController::new(
+            target_fill as f64 / resampler.output_rate(),
+            DEFAULT_KP,
+            DEFAULT_KI,
+            DEFAULT_MAX_DEVIATION,
+            FILL_SMOOTHING,
+        );
+        let bridge = ClockBridge {
+            ring,
+            channels,
+            nominal_io_ratio: resampler.io_ratio(),
+            resampler,
+            controller,
+            started: false,
+            underruns: 0,
+            feeder: BlockFeeder::new(channels, BLOCK_FRAMES),
+        };
+        Ok((producer, bridge))
+    }
+
+    /// Sets the gains of the PI controller. The fill error is measured in seconds of output, so
+    /// `kp` is per second and `ki` per second squared.
+    pub fn set_gains(&mut self, kp: f64, ki: f64) {
+        self.controller.kp = kp;
+        self.controller.ki = ki;
+    }
+
+    /// Sets how far the io ratio may deviate from its nominal value, relative to that value.
+    /// `max_deviation` has to be above 0 and below 1.
+    pub fn set_max_deviation(&mut self, max_deviation: f64) -> Result<()> {
+        check_max_deviation("ClockBridge::set_max_deviation", max_deviation)?;
+        self.controller.max_deviation = max_deviation;
+        Ok(())
+    }
+
+    /// Frames waiting in the ring and in the input block of the resampler
+    pub fn fill(&self) -> usize {
+        self.ring.len() / self.channels + self.feeder.pending()
+    }
+
+    /// Low-pass filtered fill the controller works with
+    pub fn filtered_fill(&self) -> f64 {
+        self.controller.filtered() * self.resampler.output_rate()
+    }
+
+    /// Current io ratio of the resampler
+    pub fn io_ratio(&self) -> f64 {
+        self.resampler.io_ratio()
+    }
+
+    /// Number of pulls that ran out of input after the ring had filled up
+    pub fn underruns(&self) -> usize {
+        self.underruns
+    }
+
+    /// Fills `buf_out` with resampled interleaved frames. Frames the producer did not deliver in
+    /// time are replaced by silence.
+    pub fn pull(&mut self, buf_out: &mut [T]) -> Result<()> {
+        let frames = buf_out.len() / self.channels;
+        let output_rate = self.resampler.output_rate();
+        if !self.started {
+            self.started = self.fill() as f64 >= self.controller.target * output_rate;
+            if !self.started {
+                buf_out.iter_mut().for_each(|sample| *sample = T::default());
+                return Ok(());
+            }
+            self.controller.reset(self.fill() as f64 / output_rate);
+        }
+
+        // slew to the new ratio over the frames about to be pulled
+        let correction = self.controller.update(
+            self.fill() as f64 / output_rate,
+            frames as f64 / output_rate,
+        );
+        let io_ratio =
+            (self.nominal_io_ratio * (1.0 + correction)).min(self.resampler.max_io_ratio());
+        self.resampler.set_io_ratio(io_ratio, frames)?;
+
+        let (ring, block) = (&self.ring, BLOCK_FRAMES * self.channels);
+        let produced =
+            self.feeder
+                .resample("ClockBridge::pull", &mut self.resampler, buf_out, |input| {
+                    input.resize(block, T::default());
+                    let read = ring.pop(input);
+                    input.truncate(read);
+                })?;
+        if produced < frames {
+            buf_out[produced * self.channels..]
+                .iter_mut()
+                .for_each(|sample| *sample = T::default());
+            self.underruns += 1;
+        }
+        Ok(())
+    }
+
+    /// Unwraps the resampler
+    pub fn into_inner(self) -> VariableRateResampler {
+        self.resampler
+    }
+}
+
+impl<T: Sample + Send> BridgeProducer<T> {
+    /// Pushes interleaved frames without blocking. Returns the number of frames that fitted in
+    /// the ring; the rest is dropped and counted as an overrun.
+    pub fn push(&mut self, frames: &[T]) -> usize {
+        let whole = frames.len() - frames.len() % self.channels;
+        let free = self.ring.capacity() - self.ring.len();
+        let pushed = whole.min(free - free % self.channels);
+        self.ring.push(&frames[..pushed]);
+        if pushed < whole {
+            self.overruns += 1;
+        }
+        pushed / self.channels
+    }
+
+    /// Frames that can be pushed without overrun
+    pub fn free(&self) -> usize {
+        (self.ring.capacity() - self.ring.len()) / self.channels
+    }
+
+    /// Number of pushes that did not fit in the ring completely
+    pub fn overruns(&self) -> usize {
+        self.overruns
+    }
+}
+
+// Single producer, single consumer ring of samples. `head` and `tail` count the samples ever
+// written and read; only the producer stores `head` and only the consumer stores `tail`.
+struct Ring<T> {
+    buffer: Box<[UnsafeCell<T>]>,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+// SAFETY: the producer only writes slots that the consumer released and the consumer only reads
+// slots that the producer published, with acquire/release ordering on `head` and `tail`.
+unsafe impl<T: Send> Sync for Ring<T> {}
+
+impl<T: Copy + Default> Ring<T> {
+    fn new(capacity: usize) -> Ring<T> {
+        Ring {
+            buffer: (0..capacity)
+                .map(|_| UnsafeCell::new(T::default()))
+                .collect(),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        self.buffer.len()
+    }
+
+    fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Acquire);
+        self.head.load(Ordering::Acquire).wrapping_sub(tail)
+    }
+
+    // producer only; `samples` must fit in the free space
+    fn push(&self, samples: &[T]) {
+        let head = self.head.load(Ordering::Relaxed);
+        for (n, sample) in samples.iter().enumerate() {
+            let slot = &self.buffer[head.wrapping_add(n) % self.capacity()];
+            unsafe { *slot.get() = *sample };
+        }
+        self.head
+            .store(head.wrapping_add(samples.len()), Ordering::Release);
+    }
+
+    // consumer only; returns number of samples read into `samples`
+    fn pop(&self, samples: &mut [T]) -> usize {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let available = self.head.load(Ordering::Acquire).wrapping_sub(tail);
+        let read = available.min(samples.len());
+        for (n, sample) in samples[..read].iter_mut().enumerate() {
+            let slot = &self.buffer[tail.wrapping_add(n) % self.capacity()];
+            *sample = unsafe { *slot.get() };
+        }
+        self.tail.store(tail.wrapping_add(read), Ordering::Release);
+        read
+    }
+}
+
+#[cfg(test)]
+mod bridge_tests {
+    use std::sync::Arc;
+
+    use super::{ClockBridge, Ring};
+    use crate::VariableRateResampler;
+
+    #[test]
+    fn test_ring_across_threads() {
+        let ring = Arc::new(Ring::<f32>::new(100));
+        let producer = {
+            let ring = ring.clone();
+            std::thread::spawn(move || {
+                let mut next = 0;
+                while next < 100_000 {
+                    let free = ring.capacity() - ring.len();
+                    let count = free.min(17).min(100_000 - next);
+                    let chunk: Vec<f32> = (next..next + count).map(|n| n as f32).collect();
+                    ring.push(&chunk);
+                    next += count;
+                }
+            })
+        };
+
+        let mut expected = 0;
+        let mut buffer = [0.0f32; 23];
+        while expected < 100_000 {
+            let read = ring.pop(&mut buffer);
+            for sample in buffer[..read].iter() {
+                assert_eq!(expected as f32, *sample);
+                expected += 1;
+            }
+        }
+        producer.join().unwrap();
+    }
+
+    // Simulates a producer whose clock runs `drift_ppm` fast against the consumer, both at a
+    // nominal 4 kHz. Returns the bridge after `seconds` of simulated time and the mean io ratio
+    // over the second half.
+    fn simulate(drift_ppm: f64, seconds: f64) -> (ClockBridge<f32>, f64) {
+        let rate = 4000.0;
+        let resampler =
+            VariableRateResampler::create(rate, rate, 1.1, 1, None, None, None).unwrap();
+        let (mut producer, mut bridge) = ClockBridge::new(resampler, 2048, 512).unwrap();
+
+        let producer_rate = rate * (1.0 + drift_ppm * 1e-6);
+        let (producer_block, consumer_block) = (100, 128);
+        let (mut producer_time, mut consumer_time) = (0.0, 0.0);
+        let mut phase = 0usize;
+        let mut output = vec![0.0f32; consumer_block];
+        let (mut ratio_sum, mut ratio_count) = (0.0, 0);
+        while consumer_time < seconds {
+            if producer_time <= consumer_time {
+                let block: Vec<f32> = (phase..phase + producer_block)
+                    .map(|n| (n as f32 * 0.05).sin())
+                    .collect();
+                assert_eq!(producer_block, producer.push(&block));
+                phase += producer_block;
+                producer_time += producer_block as f64 / producer_rate;
+            } else {
+                bridge.pull(&mut output).unwrap();
+                consumer_time += consumer_block as f64 / rate;
+                if consumer_time > seconds / 2.0 {
+                    ratio_sum += bridge.io_ratio();
+                    ratio_count += 1;
+                }
+            }
+        }
+        assert_eq!(0, producer.overruns());
+        (bridge, ratio_sum / ratio_count as f64)
+    }
+
+    #[test]
+    fn test_drift_compensation() {
+        for drift_ppm in [-500.0, 0.0, 300.0].iter() {
+            let (bridge, io_ratio) = simulate(*drift_ppm, 120.0);
+            assert_eq!(0, bridge.underruns(), "{} ppm", drift_ppm);
+            assert!(
+                (bridge.filtered_fill() - 512.0).abs() < 20.0,
+                "{} ppm: fill {}",
+                drift_ppm,
+                bridge.filtered_fill()
+            );
+            let measured_ppm = (io_ratio - 1.0) * 1e6;
+            assert!(
+                (measured_ppm - drift_ppm).abs() < 50.0,
+                "{} ppm: ratio {}",
+                drift_ppm,
+                io_ratio
+            );
+        }
+    }
+
+    #[test]
+    fn test_silence_until_filled() {
+        let resampler =
+            VariableRateResampler::create(1000.0, 1000.0, 1.1, 1, None, None, None).unwrap();
+        let (mut producer, mut bridge) = ClockBridge::new(resampler, 1000, 500).unwrap();
+        producer.push(&[1.0f32; 100]);
+        let mut output = [1.0f32; 100];
+        bridge.pull(&mut output).unwrap();
+        assert!(output.iter().all(|sample| *sample == 0.0));
+        assert_eq!(100, bridge.fill());
+        assert_eq!(0, bridge.underruns());
+    }
+
+    #[test]
+    fn test_invalid_target_fill() {
+        let resampler =
+            VariableRateResampler::create(1000.0, 1000.0, 1.1, 1, None, None, None).unwrap();
+        assert!(ClockBridge::<f32>::new(resampler, 100, 100).is_err());
+    }
+
+    #[test]
+    fn test_invalid_max_deviation() {
+        let resampler =
+            VariableRateResampler::create(1000.0, 1000.0, 1.1, 1, None, None, None).unwrap();
+        let (_, mut bridge) = ClockBridge::<f32>::new(resampler, 1000, 100).unwrap();
+        for max_deviation in [0.0, -0.01, 1.0, f64::NAN].iter() {
+            assert!(bridge.set_max_deviation(*max_deviation).is_err());
+        }
+        assert!(bridge.set_max_deviation(0.01).is_ok());
+    }
+}
@@ -0,0 +1,151 @@
+//! Building blocks for resamplers that steer their io ratio by the amount of buffered input
+use crate::{
+    datatype::Sample,
+    error_handling::{Error, ErrorType, Result},
+    variable::VariableRateResampler,
+};
+
+/// PI controller that turns the buffered amount of input, in seconds, into a relative correction
+/// of the io ratio. The measured fill is low-pass filtered first, as it jumps with every block
+/// that is added or taken.
+#[derive(Debug, Clone)]
+pub(crate) struct FillController {
+    pub(crate) target: f64,
+    pub(crate) kp: f64,
+    pub(crate) ki: f64,
+    pub(crate) max_deviation: f64,
+    smoothing: f64,
+    filtered: f64,
+    integral: f64,
+}
+
+impl FillController {
+    pub(crate) fn new(target: f64, kp: f64, ki: f64, max_deviation: f64, smoothing: f64) -> Self {
+        FillController {
+            target,
+            kp,
+            ki,
+            max_deviation,
+            smoothing,
+            filtered: target,
+            integral: 0.0,
+        }
+    }
+
+    /// Filtered fill in seconds
+    pub(crate) fn filtered(&self) -> f64 {
+        self.filtered
+    }
+
+    /// Restarts filtering from `fill` and forgets the integrated error
+    pub(crate) fn reset(&mut self, fill: f64) {
+        self.filtered = fill;
+        self.integral = 0.0;
+    }
+
+    /// Takes a new `fill` measurement `dt` seconds after the previous one and returns the
+    /// relative correction of the io ratio
+    pub(crate) fn update(&mut self, fill: f64, dt: f64) -> f64 {
+        let alpha = (dt / self.smoothing).min(1.0);
+        self.filtered += alpha * (fill - self.filtered);
+
+        let error = self.filtered - self.target;
+        self.integral += error * dt;
+        // keep the integral within what the ratio can correct to avoid wind-up
+        if self.ki > 0.0 {
+            let limit = self.max_deviation / self.ki;
+            self.integral = self.integral.max(-limit).min(limit);
+        }
+        (self.kp * error + self.ki * self.integral)
+            .max(-self.max_deviation)
+            .min(self.max_deviation)
+    }
+}
+
+/// Checks that a maximum relative deviation of the io ratio is above 0 and below 1
+pub(crate) fn check_max_deviation(func: &'static str, max_deviation: f64) -> Result<()> {
+    if !(max_deviation > 0.0 && max_deviation < 1.0) {
+        return Err(Error::new(
+            Some(func.into()),
+            ErrorType::ChangeError(format!(
+                "max deviation {} is not between 0 and 1",
+                max_deviation
+            )),
+        ));
+    }
+    Ok(())
+}
+
+/// Feeds blocks of interleaved input to a [VariableRateResampler] no faster than it needs them,
+/// so that input does not pile up inside the resampler where a [FillController] cannot see it
+#[derive(Debug)]
+pub(crate) struct BlockFeeder<T> {
+    channels: usize,
+    input: Vec<T>,
+    input_pos: usize,
+}
+
+impl<T: Sample> BlockFeeder<T> {
+    pub(crate) fn new(channels: usize, block_frames: usize) -> Self {
+        BlockFeeder {
+            channels,
+            input: Vec::with_capacity(block_frames * channels),
+            input_pos: 0,
+        }
+    }
+
+    /// Frames taken from the source that the resampler did not consume yet
+    pub(crate) fn pending(&self) -> usize {
+        (self.input.len() - self.input_pos) / self.channels
+    }
+
+    /// Drops the pending frames
+    pub(crate) fn clear(&mut self) {
+        self.input.clear();
+        self.input_pos = 0;
+    }
+
+    /// Resamples into `buf_out`, calling `refill` whenever a new block of input is needed.
+    /// `refill` gets an empty vector to append whole frames to; when it appends nothing the
+    /// source has run dry. Returns the number of frames written to `buf_out`.
+    pub(crate) fn resample<F>(
+        &mut self,
+        func: &'static str,
+        resampler: &mut VariableRateResampler,
+        buf_out: &mut [T],
+        mut refill: F,
+    ) -> Result<usize>
+    where
+        F: FnMut(&mut Vec<T>),
+    {
+        let frames = buf_out.len() / self.channels;
+        let mut produced = 0;
+        while produced < frames {
+            if self.input_pos == self.input.len() {
+                self.clear();
+                refill(&mut self.input);
+                if self.input.is_empty() {
+                    break;
+                }
+            }
+            let needed = ((frames - produced) as f64 * resampler.io_ratio()).ceil() as usize;
+            let end = self
+                .input
+                .len()
+                .min(self.input_pos + needed.max(1) * self.channels);
+            let (idone, odone) = resampler.process(
+                Some(&self.input[self.input_pos..end]),
+                &mut buf_out[produced * self.channels..frames * self.channels],
+            )?;
+            if idone == 0 && odone == 0 {
+                return Err(Error::new(
+                    Some(func.into()),
+                    ErrorType::ProcessError("resampler did not accept input".into()),
+                ));
+            }
+            self.input_pos += idone * self.channels;
+            produced += odone;
+        }
+        Ok(produced)
+    }
+}
@@ -36,4 +36,131 @@ impl Datatype {
             Datatype::Float32S | Datatype::Float64S | Datatype::Int16S | Datatype::Int32S => false,
         }
     }
+
+    /// size of one sample in bytes
+    pub fn sample_size(&self) -> usize {
+        match self {
+            Datatype::Float64I | Datatype::Float64S => 8,
+            Datatype::Float32I | Datatype::Float32S | Datatype::Int32I | Datatype::Int32S => 4,
+            Datatype::Int16I | Datatype::Int16S => 2,
+        }
+    }
+
+    /// multiplies the native-endian sample at the start of `bytes` by `gain`, which is at most one
+    pub(crate) fn scale(self, bytes: &mut [u8], gain: f64) {
+        self.write(bytes, self.read(bytes) * gain);
+    }
+
+    /// reads the native-endian sample at the start of `bytes` as a value in [-1, 1)
+    pub(crate) fn read(self, bytes: &[u8]) -> f64 {
+        match self {
+            Datatype::Float32I | Datatype::Float32S => {
+                f32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64
+            }
+            Datatype::Float64I | Datatype::Float64S => {
+                let mut sample = [0; 8];
+                sample.copy_from_slice(&bytes[..8]);
+                f64::from_ne_bytes(sample)
+            }
+            Datatype::Int32I | Datatype::Int32S => {
+                i32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64 / 2147483648.0
+            }
+            Datatype::Int16I | Datatype::Int16S => {
+                i16::from_ne_bytes([bytes[0], bytes[1]]) as f64 / 32768.0
+            }
+        }
+    }
+
+    /// writes `value` in [-1, 1) as native-endian sample at the start of `bytes`, clipping
+    /// integer samples
+    pub(crate) fn write(self, bytes: &mut [u8], value: f64) {
+        match self {
+            Datatype::Float32I | Datatype::Float32S => {
+                bytes[..4].copy_from_slice(&(value as f32).to_ne_bytes())
+            }
+            Datatype::Float64I | Datatype::Float64S => {
+                bytes[..8].copy_from_slice(&value.to_ne_bytes())
+            }
+            Datatype::Int32I | Datatype::Int32S => {
+                // `as` saturates at the limits of the integer type
+                let sample = (value * 2147483648.0).round() as i32;
+                bytes[..4].copy_from_slice(&sample.to_ne_bytes())
+            }
+            Datatype::Int16I | Datatype::Int16S => {
+                let sample = (value * 32768.0).round() as i16;
+                bytes[..2].copy_from_slice(&sample.to_ne_bytes())
+            }
+        }
+    }
+}
+
+/// Byte order of samples in raw PCM byte streams
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Endianness {
+    Little,
+    Big,
+}
+
+impl Endianness {
+    /// byte order of the platform
+    pub fn native() -> Endianness {
+        if cfg!(target_endian = "big") {
+            Endianness::Big
+        } else {
+            Endianness::Little
+        }
+    }
+
+    /// converts samples of `sample_size` bytes in `bytes` between this byte order and the native one
+    pub(crate) fn convert(self, bytes: &mut [u8], sample_size: usize) {
+        if self != Endianness::native() {
+            for sample in bytes.chunks_exact_mut(sample_size) {
+                sample.reverse();
+            }
+        }
+    }
+}
+
+mod private {
+    pub trait Sealed {}
+
+    impl Sealed for f32 {}
+    impl Sealed for f64 {}
+    impl Sealed for i32 {}
+    impl Sealed for i16 {}
+}
+
+/// Rust types of samples that can be exchanged with the resampler. Buffers are handed to
+/// libsoxr sized by [Sample::DATATYPE], so the trait is sealed: it is only implemented for the
+/// types that match a libsoxr datatype.
+///
+/// ```compile_fail
+/// use libsoxr::{Datatype, Sample};
+///
+/// #[derive(Clone, Copy, Default)]
+/// struct Byte(u8);
+///
+/// impl Sample for Byte {
+///     const DATATYPE: Datatype = Datatype::Float64I;
+/// }
+/// ```
+pub trait Sample: private::Sealed + Copy + Default + Unpin + 'static {
+    /// Interleaved [Datatype] for buffers of this sample type
+    const DATATYPE: Datatype;
+}
+
+impl Sample for f32 {
+    const DATATYPE: Datatype = Datatype::Float32I;
+}
+
+impl Sample for f64 {
+    const DATATYPE: Datatype = Datatype::Float64I;
+}
+
+impl Sample for i32 {
+    const DATATYPE: Datatype = Datatype::Int32I;
+}
+
+impl Sample for i16 {
+    const DATATYPE: Datatype = Datatype::Int16I;
 }
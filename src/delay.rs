@@ -0,0 +1,470 @@
+//! Per-channel fractional delays
+use crate::{
+    control::BlockFeeder,
+    datatype::Sample,
+    error_handling::{Error, ErrorType, Result},
+    spec::{IOSpec, QualitySpec, RuntimeSpec},
+    variable::VariableRateResampler,
+};
+use std::collections::VecDeque;
+
+/// Output frames between io ratio updates
+const BLOCK_FRAMES: usize = 64;
+/// Largest change of a delay per output frame, which bounds the io ratio to `1 ± MAX_SLOPE`
+const MAX_SLOPE: f64 = 0.5;
+
+/// Delays each channel of a signal by its own, fractional, number of frames, for instance to
+/// steer a beamformer or to line up microphones. Every channel runs through its own
+/// [VariableRateResampler] at the sample rate, so the delays are interpolated with the filters of
+/// libsoxr, as chosen by the [QualitySpec].
+///
+/// Frame `n` of output channel `c` is the input of that channel at frame `n - delay[c]`. The
+/// whole frames of a delay are made by putting silence before the input, the fraction by
+/// steering the io ratio of the channel around one. Delays can change while running: the change
+/// is slewed linearly, and the channel briefly plays a bit slower or faster to get there.
+///
+/// ```rust
+/// use libsoxr::FractionalDelay;
+///
+/// // the second microphone is 0.3 frames further away than the first
+/// let mut delay = FractionalDelay::<f32>::create(48000.0, 2, &[0.3, 0.0], None, None).unwrap();
+///
+/// let source = [0.0f32; 2 * 4800];
+/// let mut target = [0.0f32; 2 * 4800];
+/// let frames = delay.process(&source, &mut target).unwrap();
+/// assert!(frames <= 4800);
+///
+/// // and then moves to 2.5 frames in 10 ms
+/// delay.set_delays(&[2.5, 0.0], 480).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct FractionalDelay<T> {
+    taps: Vec<Tap<T>>,
+    emitted: u64,
+}
+
+#[derive(Debug)]
+struct Tap<T> {
+    resampler: VariableRateResampler,
+    queue: VecDeque<T>,
+    // source position of the front of the queue
+    popped: f64,
+    feeder: BlockFeeder<T>,
+    block: Vec<T>,
+    output: VecDeque<T>,
+    produced: u64,
+    slew: Slew,
+}
+
+// delay that moves linearly from `from` to `to` over `len` output frames from frame `start`
+#[derive(Debug, Clone, Copy)]
+struct Slew {
+    start: u64,
+    len: u64,
+    from: f64,
+    to: f64,
+}
+
+impl Slew {
+    fn delay_at(&self, frame: u64) -> f64 {
+        if frame >= self.start + self.len {
+            return self.to;
+        }
+        let progress = frame.saturating_sub(self.start) as f64 / self.len as f64;
+        self.from + (self.to - self.from) * progress
+    }
+}
+
+impl<T: Sample> Tap<T> {
+    fn new(resampler: VariableRateResampler, delay: f64) -> Tap<T> {
+        let mut tap = Tap {
+            resampler,
+            queue: VecDeque::new(),
+            popped: 0.0,
+            feeder: BlockFeeder::new(1, BLOCK_FRAMES),
+            block: vec![T::default(); BLOCK_FRAMES],
+            output: VecDeque::new(),
+            produced: 0,
+            slew: Slew {
+                start: 0,
+                len: 0,
+                from: delay,
+                to: delay,
+            },
+        };
+        tap.restart(delay);
+        tap
+    }
+
+    // starts over at `delay`, with the whole frames of it as silence before the input
+    fn restart(&mut self, delay: f64) {
+        let whole = delay.floor();
+        self.queue.clear();
+        self.queue.resize(whole as usize, T::default());
+        self.popped = -whole;
+        self.feeder.clear();
+        self.output.clear();
+        self.produced = 0;
+        self.slew = Slew {
+            start: 0,
+            len: 0,
+            from: delay,
+            to: delay,
+        };
+    }
+
+    // source position of the frame the resampler outputs next
+    fn position(&self) -> f64 {
+        let consumed = self.popped - self.feeder.pending() as f64;
+        consumed - self.resampler.delay() * self.resampler.io_ratio()
+    }
+
+    // resamples blocks until the queued input runs out
+    fn run(&mut self) -> Result<()> {
+        loop {
+            let end = self.produced + BLOCK_FRAMES as u64;
+            let target = end as f64 - self.slew.delay_at(end);
+            let io_ratio = ((target - self.position()) / BLOCK_FRAMES as f64)
+                .clamp(1.0 - MAX_SLOPE, 1.0 + MAX_SLOPE);
+            self.resampler.set_io_ratio(io_ratio, 0)?;
+
+            let (queue, popped) = (&mut self.queue, &mut self.popped);
+            let produced = self.feeder.resample(
+                "FractionalDelay::process",
+                &mut self.resampler,
+                &mut self.block,
+                |input| {
+                    let count = queue.len().min(BLOCK_FRAMES);
+                    input.extend(queue.drain(..count));
+                    *popped += count as f64;
+                },
+            )?;
+            self.output.extend(self.block[..produced].iter().copied());
+            self.produced += produced as u64;
+            if produced < BLOCK_FRAMES {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<T: Sample> FractionalDelay<T> {
+    /// Creates a delay line at `sample_rate` with one channel per entry of `delays`, in frames,
+    /// which must not be negative. When `quality_spec` is `None` the default of
+    /// [VariableRateResampler::create] is used.
+    pub fn create(
+        sample_rate: f64,
+        num_channels: u32,
+        delays: &[f64],
+        quality_spec: Option<&QualitySpec>,
+        runtime_spec: Option<&RuntimeSpec>,
+    ) -> Result<FractionalDelay<T>> {
+        if num_channels == 0 {
+            return Err(Error::new(
+                Some("FractionalDelay::create".into()),
+                ErrorType::CreateError("at least one channel is needed".into()),
+            ));
+        }
+        check_delays(
+            "FractionalDelay::create",
+            num_channels as usize,
+            delays,
+            ErrorType::CreateError,
+        )?;
+        let io_spec = IOSpec::new(T::DATATYPE, T::DATATYPE);
+        let mut taps = Vec::with_capacity(delays.len());
+        for delay in delays {
+            let resampler = VariableRateResampler::create(
+                sample_rate,
+                sample_rate,
+                1.0 + MAX_SLOPE,
+                1,
+                Some(&io_spec),
+                quality_spec,
+                runtime_spec,
+            )?;
+            taps.push(Tap::new(resampler, *delay));
+        }
+        Ok(FractionalDelay { taps, emitted: 0 })
+    }
+
+    /// Number of channels
+    pub fn num_channels(&self) -> usize {
+        self.taps.len()
+    }
+
+    /// Delays in frames at the next output frame, following ongoing slews
+    pub fn delays(&self) -> Vec<f64> {
+        self.taps
+            .iter()
+            .map(|tap| tap.slew.delay_at(self.emitted))
+            .collect()
+    }
+
+    /// Delays in frames the channels are heading for
+    pub fn target_delays(&self) -> Vec<f64> {
+        self.taps.iter().map(|tap| tap.slew.to).collect()
+    }
+
+    /// Moves the delays linearly to `delays` over the next `slew_len` output frames, or
+    /// immediately when `slew_len` is zero. A delay can change by at most half a frame per
+    /// output frame; a faster change is followed as fast as that allows. Output that is ready
+    /// but not returned yet keeps the delays it was made with, the slew starts after it.
+    pub fn set_delays(&mut self, delays: &[f64], slew_len: usize) -> Result<()> {
+        check_delays(
+            "FractionalDelay::set_delays",
+            self.taps.len(),
+            delays,
+            ErrorType::ChangeError,
+        )?;
+        for (tap, delay) in self.taps.iter_mut().zip(delays.iter()) {
+            // the output the tap holds is made already
+            tap.slew = Slew {
+                start: tap.produced,
+                len: slew_len as u64,
+                from: tap.slew.delay_at(tap.produced),
+                to: *delay,
+            };
+        }
+        Ok(())
+    }
+
+    /// Delays the interleaved frames in `buf_in` and writes as many delayed frames as are ready
+    /// to `buf_out`. Returns the number of frames written; the resamplers hold back a few
+    /// hundred frames, which come out with later input.
+    pub fn process(&mut self, buf_in: &[T], buf_out: &mut [T]) -> Result<usize> {
+        let channels = self.taps.len();
+        let frames = buf_in.len() / channels;
+        for (channel, tap) in self.taps.iter_mut().enumerate() {
+            tap.queue.extend(
+                buf_in[..frames * channels]
+                    .iter()
+                    .skip(channel)
+                    .step_by(channels)
+                    .copied(),
+            );
+            tap.run()?;
+        }
+
+        let ready = self
+            .taps
+            .iter()
+            .map(|tap| tap.output.len())
+            .min()
+            .unwrap_or(0)
+            .min(buf_out.len() / channels);
+        for (channel, tap) in self.taps.iter_mut().enumerate() {
+            for (frame, sample) in buf_out
+                .chunks_exact_mut(channels)
+                .zip(tap.output.drain(..ready))
+            {
+                frame[channel] = sample;
+            }
+        }
+        self.emitted += ready as u64;
+        Ok(ready)
+    }
+
+    /// Drops all pending input and output and starts over at the target delays
+    pub fn clear(&mut self) -> Result<()> {
+        for tap in self.taps.iter_mut() {
+            tap.resampler.clear()?;
+            let delay = tap.slew.to;
+            tap.restart(delay);
+        }
+        self.emitted = 0;
+        Ok(())
+    }
+}
+
+fn check_delays(
+    func: &'static str,
+    num_channels: usize,
+    delays: &[f64],
+    error: fn(String) -> ErrorType,
+) -> Result<()> {
+    if delays.len() != num_channels {
+        return Err(Error::new(
+            Some(func.into()),
+            error(format!(
+                "{} delays for {} channels",
+                delays.len(),
+                num_channels
+            )),
+        ));
+    }
+    if let Some(delay) = delays
+        .iter()
+        .find(|delay| !(delay.is_finite() && **delay >= 0.0))
+    {
+        return Err(Error::new(
+            Some(func.into()),
+            error(format!(
+                "delay {} is not a finite number of frames >= 0",
+                delay
+            )),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod delay_tests {
+    use super::FractionalDelay;
+
+    const RATE: f64 = 8000.0;
+    const FREQUENCY: f64 = 440.0;
+
+    fn sine(position: f64) -> f32 {
+        (2.0 * std::f64::consts::PI * FREQUENCY * position / RATE).sin() as f32
+    }
+
+    // runs `frames` frames of a sine on every channel through `delay` in chunks, changing the
+    // delays with `change` before every chunk, and returns the output
+    fn run<F>(delay: &mut FractionalDelay<f32>, frames: usize, mut change: F) -> Vec<f32>
+    where
+        F: FnMut(&mut FractionalDelay<f32>, usize),
+    {
+        let channels = delay.num_channels();
+        let source: Vec<f32> = (0..frames)
+            .flat_map(|n| vec![sine(n as f64); channels])
+            .collect();
+        let mut output = Vec::new();
+        let mut chunk = vec![0.0f32; 1024 * channels];
+        for input in source.chunks(250 * channels) {
+            change(delay, output.len() / channels);
+            let written = delay.process(input, &mut chunk).unwrap();
+            output.extend_from_slice(&chunk[..written * channels]);
+        }
+        output
+    }
+
+    #[test]
+    fn test_constant_delays() {
+        let delays = [0.0, 0.25, 0.5, 1.75, 10.3];
+        let mut delay = FractionalDelay::create(RATE, 5, &delays, None, None).unwrap();
+        let output = run(&mut delay, 8000, |_, _| {});
+        assert!(output.len() > 7000 * 5);
+        for (n, frame) in output.chunks_exact(5).enumerate().skip(100) {
+            for (sample, delay) in frame.iter().zip(delays.iter()) {
+                let expected = sine(n as f64 - delay);
+                assert!((sample - expected).abs() < 2e-3, "{} at {}", delay, n);
+            }
+        }
+        assert_eq!(delays.to_vec(), delay.delays());
+    }
+
+    #[test]
+    fn test_slewed_delays() {
+        let mut delay = FractionalDelay::create(RATE, 2, &[0.0, 3.0], None, None).unwrap();
+        // from 1000 frames on, move to 4.5 and 0.25 over 2000 frames
+        let mut changed = false;
+        let output = run(&mut delay, 8000, |delay, emitted| {
+            if emitted >= 1000 && !changed {
+                assert_eq!(vec![0.0, 3.0], delay.delays());
+                delay.set_delays(&[4.5, 0.25], 2000).unwrap();
+                changed = true;
+            }
+        });
+        assert!(changed);
+        assert_eq!(vec![4.5, 0.25], delay.delays());
+        assert_eq!(vec![4.5, 0.25], delay.target_delays());
+
+        let start = output[..]
+            .chunks_exact(2)
+            .enumerate()
+            .skip(1000)
+            .position(|(n, frame)| {
+                // the first frame with the slew under way, to find when it started
+                (frame[1] - sine(n as f64 - 3.0)).abs() > 2e-3
+            })
+            .unwrap()
+            + 1000;
+        let slew = |from: f64, to: f64, n: usize| {
+            let progress = (n.saturating_sub(start) as f64 / 2000.0).min(1.0);
+            from + (to - from) * progress
+        };
+        for (n, frame) in output.chunks_exact(2).enumerate().skip(100) {
+            let expected = [
+                sine(n as f64 - slew(0.0, 4.5, n)),
+                sine(n as f64 - slew(3.0, 0.25, n)),
+            ];
+            // the io ratio changes once per block, so the delays follow the slew in steps
+            assert!((frame[0] - expected[0]).abs() < 0.02, "at {}", n);
+            assert!((frame[1] - expected[1]).abs() < 0.02, "at {}", n);
+        }
+    }
+
+    #[test]
+    fn test_slew_starts_after_ready_output() {
+        let mut delay = FractionalDelay::create(RATE, 1, &[0.0], None, None).unwrap();
+        let source: Vec<f32> = (0..8000).map(|n| sine(n as f64)).collect();
+        // a small output buffer leaves thousands of frames ready in the delay
+        let mut chunk = [0.0f32; 50];
+        let mut output = Vec::new();
+        for (index, input) in source.chunks(250).enumerate() {
+            if index == 20 {
+                delay.set_delays(&[1.0], 200).unwrap();
+            }
+            let written = delay.process(input, &mut chunk).unwrap();
+            output.extend_from_slice(&chunk[..written]);
+        }
+        let mut chunk = [0.0f32; 8000];
+        let written = delay.process(&[], &mut chunk).unwrap();
+        output.extend_from_slice(&chunk[..written]);
+        assert!(output.len() > 7000);
+
+        // everything up to the first frame made after the change is at the old delay
+        let start = output
+            .iter()
+            .enumerate()
+            .skip(100)
+            .position(|(n, sample)| (sample - sine(n as f64)).abs() > 2e-3)
+            .unwrap()
+            + 100;
+        assert!(start > 3000, "{}", start);
+        for (n, sample) in output.iter().enumerate().skip(start) {
+            let slewed = ((n - start) as f64 / 200.0).min(1.0);
+            assert!((sample - sine(n as f64 - slewed)).abs() < 0.02, "at {}", n);
+        }
+    }
+
+    #[test]
+    fn test_jump_is_limited() {
+        let mut delay = FractionalDelay::create(RATE, 1, &[0.0], None, None).unwrap();
+        let mut changed = false;
+        let output = run(&mut delay, 4000, |delay, emitted| {
+            if emitted >= 1000 && !changed {
+                delay.set_delays(&[100.0], 0).unwrap();
+                changed = true;
+            }
+        });
+        // half a frame per frame: caught up 200 frames later
+        for (n, sample) in output.iter().enumerate().skip(1500) {
+            assert!((sample - sine(n as f64 - 100.0)).abs() < 2e-3, "at {}", n);
+        }
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut delay = FractionalDelay::create(RATE, 1, &[0.5], None, None).unwrap();
+        run(&mut delay, 2000, |_, _| {});
+        delay.set_delays(&[2.25], 100).unwrap();
+        delay.clear().unwrap();
+        assert_eq!(vec![2.25], delay.delays());
+        let output = run(&mut delay, 2000, |_, _| {});
+        for (n, sample) in output.iter().enumerate().skip(100) {
+            assert!((sample - sine(n as f64 - 2.25)).abs() < 2e-3, "at {}", n);
+        }
+    }
+
+    #[test]
+    fn test_invalid_delays() {
+        assert!(FractionalDelay::<f32>::create(RATE, 2, &[0.0], None, None).is_err());
+        assert!(FractionalDelay::<f32>::create(RATE, 1, &[-0.5], None, None).is_err());
+        assert!(FractionalDelay::<f32>::create(RATE, 0, &[], None, None).is_err());
+        let mut delay = FractionalDelay::<f32>::create(RATE, 1, &[0.0], None, None).unwrap();
+        assert!(delay.set_delays(&[f64::NAN], 0).is_err());
+        assert!(delay.set_delays(&[1.0, 2.0], 0).is_err());
+    }
+}
@@ -0,0 +1,277 @@
+//! Clock drift estimation from timestamps
+use crate::{error_handling::Result, variable::VariableRateResampler};
+use std::collections::VecDeque;
+
+/// Number of standard errors between the estimate and its bounds, for about 95% confidence
+const CONFIDENCE_FACTOR: f64 = 2.0;
+
+/// Rate ratio estimated by a [DriftEstimator]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DriftEstimate {
+    /// Remote sample rate divided by its nominal rate, as measured against the local clock
+    pub ratio: f64,
+    /// Lower bound of `ratio`, at about 95% confidence
+    pub lower: f64,
+    /// Upper bound of `ratio`, at about 95% confidence
+    pub upper: f64,
+    /// Number of observations the estimate is based on
+    pub observations: usize,
+}
+
+impl DriftEstimate {
+    /// Deviation of the remote clock from its nominal rate in parts per million
+    pub fn ppm(&self) -> f64 {
+        (self.ratio - 1.0) * 1e6
+    }
+
+    /// Half the width of the confidence interval in parts per million
+    pub fn uncertainty_ppm(&self) -> f64 {
+        (self.upper - self.lower) / 2.0 * 1e6
+    }
+}
+
+/// Estimates how fast a remote clock runs compared to the local clock, from observations of
+/// the remote sample position at local times, as carried by RTP timestamps with RTCP sender
+/// reports or by PTP. The estimate is a least squares fit of a line through the most recent
+/// observations, which filters out the noise on the individual timestamps, together with
+/// confidence bounds derived from the scatter around that line.
+///
+/// The estimate can drive a [VariableRateResampler] that converts the remote stream to the local
+/// rate, see [DriftEstimator::apply].
+///
+/// ```rust
+/// use libsoxr::DriftEstimator;
+///
+/// // a remote 48 kHz clock that runs 100 ppm fast
+/// let mut estimator = DriftEstimator::new(48000.0, 32);
+/// for n in 0..32u64 {
+///     let local_time = n as f64 * 0.1;
+///     estimator.observe(local_time, (local_time * 48000.0 * 1.0001).round() as u64);
+/// }
+/// let estimate = estimator.estimate().unwrap();
+/// assert!((estimate.ppm() - 100.0).abs() < 1.0);
+/// ```
+#[derive(Debug, Clone)]
+pub struct DriftEstimator {
+    nominal_rate: f64,
+    window: usize,
+    observations: VecDeque<(f64, u64)>,
+}
+
+impl DriftEstimator {
+    /// Creates an estimator for a remote stream with a nominal rate of `nominal_rate` frames per
+    /// second, that fits the last `window` observations. The window needs at least 3
+    /// observations.
+    pub fn new(nominal_rate: f64, window: usize) -> DriftEstimator {
+        DriftEstimator {
+            nominal_rate,
+            window: window.max(3),
+            observations: VecDeque::with_capacity(window.max(3)),
+        }
+    }
+
+    /// Nominal rate of the remote stream
+    pub fn nominal_rate(&self) -> f64 {
+        self.nominal_rate
+    }
+
+    /// Records that the remote stream was at frame `remote_position` at local time
+    /// `local_time`, in seconds
+    pub fn observe(&mut self, local_time: f64, remote_position: u64) {
+        if self.observations.len() == self.window {
+            self.observations.pop_front();
+        }
+        self.observations.push_back((local_time, remote_position));
+    }
+
+    /// Forgets all observations, for instance after the remote stream restarted
+    pub fn clear(&mut self) {
+        self.observations.clear();
+    }
+
+    /// Current estimate, or `None` while there are fewer than 3 observations or all were made at
+    /// the same local time
+    pub fn estimate(&self) -> Option<DriftEstimate> {
+        let fit = self.fit()?;
+        Some(DriftEstimate {
+            ratio: fit.rate / self.nominal_rate,
+            lower: (fit.rate - CONFIDENCE_FACTOR * fit.std_error) / self.nominal_rate,
+            upper: (fit.rate + CONFIDENCE_FACTOR * fit.std_error) / self.nominal_rate,
+            observations: self.observations.len(),
+        })
+    }
+
+    /// Remote position at `local_time` on the fitted line, or `None` when there is no estimate
+    pub fn position_at(&self, local_time: f64) -> Option<f64> {
+        self.fit()
+            .map(|fit| fit.position + fit.rate * (local_time - fit.time))
+    }
+
+    /// Local time at `remote_position` on the fitted line, or `None` when there is no estimate
+    pub fn time_at(&self, remote_position: f64) -> Option<f64> {
+        self.fit()
+            .map(|fit| fit.time + (remote_position - fit.position) / fit.rate)
+    }
+
+    /// Io ratio that converts the remote stream to `output_rate` at the local clock
+    pub fn io_ratio(&self, output_rate: f64) -> Option<f64> {
+        self.estimate()
+            .map(|estimate| estimate.ratio * self.nominal_rate / output_rate)
+    }
+
+    /// Sets the io ratio of `resampler` to the estimated one, slewing over `slew_len` output
+    /// frames. Leaves `resampler` alone and returns `false` when there is no estimate yet.
+    pub fn apply(&self, resampler: &mut VariableRateResampler, slew_len: usize) -> Result<bool> {
+        match self.io_ratio(resampler.output_rate()) {
+            Some(io_ratio) => resampler.set_io_ratio(io_ratio, slew_len).map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    // least squares fit of the remote position against the local time
+    fn fit(&self) -> Option<Fit> {
+        let n = self.observations.len();
+        if n < 3 {
+            return None;
+        }
+        // work relative to the first observation to keep the sums precise
+        let (t0, p0) = self.observations[0];
+        let points = || {
+            self.observations
+                .iter()
+                .map(move |(t, p)| (t - t0, (*p as i128 - p0 as i128) as f64))
+        };
+        let mean_t = points().map(|(t, _)| t).sum::<f64>() / n as f64;
+        let mean_p = points().map(|(_, p)| p).sum::<f64>() / n as f64;
+        let (mut stt, mut stp) = (0.0, 0.0);
+        for (t, p) in points() {
+            stt += (t - mean_t) * (t - mean_t);
+            stp += (t - mean_t) * (p - mean_p);
+        }
+        if stt <= 0.0 {
+            return None;
+        }
+
+        let rate = stp / stt;
+        let residuals: f64 = points()
+            .map(|(t, p)| {
+                let residual = p - mean_p - rate * (t - mean_t);
+                residual * residual
+            })
+            .sum();
+        Some(Fit {
+            time: t0 + mean_t,
+            position: p0 as f64 + mean_p,
+            rate,
+            std_error: (residuals / (n - 2) as f64 / stt).sqrt(),
+        })
+    }
+}
+
+// A line through the observations: `rate` frames per second through (`time`, `position`)
+struct Fit {
+    time: f64,
+    position: f64,
+    rate: f64,
+    std_error: f64,
+}
+
+#[cfg(test)]
+mod drift_tests {
+    use approx::assert_abs_diff_eq;
+
+    use super::DriftEstimator;
+    use crate::{test_util::noisy_arrivals, VariableRateResampler};
+
+    // Observes a 48 kHz remote clock running `ppm` fast every 100 ms, from `start` seconds on,
+    // with up to `noise` seconds of uniform noise on the local timestamps
+    fn observe(estimator: &mut DriftEstimator, ppm: f64, start: f64, seconds: f64, noise: f64) {
+        let steps = (seconds * 10.0) as usize;
+        for observation in noisy_arrivals(0x9e37_79b9_7f4a_7c15, 0.1, 0.0, |_| noise).take(steps) {
+            let local_time = start + observation.sent;
+            let remote_position = (local_time * 48000.0 * (1.0 + ppm * 1e-6)).round() as u64;
+            estimator.observe(start + observation.arrival, remote_position);
+        }
+    }
+
+    #[test]
+    fn test_exact_offsets() {
+        for ppm in [-250.0, 0.0, 40.0, 1000.0].iter() {
+            let mut estimator = DriftEstimator::new(48000.0, 64);
+            observe(&mut estimator, *ppm, 10.0, 10.0, 0.0);
+            let estimate = estimator.estimate().unwrap();
+            assert_eq!(64, estimate.observations);
+            // only the rounding of the positions to whole frames is left as noise
+            assert_abs_diff_eq!(*ppm, estimate.ppm(), epsilon = 1.0);
+            assert!(estimate.uncertainty_ppm() < 2.0);
+        }
+    }
+
+    #[test]
+    fn test_noisy_offsets() {
+        for ppm in [-80.0, 120.0].iter() {
+            let mut estimator = DriftEstimator::new(48000.0, 600);
+            // 2 ms of timestamp noise on observations every 100 ms
+            observe(&mut estimator, *ppm, 0.0, 60.0, 0.002);
+            let estimate = estimator.estimate().unwrap();
+            assert!(estimate.lower <= 1.0 + ppm * 1e-6, "{} ppm", ppm);
+            assert!(estimate.upper >= 1.0 + ppm * 1e-6, "{} ppm", ppm);
+            assert!(estimate.uncertainty_ppm() < 50.0, "{} ppm", ppm);
+            assert_abs_diff_eq!(*ppm, estimate.ppm(), epsilon = 50.0);
+        }
+    }
+
+    #[test]
+    fn test_more_noise_wider_bounds() {
+        let uncertainty = |noise| {
+            let mut estimator = DriftEstimator::new(48000.0, 100);
+            observe(&mut estimator, 100.0, 0.0, 10.0, noise);
+            estimator.estimate().unwrap().uncertainty_ppm()
+        };
+        assert!(uncertainty(0.004) > 2.0 * uncertainty(0.001));
+    }
+
+    #[test]
+    fn test_window_follows_change() {
+        let mut estimator = DriftEstimator::new(48000.0, 50);
+        observe(&mut estimator, 300.0, 0.0, 10.0, 0.0);
+        observe(&mut estimator, -300.0, 10.0, 10.0, 0.0);
+        assert_abs_diff_eq!(-300.0, estimator.estimate().unwrap().ppm(), epsilon = 2.0);
+    }
+
+    #[test]
+    fn test_position_and_time() {
+        let mut estimator = DriftEstimator::new(48000.0, 64);
+        observe(&mut estimator, 200.0, 100.0, 10.0, 0.0);
+        let position = estimator.position_at(120.0).unwrap();
+        assert_abs_diff_eq!(120.0 * 48000.0 * 1.0002, position, epsilon = 1.0);
+        assert_abs_diff_eq!(120.0, estimator.time_at(position).unwrap(), epsilon = 1e-9);
+    }
+
+    #[test]
+    fn test_too_few_observations() {
+        let mut estimator = DriftEstimator::new(48000.0, 10);
+        estimator.observe(0.0, 0);
+        estimator.observe(0.1, 4800);
+        assert_eq!(None, estimator.estimate());
+        assert_eq!(None, estimator.position_at(0.2));
+        estimator.observe(0.2, 9600);
+        assert!(estimator.estimate().is_some());
+        estimator.clear();
+        assert_eq!(None, estimator.estimate());
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut resampler =
+            VariableRateResampler::create(48000.0, 44100.0, 1.2, 1, None, None, None).unwrap();
+        let mut estimator = DriftEstimator::new(48000.0, 64);
+        assert!(!estimator.apply(&mut resampler, 0).unwrap());
+        assert_eq!(48000.0 / 44100.0, resampler.io_ratio());
+
+        observe(&mut estimator, 500.0, 0.0, 10.0, 0.0);
+        assert!(estimator.apply(&mut resampler, 0).unwrap());
+        let expected = 48000.0 * 1.0005 / 44100.0;
+        assert_abs_diff_eq!(expected, resampler.io_ratio(), epsilon = 1e-6);
+    }
+}
@@ -0,0 +1,219 @@
+//! Ratio automation for variable-rate resampling
+use crate::error_handling::{Error, ErrorType, Result};
+use std::collections::VecDeque;
+
+/// Length in output frames of the linear pieces that approximate an exponential ramp
+const EXPONENTIAL_PIECE: u64 = 64;
+
+/// How the io ratio moves from the previous breakpoint to a [Breakpoint]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ramp {
+    /// Changes by the same amount every output frame
+    Linear,
+    /// Changes by the same factor every output frame, so that musical pitch glides evenly
+    Exponential,
+}
+
+/// Io ratio to reach at an output frame position
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Breakpoint {
+    /// Output frame, counted from the start of the stream
+    pub frame: u64,
+    /// Io ratio at `frame`
+    pub io_ratio: f64,
+    /// How the ratio gets there from the previous breakpoint
+    pub ramp: Ramp,
+}
+
+/// A curve of io ratios over output frames to schedule on a
+/// [VariableRateResampler](crate::variable::VariableRateResampler). The curve starts at the
+/// position and io ratio of the resampler when it is scheduled and runs through the breakpoints
+/// in order. After the last breakpoint the io ratio stays put.
+///
+/// ```rust
+/// use libsoxr::RatioEnvelope;
+///
+/// // tape stop: slow down to a tenth of the speed in one second at 48 kHz, then speed up again
+/// let envelope = RatioEnvelope::new()
+///     .exponential_to(48000, 0.1)
+///     .linear_to(96000, 1.0);
+/// assert_eq!(2, envelope.breakpoints().len());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct RatioEnvelope {
+    breakpoints: Vec<Breakpoint>,
+}
+
+/// A single call to `soxr_set_io_ratio` due at an output frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct RatioChange {
+    pub(crate) frame: u64,
+    pub(crate) io_ratio: f64,
+    pub(crate) slew_len: usize,
+}
+
+impl RatioEnvelope {
+    /// Creates an empty envelope
+    pub fn new() -> RatioEnvelope {
+        RatioEnvelope::default()
+    }
+
+    /// Adds a breakpoint
+    pub fn push(mut self, breakpoint: Breakpoint) -> RatioEnvelope {
+        self.breakpoints.push(breakpoint);
+        self
+    }
+
+    /// Adds a breakpoint reached by a [Ramp::Linear] ramp
+    pub fn linear_to(self, frame: u64, io_ratio: f64) -> RatioEnvelope {
+        self.push(Breakpoint {
+            frame,
+            io_ratio,
+            ramp: Ramp::Linear,
+        })
+    }
+
+    /// Adds a breakpoint reached by a [Ramp::Exponential] ramp
+    pub fn exponential_to(self, frame: u64, io_ratio: f64) -> RatioEnvelope {
+        self.push(Breakpoint {
+            frame,
+            io_ratio,
+            ramp: Ramp::Exponential,
+        })
+    }
+
+    /// The breakpoints of this envelope
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    /// Io ratio of the envelope at output frame `frame` when it starts at `from_frame` with
+    /// `from_ratio`
+    pub fn io_ratio_at(&self, from_frame: u64, from_ratio: f64, frame: u64) -> f64 {
+        let (mut start, mut ratio) = (from_frame, from_ratio);
+        if frame <= from_frame {
+            return from_ratio;
+        }
+        for breakpoint in self.breakpoints.iter() {
+            let end = breakpoint.frame.max(start);
+            if frame < end {
+                let progress = (frame - start) as f64 / (end - start) as f64;
+                return interpolate(breakpoint.ramp, ratio, breakpoint.io_ratio, progress);
+            }
+            start = end;
+            ratio = breakpoint.io_ratio;
+        }
+        ratio
+    }
+
+    // Turns the envelope into the ratio changes for libsoxr, which only slews linearly.
+    // Breakpoints before `from_frame` take effect immediately.
+    pub(crate) fn changes(
+        &self,
+        from_frame: u64,
+        from_ratio: f64,
+        max_io_ratio: f64,
+    ) -> Result<VecDeque<RatioChange>> {
+        let mut changes = VecDeque::new();
+        let (mut start, mut ratio) = (from_frame, from_ratio);
+        let mut previous = 0;
+        for breakpoint in self.breakpoints.iter() {
+            if breakpoint.frame < previous {
+                return Err(Error::new(
+                    Some("RatioEnvelope".into()),
+                    ErrorType::ChangeError("breakpoints are not in order".into()),
+                ));
+            }
+            if !(breakpoint.io_ratio > 0.0 && breakpoint.io_ratio <= max_io_ratio) {
+                return Err(Error::new(
+                    Some("RatioEnvelope".into()),
+                    ErrorType::ChangeError(format!(
+                        "io ratio {} outside of (0, {}]",
+                        breakpoint.io_ratio, max_io_ratio
+                    )),
+                ));
+            }
+            previous = breakpoint.frame;
+
+            let end = breakpoint.frame.max(start);
+            let pieces = match breakpoint.ramp {
+                Ramp::Linear => 1,
+                Ramp::Exponential => ((end - start) / EXPONENTIAL_PIECE).max(1),
+            };
+            for piece in 0..pieces {
+                let piece_start = start + (end - start) * piece / pieces;
+                let piece_end = start + (end - start) * (piece + 1) / pieces;
+                let progress = if end == start {
+                    1.0
+                } else {
+                    (piece_end - start) as f64 / (end - start) as f64
+                };
+                changes.push_back(RatioChange {
+                    frame: piece_start,
+                    io_ratio: interpolate(breakpoint.ramp, ratio, breakpoint.io_ratio, progress),
+                    slew_len: (piece_end - piece_start) as usize,
+                });
+            }
+            start = end;
+            ratio = breakpoint.io_ratio;
+        }
+        Ok(changes)
+    }
+}
+
+fn interpolate(ramp: Ramp, from: f64, to: f64, progress: f64) -> f64 {
+    match ramp {
+        Ramp::Linear => from + (to - from) * progress,
+        Ramp::Exponential => from * (to / from).powf(progress),
+    }
+}
+
+#[cfg(test)]
+mod envelope_tests {
+    use approx::assert_abs_diff_eq;
+
+    use super::{RatioChange, RatioEnvelope};
+
+    #[test]
+    fn test_linear_changes() {
+        let envelope = RatioEnvelope::new().linear_to(100, 2.0).linear_to(100, 0.5);
+        let changes: Vec<RatioChange> = envelope.changes(20, 1.0, 2.0).unwrap().into();
+        assert_eq!(
+            vec![
+                RatioChange {
+                    frame: 20,
+                    io_ratio: 2.0,
+                    slew_len: 80
+                },
+                RatioChange {
+                    frame: 100,
+                    io_ratio: 0.5,
+                    slew_len: 0
+                }
+            ],
+            changes
+        );
+    }
+
+    #[test]
+    fn test_exponential_changes() {
+        let envelope = RatioEnvelope::new().exponential_to(640, 2.0);
+        let changes = envelope.changes(0, 0.5, 2.0).unwrap();
+        assert_eq!(10, changes.len());
+        // pieces end on the exponential curve
+        for change in changes.iter() {
+            let end = change.frame + change.slew_len as u64;
+            assert_abs_diff_eq!(envelope.io_ratio_at(0, 0.5, end), change.io_ratio);
+        }
+        assert_abs_diff_eq!(1.0, envelope.io_ratio_at(0, 0.5, 320), epsilon = 1e-12);
+        assert_eq!(2.0, changes.back().unwrap().io_ratio);
+    }
+
+    #[test]
+    fn test_invalid_envelopes() {
+        let envelope = RatioEnvelope::new().linear_to(100, 2.0).linear_to(50, 1.0);
+        assert!(envelope.changes(0, 1.0, 2.0).is_err());
+        let envelope = RatioEnvelope::new().linear_to(100, 3.0);
+        assert!(envelope.changes(0, 1.0, 2.0).is_err());
+    }
+}
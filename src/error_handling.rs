@@ -48,4 +48,10 @@ impl fmt::Display for Error {
     }
 }
 
+impl From<Error> for ::std::io::Error {
+    fn from(error: Error) -> ::std::io::Error {
+        ::std::io::Error::other(error)
+    }
+}
+
 pub type Result<T> = ::std::result::Result<T, Error>;
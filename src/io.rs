@@ -0,0 +1,551 @@
+//! `std::io` adapters that resample raw PCM byte streams
+use crate::{
+    datatype::Endianness,
+    error_handling::{Error, ErrorType, Result},
+    soxr::Soxr,
+    wrapper_helpers::{as_bytes, as_bytes_mut},
+};
+use std::io::{self, Read, Write};
+
+/// Number of input frames buffered before they are resampled
+const BLOCK_FRAMES: usize = 1024;
+/// Extra room in output frames made available for each call to the resampler
+const OUTPUT_MARGIN: usize = 1024;
+
+/// Reads raw PCM bytes from an inner reader and resamples them. The bytes are interpreted
+/// according to the input [Datatype](crate::datatype::Datatype) of the [IOSpec](crate::spec::IOSpec)
+/// of the resampler and are read back in its output `Datatype`, both in the given byte order.
+///
+/// Frames split over several reads of the inner reader are carried over; an incomplete frame
+/// at the end of the inner stream is dropped. Only interleaved datatypes are supported.
+///
+/// ```rust
+/// use std::io::Read;
+/// use libsoxr::{Datatype, Endianness, IOSpec, Soxr, SoxrReader};
+///
+/// // 100 frames of 16 bit little endian mono PCM
+/// let pcm: Vec<u8> = (0..100i16).flat_map(|n| (n * 100).to_le_bytes()).collect();
+///
+/// let io_spec = IOSpec::new(Datatype::Int16I, Datatype::Int16I);
+/// let soxr = Soxr::create(1.0, 2.0, 1, Some(&io_spec), None, None).unwrap();
+/// let mut reader = SoxrReader::new(&pcm[..], soxr, Endianness::Little).unwrap();
+///
+/// let mut resampled = Vec::new();
+/// reader.read_to_end(&mut resampled).unwrap();
+/// assert_eq!(2 * 200, resampled.len());
+/// ```
+pub struct SoxrReader<R> {
+    inner: R,
+    resampler: ByteResampler,
+    output_pos: usize,
+    eof: bool,
+    finished: bool,
+}
+
+impl<R: Read> SoxrReader<R> {
+    /// Resamples the bytes read from `inner` using `soxr`. Samples in both the inner stream and
+    /// the resampled stream are in `endianness` byte order.
+    pub fn new(inner: R, soxr: Soxr, endianness: Endianness) -> Result<SoxrReader<R>> {
+        Ok(SoxrReader {
+            inner,
+            resampler: ByteResampler::new("SoxrReader::new", soxr, endianness)?,
+            output_pos: 0,
+            eof: false,
+            finished: false,
+        })
+    }
+
+    /// Gets a reference to the inner reader
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Gets a reference to the resampler
+    pub fn soxr(&self) -> &Soxr {
+        &self.resampler.soxr
+    }
+
+    /// Unwraps this `SoxrReader`, returning the inner reader. Input that has not been resampled
+    /// yet is lost.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn refill(&mut self) -> io::Result<()> {
+        self.resampler.output_len = 0;
+        self.output_pos = 0;
+
+        if self.eof {
+            self.finished = self.resampler.drain()? == 0;
+            return Ok(());
+        }
+
+        let read = loop {
+            match self.inner.read(self.resampler.input_space()) {
+                Ok(read) => break read,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => return Err(error),
+            }
+        };
+        self.eof = read == 0;
+        self.resampler.input_len += read;
+        self.resampler.resample_input()
+    }
+}
+
+impl<R: Read> Read for SoxrReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.output_pos == self.resampler.output_len {
+            if self.finished || buf.is_empty() {
+                return Ok(0);
+            }
+            self.refill()?;
+        }
+
+        let output = &self.resampler.output()[self.output_pos..];
+        let read = buf.len().min(output.len());
+        buf[..read].copy_from_slice(&output[..read]);
+        self.output_pos += read;
+        Ok(read)
+    }
+}
+
+/// Resamples raw PCM bytes written to it and writes the result to an inner writer. The bytes
+/// written are interpreted according to the input [Datatype](crate::datatype::Datatype) of the
+/// [IOSpec](crate::spec::IOSpec) of the resampler and are written to the inner writer in its
+/// output `Datatype`, both in the given byte order.
+///
+/// Chunks of any size can be written, also chunks that end halfway through a frame. Resampled
+/// output is passed on to the inner writer before the next chunk is taken, so after an error of
+/// the inner writer a write can be retried without repeating any input or output. Calling
+/// [flush](Write::flush) passes the output produced so far on to the inner writer and leaves
+/// the stream as it is. [SoxrWriter::finish] ends the stream: the tail that libsoxr holds is
+/// drained into the inner writer. Only interleaved datatypes are supported.
+///
+/// ```rust
+/// use std::io::Write;
+/// use libsoxr::{Datatype, Endianness, IOSpec, Soxr, SoxrWriter};
+///
+/// let io_spec = IOSpec::new(Datatype::Int16I, Datatype::Float32I);
+/// let soxr = Soxr::create(1.0, 2.0, 1, Some(&io_spec), None, None).unwrap();
+/// let mut writer = SoxrWriter::new(Vec::new(), soxr, Endianness::Little).unwrap();
+///
+/// // 100 frames of 16 bit little endian mono PCM
+/// for n in 0..100i16 {
+///     writer.write_all(&(n * 100).to_le_bytes()).unwrap();
+/// }
+/// let resampled = writer.finish().unwrap();
+/// assert_eq!(4 * 200, resampled.len());
+/// ```
+pub struct SoxrWriter<W: Write> {
+    inner: Option<W>,
+    resampler: ByteResampler,
+    output_pos: usize,
+}
+
+impl<W: Write> SoxrWriter<W> {
+    /// Resamples the bytes written using `soxr` and writes them to `inner`. Samples in both the
+    /// bytes written and the inner stream are in `endianness` byte order.
+    pub fn new(inner: W, soxr: Soxr, endianness: Endianness) -> Result<SoxrWriter<W>> {
+        Ok(SoxrWriter {
+            inner: Some(inner),
+            resampler: ByteResampler::new("SoxrWriter::new", soxr, endianness)?,
+            output_pos: 0,
+        })
+    }
+
+    /// Gets a reference to the inner writer
+    pub fn get_ref(&self) -> &W {
+        self.inner.as_ref().unwrap()
+    }
+
+    /// Gets a reference to the resampler
+    pub fn soxr(&self) -> &Soxr {
+        &self.resampler.soxr
+    }
+
+    /// Drains the tail of the resampler into the inner writer, and returns the inner writer.
+    /// An incomplete frame that was written last is dropped.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.drain()?;
+        let mut inner = self.inner.take().unwrap();
+        inner.flush()?;
+        Ok(inner)
+    }
+
+    /// Unwraps this `SoxrWriter` without draining the resampler, returning the inner writer.
+    /// Output held by the resampler is lost.
+    pub fn into_inner(mut self) -> W {
+        self.inner.take().unwrap()
+    }
+
+    // writes all resampled output to the inner writer
+    fn write_output(&mut self) -> io::Result<()> {
+        let inner = self.inner.as_mut().unwrap();
+        while self.output_pos < self.resampler.output_len {
+            match inner.write(&self.resampler.output()[self.output_pos..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(written) => self.output_pos += written,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => return Err(error),
+            }
+        }
+        self.resampler.output_len = 0;
+        self.output_pos = 0;
+        Ok(())
+    }
+
+    fn drain(&mut self) -> io::Result<()> {
+        self.write_output()?;
+        self.resampler.input_len = 0;
+        while self.resampler.drain()? > 0 {
+            self.write_output()?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for SoxrWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_output()?;
+
+        let space = self.resampler.input_space();
+        let written = buf.len().min(space.len());
+        space[..written].copy_from_slice(&buf[..written]);
+        self.resampler.input_len += written;
+        self.resampler.resample_input()?;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_output()?;
+        self.inner.as_mut().unwrap().flush()
+    }
+}
+
+impl<W: Write> Drop for SoxrWriter<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            // like `BufWriter`, errors on drop are ignored; call `finish` to handle them
+            let _ = self.drain();
+        }
+    }
+}
+
+// Resamples raw sample bytes, carrying incomplete input frames over between calls. Buffers are
+// allocated as words so that libsoxr gets correctly aligned samples of any datatype.
+pub(crate) struct ByteResampler {
+    pub(crate) soxr: Soxr,
+    endianness: Endianness,
+    input: Vec<u64>,
+    pub(crate) input_len: usize,
+    output: Vec<u64>,
+    pub(crate) output_len: usize,
+}
+
+impl ByteResampler {
+    pub(crate) fn new(
+        func: &'static str,
+        soxr: Soxr,
+        endianness: Endianness,
+    ) -> Result<ByteResampler> {
+        let (input_type, output_type) = soxr.datatypes();
+        if !input_type.is_interleaved() || !output_type.is_interleaved() {
+            return Err(Error::new(
+                Some(func.into()),
+                ErrorType::CreateError("only interleaved datatypes are supported".into()),
+            ));
+        }
+
+        let input_frame = soxr.num_channels() as usize * input_type.sample_size();
+        Ok(ByteResampler {
+            soxr,
+            endianness,
+            input: vec![0; (BLOCK_FRAMES * input_frame).div_ceil(8)],
+            input_len: 0,
+            output: Vec::new(),
+            output_len: 0,
+        })
+    }
+
+    fn frame_sizes(&self) -> (usize, usize) {
+        let (input_type, output_type) = self.soxr.datatypes();
+        let channels = self.soxr.num_channels() as usize;
+        (
+            channels * input_type.sample_size(),
+            channels * output_type.sample_size(),
+        )
+    }
+
+    // free space for input bytes, to be committed by adding to `input_len`
+    pub(crate) fn input_space(&mut self) -> &mut [u8] {
+        let (input_frame, _) = self.frame_sizes();
+        &mut as_bytes_mut(&mut self.input)[self.input_len..BLOCK_FRAMES * input_frame]
+    }
+
+    pub(crate) fn output(&self) -> &[u8] {
+        &as_bytes(&self.output)[..self.output_len]
+    }
+
+    // resamples all whole frames of input, appending to the output
+    pub(crate) fn resample_input(&mut self) -> io::Result<()> {
+        let (input_frame, _) = self.frame_sizes();
+        let (input_type, _) = self.soxr.datatypes();
+        let whole = self.input_len - self.input_len % input_frame;
+        if whole > 0 {
+            self.endianness.convert(
+                &mut as_bytes_mut(&mut self.input)[..whole],
+                input_type.sample_size(),
+            );
+            self.resample(Some(whole))?;
+            // carry the incomplete frame over
+            as_bytes_mut(&mut self.input).copy_within(whole..self.input_len, 0);
+            self.input_len -= whole;
+        }
+        Ok(())
+    }
+
+    // flushes the tail of the resampler, appending to the output. Returns number of frames appended.
+    pub(crate) fn drain(&mut self) -> io::Result<usize> {
+        self.resample(None)
+    }
+
+    // resamples the first `input_bytes` of the input or flushes when `None`, appending to the
+    // output. Returns number of frames appended.
+    fn resample(&mut self, input_bytes: Option<usize>) -> io::Result<usize> {
+        let (input_frame, output_frame) = self.frame_sizes();
+        let (_, output_type) = self.soxr.datatypes();
+        let ratio = self.soxr.output_rate() / self.soxr.input_rate();
+
+        let (mut consumed, mut produced) = (0, 0);
+        loop {
+            let frames = input_bytes.map_or(BLOCK_FRAMES, |total| (total - consumed) / input_frame);
+            let room = ((frames as f64 * ratio).ceil() as usize + OUTPUT_MARGIN) * output_frame;
+            let words = (self.output_len + room).div_ceil(8);
+            if self.output.len() < words {
+                self.output.resize(words, 0);
+            }
+
+            let input = as_bytes(&self.input);
+            let buf_in = input_bytes.map(|total| &input[consumed..total]);
+            let buf_out =
+                &mut as_bytes_mut(&mut self.output)[self.output_len..self.output_len + room];
+            let (idone, odone) = self.soxr.process_bytes(buf_in, buf_out)?;
+            self.endianness.convert(
+                &mut buf_out[..odone * output_frame],
+                output_type.sample_size(),
+            );
+
+            self.output_len += odone * output_frame;
+            consumed += idone * input_frame;
+            produced += odone;
+            match input_bytes {
+                Some(total) if consumed < total => {
+                    if idone == 0 && odone == 0 {
+                        return Err(Error::new(
+                            Some("Soxr::process".into()),
+                            ErrorType::ProcessError("resampler did not accept input".into()),
+                        )
+                        .into());
+                    }
+                }
+                _ => return Ok(produced),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod io_tests {
+    use std::io::{self, Read, Write};
+
+    use super::{SoxrReader, SoxrWriter};
+    use crate::{
+        datatype::{Datatype, Endianness},
+        error_handling::{Error, ErrorType},
+        spec::IOSpec,
+        Soxr,
+    };
+
+    // returns at most 3 bytes per read to split frames and samples
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let read = buf.len().min(3).min(self.0.len());
+            buf[..read].copy_from_slice(&self.0[..read]);
+            self.0 = &self.0[read..];
+            Ok(read)
+        }
+    }
+
+    fn source() -> Vec<i16> {
+        (0..2 * 4410)
+            .map(|n| if n % 2 == 0 { 8192 } else { -16384 })
+            .collect()
+    }
+
+    fn resample<R: Read>(inner: R, endianness: Endianness) -> Vec<f32> {
+        let io_spec = IOSpec::new(Datatype::Int16I, Datatype::Float32I);
+        let soxr = Soxr::create(44100.0, 48000.0, 2, Some(&io_spec), None, None).unwrap();
+        let mut reader = SoxrReader::new(inner, soxr, endianness).unwrap();
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).unwrap();
+        bytes
+            .chunks_exact(4)
+            .map(|sample| match endianness {
+                Endianness::Little => {
+                    f32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]])
+                }
+                Endianness::Big => f32::from_be_bytes([sample[0], sample[1], sample[2], sample[3]]),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_read() {
+        let pcm: Vec<u8> = source().iter().flat_map(|s| s.to_le_bytes()).collect();
+        let output = resample(&pcm[..], Endianness::Little);
+        assert_eq!(2 * 4800, output.len());
+        for frame in output.chunks(2).skip(500).take(3800) {
+            assert!((frame[0] - 0.25).abs() < 0.01);
+            assert!((frame[1] + 0.5).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn test_endianness() {
+        let little: Vec<u8> = source().iter().flat_map(|s| s.to_le_bytes()).collect();
+        let big: Vec<u8> = source().iter().flat_map(|s| s.to_be_bytes()).collect();
+        assert_eq!(
+            resample(&little[..], Endianness::Little),
+            resample(&big[..], Endianness::Big)
+        );
+    }
+
+    #[test]
+    fn test_partial_frames() {
+        let pcm: Vec<u8> = source().iter().flat_map(|s| s.to_le_bytes()).collect();
+        assert_eq!(
+            resample(&pcm[..], Endianness::Little),
+            resample(Trickle(&pcm), Endianness::Little)
+        );
+    }
+
+    #[test]
+    fn test_split_channels_rejected() {
+        let io_spec = IOSpec::new(Datatype::Int16S, Datatype::Int16S);
+        let soxr = Soxr::create(44100.0, 48000.0, 2, Some(&io_spec), None, None).unwrap();
+        assert!(SoxrReader::new(io::empty(), soxr, Endianness::Little).is_err());
+    }
+
+    #[test]
+    fn test_error_conversion() {
+        let error = Error::new(None, ErrorType::ProcessError("broken".into()));
+        let error: io::Error = error.into();
+        assert_eq!(io::ErrorKind::Other, error.kind());
+    }
+
+    fn writer() -> SoxrWriter<Vec<u8>> {
+        let io_spec = IOSpec::new(Datatype::Int16I, Datatype::Float32I);
+        let soxr = Soxr::create(44100.0, 48000.0, 2, Some(&io_spec), None, None).unwrap();
+        SoxrWriter::new(Vec::new(), soxr, Endianness::Little).unwrap()
+    }
+
+    #[test]
+    fn test_write() {
+        let pcm: Vec<u8> = source().iter().flat_map(|s| s.to_le_bytes()).collect();
+        let mut writer = writer();
+        // odd chunk sizes split frames and samples
+        for chunk in pcm.chunks(5) {
+            writer.write_all(chunk).unwrap();
+        }
+        let written = writer.finish().unwrap();
+
+        let mut read = Vec::new();
+        let io_spec = IOSpec::new(Datatype::Int16I, Datatype::Float32I);
+        let soxr = Soxr::create(44100.0, 48000.0, 2, Some(&io_spec), None, None).unwrap();
+        SoxrReader::new(&pcm[..], soxr, Endianness::Little)
+            .unwrap()
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(4 * 2 * 4800, written.len());
+        assert_eq!(read, written);
+    }
+
+    #[test]
+    fn test_flush_keeps_stream() {
+        let pcm: Vec<u8> = source().iter().flat_map(|s| s.to_le_bytes()).collect();
+        let mut unflushed = writer();
+        unflushed.write_all(&pcm).unwrap();
+        let expected = unflushed.finish().unwrap();
+
+        let mut writer = writer();
+        for chunk in pcm.chunks(5) {
+            writer.write_all(chunk).unwrap();
+            writer.flush().unwrap();
+            writer.flush().unwrap();
+        }
+        // the tail stays in the resampler until the stream is finished
+        assert!(writer.get_ref().len() < expected.len());
+        assert_eq!(expected, writer.finish().unwrap());
+    }
+
+    // takes at most 7 bytes per write and fails every third write, a limited number of times
+    struct Flaky {
+        written: Vec<u8>,
+        calls: usize,
+        failures: usize,
+    }
+
+    impl Write for Flaky {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.calls += 1;
+            if self.calls % 3 == 2 && self.failures < 100 {
+                self.failures += 1;
+                return Err(io::ErrorKind::Other.into());
+            }
+            let written = buf.len().min(7);
+            self.written.extend_from_slice(&buf[..written]);
+            Ok(written)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_retry_after_inner_error() {
+        let pcm: Vec<u8> = source().iter().flat_map(|s| s.to_le_bytes()).collect();
+        let expected = {
+            let mut writer = writer();
+            writer.write_all(&pcm).unwrap();
+            writer.finish().unwrap()
+        };
+
+        let io_spec = IOSpec::new(Datatype::Int16I, Datatype::Float32I);
+        let soxr = Soxr::create(44100.0, 48000.0, 2, Some(&io_spec), None, None).unwrap();
+        let flaky = Flaky {
+            written: Vec::new(),
+            calls: 0,
+            failures: 0,
+        };
+        let mut writer = SoxrWriter::new(flaky, soxr, Endianness::Little).unwrap();
+        let mut failed = 0;
+        for mut chunk in pcm.chunks(5) {
+            // retrying after an error neither repeats nor loses any bytes
+            while !chunk.is_empty() {
+                match writer.write(chunk) {
+                    Ok(written) => chunk = &chunk[written..],
+                    Err(_) => failed += 1,
+                }
+            }
+        }
+        while writer.flush().is_err() {
+            failed += 1;
+        }
+        assert_eq!(100, failed);
+        assert_eq!(expected, writer.finish().unwrap().written);
+    }
+}
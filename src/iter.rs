@@ -0,0 +1,276 @@
+//! Lazy resampling of iterators over samples or frames
+use crate::{
+    datatype::Sample,
+    error_handling::{Error, ErrorType, Result},
+    soxr::Soxr,
+};
+
+/// Extra room in output frames made available for each call to the resampler
+const OUTPUT_MARGIN: usize = 1024;
+
+mod private {
+    pub trait Sealed {}
+}
+
+/// An item that [ResampleIter] can resample: a single [Sample] of an interleaved stream or a
+/// whole frame `[S; N]` holding one sample for each of `N` channels. The trait is sealed, as
+/// [ResampleIter] sizes its buffers by [Frame::SAMPLES].
+pub trait Frame: private::Sealed + Copy {
+    /// Type of the samples in this item
+    type Sample: Sample;
+    /// Number of samples in one item
+    const SAMPLES: usize;
+
+    /// Appends the samples of this item to `buf`
+    fn write_to(self, buf: &mut Vec<Self::Sample>);
+    /// Creates an item from the first `SAMPLES` samples in `buf`
+    fn read_from(buf: &[Self::Sample]) -> Self;
+}
+
+macro_rules! impl_frame {
+    ($($sample:ty),*) => {
+        $(
+            impl private::Sealed for $sample {}
+            impl<const N: usize> private::Sealed for [$sample; N] {}
+
+            impl Frame for $sample {
+                type Sample = $sample;
+                const SAMPLES: usize = 1;
+
+                fn write_to(self, buf: &mut Vec<$sample>) {
+                    buf.push(self);
+                }
+
+                fn read_from(buf: &[$sample]) -> Self {
+                    buf[0]
+                }
+            }
+
+            impl<const N: usize> Frame for [$sample; N] {
+                type Sample = $sample;
+                const SAMPLES: usize = N;
+
+                fn write_to(self, buf: &mut Vec<$sample>) {
+                    buf.extend_from_slice(&self);
+                }
+
+                fn read_from(buf: &[$sample]) -> Self {
+                    let mut frame = [<$sample>::default(); N];
+                    frame.copy_from_slice(&buf[..N]);
+                    frame
+                }
+            }
+        )*
+    };
+}
+
+impl_frame!(f32, f64, i32, i16);
+
+/// Resamples the items of an iterator lazily. Items are pulled from the source in blocks of
+/// `block_size` frames, and when the source ends the resampler is flushed so that no output is
+/// lost. Items are either interleaved samples or whole frames, see [Frame].
+///
+/// The [IOSpec](crate::spec::IOSpec) of the resampler must use the interleaved [Datatype] of the
+/// sample type for both input and output. An incomplete frame at the end of an interleaved
+/// source is dropped.
+///
+/// ```rust
+/// use libsoxr::{ResampleIter, Soxr};
+///
+/// let soxr = Soxr::create(1.0, 2.0, 2, None, None, None).unwrap();
+/// let source = (0..100).map(|n| [n as f32 / 100.0, 0.0]);
+/// let resampled: Vec<[f32; 2]> = ResampleIter::new(source, soxr, 32).unwrap().collect();
+/// assert_eq!(200, resampled.len());
+/// ```
+pub struct ResampleIter<It>
+where
+    It: Iterator,
+    It::Item: Frame,
+{
+    source: It,
+    soxr: Soxr,
+    block_size: usize,
+    input: Vec<<It::Item as Frame>::Sample>,
+    output: Vec<<It::Item as Frame>::Sample>,
+    position: usize,
+    source_done: bool,
+    finished: bool,
+    error: Option<Error>,
+}
+
+impl<It> ResampleIter<It>
+where
+    It: Iterator,
+    It::Item: Frame,
+{
+    /// Wraps `source` which is resampled by `soxr` in blocks of `block_size` frames
+    pub fn new(source: It, soxr: Soxr, block_size: usize) -> Result<ResampleIter<It>> {
+        let channels = soxr.num_channels() as usize;
+        let samples = <It::Item as Frame>::SAMPLES;
+        if samples != 1 && samples != channels {
+            return Err(Error::new(
+                Some("ResampleIter::new".into()),
+                ErrorType::CreateError(format!(
+                    "frames of {} samples do not match {} channels",
+                    samples, channels
+                )),
+            ));
+        }
+
+        soxr.check_sample_type::<<It::Item as Frame>::Sample>("ResampleIter::new")?;
+
+        Ok(ResampleIter {
+            source,
+            soxr,
+            block_size: block_size.max(1),
+            input: Vec::with_capacity(block_size * channels),
+            output: Vec::new(),
+            position: 0,
+            source_done: false,
+            finished: false,
+            error: None,
+        })
+    }
+
+    /// Error that ended the iteration early, if any
+    pub fn error(&self) -> Option<&Error> {
+        self.error.as_ref()
+    }
+
+    /// Returns the resampler and the remainder of the source
+    pub fn into_inner(self) -> (Soxr, It) {
+        (self.soxr, self.source)
+    }
+
+    fn refill(&mut self) -> Result<()> {
+        let channels = self.soxr.num_channels() as usize;
+        self.output.clear();
+        self.position = 0;
+
+        if !self.source_done {
+            self.input.clear();
+            while self.input.len() < self.block_size * channels {
+                match self.source.next() {
+                    Some(item) => item.write_to(&mut self.input),
+                    None => {
+                        self.source_done = true;
+                        break;
+                    }
+                }
+            }
+            let whole = self.input.len() - self.input.len() % channels;
+            self.input.truncate(whole);
+
+            let mut consumed = 0;
+            while consumed < self.input.len() {
+                let (idone, _) = self.resample(Some(consumed))?;
+                consumed += idone * channels;
+            }
+            return Ok(());
+        }
+
+        let (_, odone) = self.resample(None)?;
+        self.finished = odone == 0;
+        Ok(())
+    }
+
+    // resamples input from `offset` or flushes when `None`, appending to the output
+    fn resample(&mut self, offset: Option<usize>) -> Result<(usize, usize)> {
+        let channels = self.soxr.num_channels() as usize;
+        let frames = offset.map_or(self.block_size, |offset| {
+            (self.input.len() - offset) / channels
+        });
+        let ratio = self.soxr.output_rate() / self.soxr.input_rate();
+        let room = (frames as f64 * ratio).ceil() as usize + OUTPUT_MARGIN;
+
+        let length = self.output.len();
+        self.output
+            .resize(length + room * channels, Default::default());
+        let input = &self.input;
+        let buf_in = offset.map(|offset| &input[offset..]);
+        let (idone, odone) = self.soxr.process(buf_in, &mut self.output[length..])?;
+        self.output.truncate(length + odone * channels);
+        if buf_in.is_some() && idone == 0 && odone == 0 {
+            return Err(Error::new(
+                Some("ResampleIter::next".into()),
+                ErrorType::ProcessError("resampler did not accept input".into()),
+            ));
+        }
+        Ok((idone, odone))
+    }
+}
+
+impl<It> Iterator for ResampleIter<It>
+where
+    It: Iterator,
+    It::Item: Frame,
+{
+    type Item = It::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let samples = <It::Item as Frame>::SAMPLES;
+        while self.position + samples > self.output.len() {
+            if self.finished {
+                return None;
+            }
+            if let Err(error) = self.refill() {
+                self.error = Some(error);
+                self.finished = true;
+                return None;
+            }
+        }
+        let item = Frame::read_from(&self.output[self.position..]);
+        self.position += samples;
+        Some(item)
+    }
+}
+
+#[cfg(test)]
+mod iter_tests {
+    use std::cell::Cell;
+
+    use super::ResampleIter;
+    use crate::{datatype::Datatype, spec::IOSpec, Soxr};
+
+    #[test]
+    fn test_interleaved_samples() {
+        let soxr = Soxr::create(44100.0, 48000.0, 2, None, None, None).unwrap();
+        let source = (0..2 * 4410).map(|n| (n as f32 * 0.001).sin());
+        let resampled = ResampleIter::new(source, soxr, 100).unwrap();
+        assert_eq!(2 * 4800, resampled.count());
+    }
+
+    #[test]
+    fn test_frames() {
+        let soxr = Soxr::create(48000.0, 16000.0, 3, None, None, None).unwrap();
+        let source = (0..3000).map(|n| [n as f64 / 3000.0, 0.5, -0.5]);
+        let io_spec = IOSpec::new(Datatype::Float64I, Datatype::Float64I);
+        assert!(ResampleIter::new(source.clone(), soxr, 256).is_err());
+
+        let soxr = Soxr::create(48000.0, 16000.0, 3, Some(&io_spec), None, None).unwrap();
+        let resampled: Vec<[f64; 3]> = ResampleIter::new(source, soxr, 256).unwrap().collect();
+        assert_eq!(1000, resampled.len());
+        for frame in resampled.iter().skip(100).take(800) {
+            assert!((frame[1] - 0.5).abs() < 0.01);
+            assert!((frame[2] + 0.5).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn test_frame_size_mismatch() {
+        let soxr = Soxr::create(44100.0, 48000.0, 2, None, None, None).unwrap();
+        let source = vec![[0.0f32; 3]; 10].into_iter();
+        assert!(ResampleIter::new(source, soxr, 100).is_err());
+    }
+
+    #[test]
+    fn test_lazy() {
+        let pulled = Cell::new(0);
+        let soxr = Soxr::create(1.0, 2.0, 1, None, None, None).unwrap();
+        let source = std::iter::repeat(0.25f32).inspect(|_| pulled.set(pulled.get() + 1));
+        let mut resampled = ResampleIter::new(source, soxr, 500).unwrap();
+        assert!(resampled.next().is_some());
+        assert_eq!(0, pulled.get() % 500);
+        assert!(pulled.get() < 10_000);
+    }
+}
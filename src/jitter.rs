@@ -0,0 +1,608 @@
+//! Adaptive jitter buffer for packetized audio
+use crate::{
+    control::{check_max_deviation, BlockFeeder, FillController},
+    datatype::Sample,
+    error_handling::{Error, ErrorType, Result},
+    variable::VariableRateResampler,
+};
+use std::collections::{btree_map::Entry, BTreeMap};
+
+/// Number of frames taken from the packets for each call to the resampler
+const BLOCK_FRAMES: usize = 64;
+/// Target delay in multiples of the observed jitter, on top of the packet duration
+const JITTER_FACTOR: f64 = 4.0;
+/// Time constants in seconds with which the target delay follows a rising and a falling jitter
+const TARGET_ATTACK: f64 = 0.5;
+const TARGET_RELEASE: f64 = 4.0;
+/// Time constant in seconds of the low-pass filter on the measured delay
+const DELAY_SMOOTHING: f64 = 1.0;
+/// Default proportional gain, per second of delay error
+const DEFAULT_KP: f64 = 0.5;
+/// Default integral gain, per second squared of integrated delay error
+const DEFAULT_KI: f64 = 0.05;
+/// Default largest relative deviation from the nominal io ratio
+const DEFAULT_MAX_DEVIATION: f64 = 0.03;
+/// Concealment repeats the last packet at most this many times before falling back to silence
+const MAX_REPEATS: usize = 3;
+
+/// How a [JitterBuffer] fills the gap left by a lost packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Concealment {
+    /// Plays silence
+    Silence,
+    /// Repeats the last packet played a few times, then plays silence
+    RepeatLast,
+}
+
+/// Counters kept by a [JitterBuffer]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct JitterStats {
+    /// Packets accepted into the buffer
+    pub received: u64,
+    /// Packets dropped because a packet with the same timestamp was received before
+    pub duplicates: u64,
+    /// Packets dropped because they arrived after their playout time
+    pub late: u64,
+    /// Frames of concealment inserted for lost packets
+    pub concealed_frames: u64,
+    /// Number of times the buffer ran empty and had to build up its delay again
+    pub underruns: u64,
+}
+
+/// Receives timestamped packets of interleaved frames, like RTP payloads, and plays them out at
+/// the local rate. Packets may arrive reordered, late or not at all: they are put back in order,
+/// late packets are dropped and gaps are filled according to the [Concealment].
+///
+/// The buffer estimates the interarrival jitter as in RFC 3550 and sets its target delay to the
+/// packet duration plus a multiple of that jitter, within the limits set by
+/// [JitterBuffer::set_delay_limits]. Instead of dropping or repeating frames to reach the target
+/// delay, a PI controller gently speeds up or slows down the playout through a
+/// [VariableRateResampler], which also absorbs the skew between the sender clock and the local
+/// clock. When the buffer runs empty it plays silence until the target delay is reached again.
+///
+/// Timestamps count frames at the sender rate and must not wrap; extend 32 bit RTP timestamps
+/// before inserting the packets.
+///
+/// ```rust
+/// use libsoxr::{Concealment, JitterBuffer, VariableRateResampler};
+///
+/// // 8 kHz narrowband, played out at 48 kHz
+/// let resampler =
+///     VariableRateResampler::create(8000.0, 48000.0, 1.1 * 8000.0 / 48000.0, 1, None, None, None)
+///         .unwrap();
+/// let mut buffer = JitterBuffer::<f32>::new(resampler, Concealment::RepeatLast).unwrap();
+///
+/// // packets of 20 ms, the second one overtook the first
+/// buffer.insert(0.041, 160, &[0.0; 160]);
+/// buffer.insert(0.043, 0, &[0.0; 160]);
+///
+/// let mut playout = [0.0f32; 480];
+/// buffer.pull(&mut playout).unwrap();
+/// assert_eq!(2, buffer.stats().received);
+/// ```
+pub struct JitterBuffer<T> {
+    resampler: VariableRateResampler,
+    channels: usize,
+    input_rate: f64,
+    nominal_io_ratio: f64,
+    timeline: Timeline<T>,
+    feeder: BlockFeeder<T>,
+    controller: FillController,
+    min_delay: f64,
+    max_delay: f64,
+    jitter: f64,
+    packet_duration: f64,
+    target_delay: f64,
+    last_arrival: Option<(f64, u64)>,
+    started: bool,
+}
+
+impl<T: Sample> JitterBuffer<T> {
+    /// Creates a jitter buffer that plays out through `resampler`. The io ratio of `resampler`
+    /// is taken as the nominal ratio between the sender rate and the local rate.
+    pub fn new(
+        resampler: VariableRateResampler,
+        concealment: Concealment,
+    ) -> Result<JitterBuffer<T>> {
+        resampler
+            .soxr()
+            .check_sample_type::<T>("JitterBuffer::new")?;
+        let channels = resampler.num_channels() as usize;
+        let min_delay = 0.02;
+        Ok(JitterBuffer {
+            channels,
+            input_rate: resampler.input_rate(),
+            nominal_io_ratio: resampler.io_ratio(),
+            resampler,
+            timeline: Timeline::new(channels, concealment),
+            feeder: BlockFeeder::new(channels, BLOCK_FRAMES),
+            controller: FillController::new(
+                min_delay,
+                DEFAULT_KP,
+                DEFAULT_KI,
+                DEFAULT_MAX_DEVIATION,
+                DELAY_SMOOTHING,
+            ),
+            min_delay,
+            max_delay: 0.5,
+            jitter: 0.0,
+            packet_duration: 0.0,
+            target_delay: min_delay,
+            last_arrival: None,
+            started: false,
+        })
+    }
+
+    /// Sets the range in seconds of the target delay, 20 ms to 500 ms by default
+    pub fn set_delay_limits(&mut self, min_delay: f64, max_delay: f64) -> Result<()> {
+        if !(min_delay >= 0.0 && min_delay <= max_delay) {
+            return Err(Error::new(
+                Some("JitterBuffer::set_delay_limits".into()),
+                ErrorType::ChangeError("delay limits must satisfy 0 <= min <= max".into()),
+            ));
+        }
+        self.min_delay = min_delay;
+        self.max_delay = max_delay;
+        Ok(())
+    }
+
+    /// Sets the gains of the PI controller. The delay error is measured in seconds, so `kp` is
+    /// per second and `ki` per second squared.
+    pub fn set_gains(&mut self, kp: f64, ki: f64) {
+        self.controller.kp = kp;
+        self.controller.ki = ki;
+    }
+
+    /// Sets how far the io ratio may deviate from its nominal value, relative to that value.
+    /// `max_deviation` has to be above 0 and below 1.
+    pub fn set_max_deviation(&mut self, max_deviation: f64) -> Result<()> {
+        check_max_deviation("JitterBuffer::set_max_deviation", max_deviation)?;
+        self.controller.max_deviation = max_deviation;
+        Ok(())
+    }
+
+    /// Adds a packet that arrived at local time `arrival`, in seconds, and whose first frame has
+    /// sender timestamp `timestamp`
+    pub fn insert(&mut self, arrival: f64, timestamp: u64, frames: &[T]) {
+        let len = (frames.len() / self.channels) as u64;
+        if len == 0 {
+            return;
+        }
+        if let Some((last_arrival, last_timestamp)) = self.last_arrival {
+            let transit = (arrival - last_arrival)
+                - (timestamp as f64 - last_timestamp as f64) / self.input_rate;
+            self.jitter += (transit.abs() - self.jitter) / 16.0;
+        }
+        self.last_arrival = Some((arrival, timestamp));
+        self.packet_duration = len as f64 / self.input_rate;
+        self.timeline
+            .insert(timestamp, &frames[..len as usize * self.channels]);
+    }
+
+    /// Fills `buf_out` with interleaved frames at the local rate
+    pub fn pull(&mut self, buf_out: &mut [T]) -> Result<()> {
+        let frames = buf_out.len() / self.channels;
+        let dt = frames as f64 / self.resampler.output_rate();
+        self.update_target_delay(dt);
+        if !self.started {
+            self.started = self.timeline.has_packets() && self.delay() >= self.target_delay();
+            if !self.started {
+                buf_out.iter_mut().for_each(|sample| *sample = T::default());
+                return Ok(());
+            }
+            self.timeline.start();
+            self.controller.reset(self.delay());
+        }
+
+        // slew to the new ratio over the frames about to be pulled
+        let correction = self.controller.update(self.delay(), dt);
+        let io_ratio =
+            (self.nominal_io_ratio * (1.0 + correction)).min(self.resampler.max_io_ratio());
+        self.resampler.set_io_ratio(io_ratio, frames)?;
+
+        let timeline = &mut self.timeline;
+        let produced = self.feeder.resample(
+            "JitterBuffer::pull",
+            &mut self.resampler,
+            buf_out,
+            |input| timeline.read(input, BLOCK_FRAMES),
+        )?;
+        if produced < frames {
+            buf_out[produced * self.channels..]
+                .iter_mut()
+                .for_each(|sample| *sample = T::default());
+            self.timeline.stats.underruns += 1;
+            self.started = false;
+        }
+        Ok(())
+    }
+
+    /// Interarrival jitter in seconds, estimated as in RFC 3550
+    pub fn jitter(&self) -> f64 {
+        self.jitter
+    }
+
+    /// Delay in seconds the buffer steers to
+    pub fn target_delay(&self) -> f64 {
+        self.target_delay
+    }
+
+    /// Current delay in seconds: the sender time between the next frame to play and the end of
+    /// the newest packet received
+    pub fn delay(&self) -> f64 {
+        let frames = self.timeline.buffered() + self.feeder.pending() as u64;
+        frames as f64 / self.input_rate
+    }
+
+    /// Current io ratio of the resampler
+    pub fn io_ratio(&self) -> f64 {
+        self.resampler.io_ratio()
+    }
+
+    /// Counters of received, dropped and concealed packets
+    pub fn stats(&self) -> JitterStats {
+        self.timeline.stats
+    }
+
+    /// Unwraps the resampler
+    pub fn into_inner(self) -> VariableRateResampler {
+        self.resampler
+    }
+
+    // moves the target delay towards what the jitter asks for, quickly when it has to grow
+    fn update_target_delay(&mut self, dt: f64) {
+        let target = (self.packet_duration + JITTER_FACTOR * self.jitter)
+            .max(self.min_delay)
+            .min(self.max_delay);
+        if !self.started {
+            self.target_delay = target;
+        } else {
+            let time_constant = if target > self.target_delay {
+                TARGET_ATTACK
+            } else {
+                TARGET_RELEASE
+            };
+            self.target_delay += (dt / time_constant).min(1.0) * (target - self.target_delay);
+        }
+        self.controller.target = self.target_delay;
+    }
+}
+
+// The packets received, ordered by timestamp, and the playout position among them
+struct Timeline<T> {
+    channels: usize,
+    concealment: Concealment,
+    packets: BTreeMap<u64, Vec<T>>,
+    next: Option<u64>,
+    end: u64,
+    last: Vec<T>,
+    concealed: usize,
+    stats: JitterStats,
+}
+
+impl<T: Sample> Timeline<T> {
+    fn new(channels: usize, concealment: Concealment) -> Self {
+        Timeline {
+            channels,
+            concealment,
+            packets: BTreeMap::new(),
+            next: None,
+            end: 0,
+            last: Vec::new(),
+            concealed: 0,
+            stats: JitterStats::default(),
+        }
+    }
+
+    fn has_packets(&self) -> bool {
+        !self.packets.is_empty()
+    }
+
+    // frames from the playout position to the end of the newest packet
+    fn buffered(&self) -> u64 {
+        let next = self
+            .next
+            .or_else(|| self.packets.keys().next().copied())
+            .unwrap_or(self.end);
+        self.end.saturating_sub(next)
+    }
+
+    // starts playout at the first packet unless it started before
+    fn start(&mut self) {
+        if self.next.is_none() {
+            self.next = self.packets.keys().next().copied();
+        }
+    }
+
+    fn insert(&mut self, timestamp: u64, frames: &[T]) {
+        let end = timestamp + (frames.len() / self.channels) as u64;
+        if self.next.is_some_and(|next| end <= next) {
+            self.stats.late += 1;
+            return;
+        }
+        match self.packets.entry(timestamp) {
+            Entry::Occupied(_) => self.stats.duplicates += 1,
+            Entry::Vacant(entry) => {
+                entry.insert(frames.to_vec());
+                self.end = self.end.max(end);
+                self.stats.received += 1;
+            }
+        }
+    }
+
+    // appends up to `max_frames` frames from the playout position to `input`; appends nothing
+    // when no packet is left to play
+    fn read(&mut self, input: &mut Vec<T>, max_frames: usize) {
+        let next = match self.next {
+            Some(next) => next,
+            None => return,
+        };
+        let current = self
+            .packets
+            .range(..=next)
+            .next_back()
+            .map(|(timestamp, packet)| (*timestamp, packet.len() / self.channels));
+        match current {
+            Some((timestamp, len)) if timestamp + len as u64 > next => {
+                let offset = (next - timestamp) as usize;
+                let count = (len - offset).min(max_frames);
+                let packet = &self.packets[&timestamp];
+                input.extend_from_slice(
+                    &packet[offset * self.channels..(offset + count) * self.channels],
+                );
+                if offset + count == len {
+                    self.last = self.packets.remove(&timestamp).unwrap_or_default();
+                    self.concealed = 0;
+                }
+                self.next = Some(next + count as u64);
+            }
+            _ => {
+                // packets that overlapped with ones played before are not needed anymore
+                let stale: Vec<u64> = self.packets.range(..=next).map(|(ts, _)| *ts).collect();
+                stale.iter().for_each(|timestamp| {
+                    self.packets.remove(timestamp);
+                });
+                if let Some(timestamp) = self.packets.keys().next().copied() {
+                    let count = ((timestamp - next) as usize).min(max_frames);
+                    self.conceal(input, count);
+                    self.next = Some(next + count as u64);
+                }
+            }
+        }
+    }
+
+    fn conceal(&mut self, input: &mut Vec<T>, count: usize) {
+        let last_frames = self.last.len() / self.channels;
+        for _ in 0..count {
+            if self.concealment == Concealment::RepeatLast
+                && last_frames > 0
+                && self.concealed < MAX_REPEATS * last_frames
+            {
+                let frame = self.concealed % last_frames;
+                input.extend_from_slice(
+                    &self.last[frame * self.channels..(frame + 1) * self.channels],
+                );
+            } else {
+                input.extend((0..self.channels).map(|_| T::default()));
+            }
+            self.concealed += 1;
+        }
+        self.stats.concealed_frames += count as u64;
+    }
+}
+
+#[cfg(test)]
+mod jitter_tests {
+    use approx::assert_abs_diff_eq;
+
+    use super::{Concealment, JitterBuffer, JitterStats, Timeline};
+    use crate::{test_util::noisy_arrivals, VariableRateResampler};
+
+    const RATE: f64 = 8000.0;
+    const PACKET: usize = 160;
+
+    struct Packet {
+        arrival: f64,
+        timestamp: u64,
+    }
+
+    // Packets of 20 ms sent by a clock `skew_ppm` fast, each delayed by a random network delay
+    // of up to `max_jitter(send time)` seconds. Every `lose_every`th packet is lost.
+    fn trace<F: Fn(f64) -> f64>(
+        seconds: f64,
+        skew_ppm: f64,
+        max_jitter: F,
+        lose_every: Option<u64>,
+    ) -> Vec<Packet> {
+        let mut packets: Vec<Packet> = noisy_arrivals(
+            0x2545_f491_4f6c_dd1d,
+            PACKET as f64 / RATE,
+            skew_ppm,
+            max_jitter,
+        )
+        .take_while(|packet| packet.sent <= seconds)
+        .filter(|packet| lose_every.is_none_or(|every| packet.index % every != every - 1))
+        .map(|packet| Packet {
+            arrival: packet.arrival + 0.01,
+            timestamp: packet.index * PACKET as u64,
+        })
+        .collect();
+        packets.sort_by(|a, b| a.arrival.partial_cmp(&b.arrival).unwrap());
+        packets
+    }
+
+    fn payload(timestamp: u64) -> Vec<f32> {
+        (timestamp..timestamp + PACKET as u64)
+            .map(|n| (n as f32 * 0.05).sin())
+            .collect()
+    }
+
+    // Plays `packets` out in blocks of 10 ms. Calls `observe` after every block that is played
+    // after `settle` seconds.
+    fn play<F: FnMut(f64, &JitterBuffer<f32>)>(
+        packets: &[Packet],
+        settle: f64,
+        mut observe: F,
+    ) -> JitterBuffer<f32> {
+        let resampler =
+            VariableRateResampler::create(RATE, RATE, 1.1, 1, None, None, None).unwrap();
+        let mut buffer = JitterBuffer::new(resampler, Concealment::RepeatLast).unwrap();
+        let mut output = [0.0f32; 80];
+        let mut packets = packets.iter().peekable();
+        let mut time = 0.0;
+        while packets.peek().is_some() {
+            while let Some(packet) = packets.next_if(|packet| packet.arrival <= time) {
+                buffer.insert(packet.arrival, packet.timestamp, &payload(packet.timestamp));
+            }
+            buffer.pull(&mut output).unwrap();
+            time += output.len() as f64 / RATE;
+            if time > settle {
+                observe(time, &buffer);
+            }
+        }
+        buffer
+    }
+
+    // Statistics and averages over the blocks played after settling
+    #[derive(Default)]
+    struct Observed {
+        settled: Option<JitterStats>,
+        blocks: usize,
+        delay_error: f64,
+        io_ratio: f64,
+    }
+
+    impl Observed {
+        fn observe(&mut self, buffer: &JitterBuffer<f32>) {
+            self.settled.get_or_insert(buffer.stats());
+            self.blocks += 1;
+            self.delay_error += buffer.delay() - buffer.target_delay();
+            self.io_ratio += buffer.io_ratio();
+        }
+
+        fn mean_delay_error(&self) -> f64 {
+            self.delay_error / self.blocks as f64
+        }
+
+        fn mean_io_ratio(&self) -> f64 {
+            self.io_ratio / self.blocks as f64
+        }
+    }
+
+    #[test]
+    fn test_reordered_packets() {
+        // up to 50 ms of jitter reorders packets of 20 ms
+        let packets = trace(30.0, 0.0, |_| 0.05, None);
+        assert!(packets
+            .windows(2)
+            .any(|pair| pair[0].timestamp > pair[1].timestamp));
+
+        let mut observed = Observed::default();
+        let buffer = play(&packets, 5.0, |_, buffer| observed.observe(buffer));
+        let (settled, stats) = (observed.settled.unwrap(), buffer.stats());
+        assert_eq!(packets.len() as u64, stats.received + stats.late);
+        assert_eq!(settled.late, stats.late);
+        assert_eq!(settled.concealed_frames, stats.concealed_frames);
+        assert_eq!(settled.underruns, stats.underruns);
+    }
+
+    #[test]
+    fn test_lost_packets_are_concealed() {
+        let packets = trace(30.0, 0.0, |_| 0.02, Some(10));
+        let lost = 1500 / 10;
+        let mut observed = Observed::default();
+        let buffer = play(&packets, 5.0, |_, buffer| observed.observe(buffer));
+        let stats = buffer.stats();
+        assert_eq!(observed.settled.unwrap().underruns, stats.underruns);
+        // the trace ends a packet after the last loss, which is still waiting to be played
+        assert!(stats.concealed_frames >= (lost - 1) * PACKET as u64);
+        assert!(stats.concealed_frames <= (lost + stats.late) * PACKET as u64);
+    }
+
+    #[test]
+    fn test_clock_skew() {
+        for skew_ppm in [-1000.0, 500.0].iter() {
+            let packets = trace(120.0, *skew_ppm, |_| 0.03, None);
+            let mut observed = Observed::default();
+            let buffer = play(&packets, 60.0, |_, buffer| observed.observe(buffer));
+            let (settled, stats) = (observed.settled.unwrap(), buffer.stats());
+            assert_eq!(settled.underruns, stats.underruns, "{} ppm", skew_ppm);
+            assert_eq!(settled.late, stats.late, "{} ppm", skew_ppm);
+            assert_abs_diff_eq!(0.0, observed.mean_delay_error(), epsilon = 0.01);
+            let measured_ppm = (observed.mean_io_ratio() - 1.0) * 1e6;
+            assert_abs_diff_eq!(*skew_ppm, measured_ppm, epsilon = 100.0);
+        }
+    }
+
+    #[test]
+    fn test_delay_tracks_jitter() {
+        let packets = trace(
+            90.0,
+            0.0,
+            |sent| if sent < 30.0 { 0.005 } else { 0.06 },
+            None,
+        );
+        let mut quiet = 0.0;
+        let mut observed = Observed::default();
+        let buffer = play(&packets, 20.0, |time, buffer| {
+            if time < 30.0 {
+                quiet = buffer.target_delay();
+            } else if time > 60.0 {
+                observed.observe(buffer);
+            }
+        });
+        assert!(buffer.target_delay() > quiet + 0.05);
+        assert_abs_diff_eq!(0.0, observed.mean_delay_error(), epsilon = 0.01);
+        // a few packets are late while the delay catches up, none once it has
+        assert_eq!(observed.settled.unwrap().late, buffer.stats().late);
+        assert!(buffer.stats().late < 10);
+    }
+
+    #[test]
+    fn test_concealment() {
+        for concealment in [Concealment::Silence, Concealment::RepeatLast].iter() {
+            let mut timeline = Timeline::new(1, *concealment);
+            timeline.insert(0, &[1.0f32, 2.0]);
+            timeline.insert(4, &[5.0, 6.0]);
+            timeline.start();
+
+            let mut input = Vec::new();
+            while input.len() < 6 {
+                timeline.read(&mut input, 64);
+            }
+            let gap = match concealment {
+                Concealment::Silence => [0.0, 0.0],
+                Concealment::RepeatLast => [1.0, 2.0],
+            };
+            assert_eq!(vec![1.0, 2.0, gap[0], gap[1], 5.0, 6.0], input);
+            assert_eq!(2, timeline.stats.concealed_frames);
+        }
+    }
+
+    #[test]
+    fn test_late_and_duplicate_packets() {
+        let mut timeline = Timeline::new(1, Concealment::Silence);
+        timeline.insert(0, &[1.0f32, 2.0]);
+        timeline.insert(0, &[1.0, 2.0]);
+        timeline.start();
+        let mut input = Vec::new();
+        timeline.read(&mut input, 64);
+        timeline.insert(0, &[1.0, 2.0]);
+        timeline.insert(2, &[3.0, 4.0]);
+        assert_eq!(1, timeline.stats.duplicates);
+        assert_eq!(1, timeline.stats.late);
+        assert_eq!(2, timeline.stats.received);
+        assert_eq!(2, timeline.buffered());
+    }
+
+    #[test]
+    fn test_invalid_max_deviation() {
+        let resampler =
+            VariableRateResampler::create(RATE, RATE, 1.1, 1, None, None, None).unwrap();
+        let mut buffer = JitterBuffer::<f32>::new(resampler, Concealment::Silence).unwrap();
+        for max_deviation in [0.0, -0.01, 1.0, f64::NAN].iter() {
+            assert!(buffer.set_max_deviation(*max_deviation).is_err());
+        }
+        assert!(buffer.set_max_deviation(0.01).is_ok());
+    }
+}
@@ -22,7 +22,7 @@
 //! ```rust
 //! # use libsoxr::Soxr;
 //! // upscale factor 2, one channel with all the defaults
-//! let soxr = Soxr::create(1.0, 2.0, 1, None, None, None).unwrap();
+//! let mut soxr = Soxr::create(1.0, 2.0, 1, None, None, None).unwrap();
 //!
 //! // source data, taken from 1-single-block.c of libsoxr examples.
 //! let source: [f32; 48] = [0.0, 1.0, 0.0, -1.0, 0.0, 1.0, 0.0, -1.0, 0.0, 1.0, 0.0, -1.0, 0.0,
@@ -46,16 +46,55 @@
 #[macro_use]
 extern crate bitflags;
 
+pub mod aligned;
+pub mod aligner;
+#[cfg(feature = "tokio")]
+pub mod async_io;
+pub mod bridge;
 pub mod datatype;
+pub mod delay;
+pub mod drift;
+pub mod envelope;
+pub mod io;
+pub mod iter;
+pub mod jitter;
+pub mod offline;
 pub mod soxr;
 pub mod spec;
+#[cfg(feature = "futures")]
+pub mod stream;
+pub mod stretch;
+pub mod variable;
+pub mod varispeed;
 
+mod control;
 mod error_handling;
+#[cfg(test)]
+mod test_util;
+mod timeline;
 mod wrapper_helpers;
 
 pub use crate::{
-    datatype::Datatype,
+    aligned::AlignedSoxr,
+    aligner::StreamAligner,
+    bridge::{BridgeProducer, ClockBridge},
+    datatype::{Datatype, Endianness, Sample},
+    delay::FractionalDelay,
+    drift::{DriftEstimate, DriftEstimator},
+    envelope::{Breakpoint, Ramp, RatioEnvelope},
     error_handling::{Error, ErrorType, Result},
-    soxr::{Soxr, SoxrFunction},
+    io::{SoxrReader, SoxrWriter},
+    iter::{Frame, ResampleIter},
+    jitter::{Concealment, JitterBuffer, JitterStats},
+    offline::OfflineConverter,
+    soxr::{Boundary, Pull, PullResampler, Push, PushResampler, Soxr, SoxrFunction},
     spec::{IOSpec, QualityFlags, QualityRecipe, QualitySpec, RuntimeSpec},
+    stretch::TimeStretcher,
+    variable::VariableRateResampler,
+    varispeed::Varispeed,
 };
+
+#[cfg(feature = "tokio")]
+pub use crate::async_io::{AsyncSoxrReader, AsyncSoxrWriter};
+#[cfg(feature = "futures")]
+pub use crate::stream::{ResampleSink, ResampleStream, SinkError};
@@ -0,0 +1,374 @@
+//! Exact-length conversion of complete signals
+use crate::{
+    aligned::AlignedSoxr,
+    datatype::Sample,
+    error_handling::{Error, ErrorType, Result},
+    soxr::Soxr,
+    spec::{IOSpec, QualitySpec, RuntimeSpec},
+};
+
+/// Minimal room in output frames made available for each call to the resampler
+const OUTPUT_BLOCK: usize = 4096;
+/// Minimal number of frames from the other end of a loop put before and after it
+const LOOP_PRE_ROLL: usize = 8192;
+
+/// Converts a complete signal into exactly `round(input_frames * output_rate / input_rate)`
+/// output frames that are time-aligned with the input: no leading filter delay and no missing
+/// tail. Input can be pushed in chunks of any size, also chunks that end halfway through a frame;
+/// the result is the same regardless of how the input was chunked.
+///
+/// ```rust
+/// use libsoxr::{OfflineConverter, Soxr};
+///
+/// let soxr = Soxr::create(44100.0, 48000.0, 2, None, None, None).unwrap();
+/// let mut converter = OfflineConverter::<f32, f32>::new(soxr).unwrap();
+///
+/// let source = [0.1f32; 2 * 441];
+/// for chunk in source.chunks(100) {
+///     converter.push(chunk).unwrap();
+/// }
+/// let target = converter.finish().unwrap();
+/// assert_eq!(2 * 480, target.len());
+/// ```
+#[derive(Debug)]
+pub struct OfflineConverter<I, O> {
+    aligned: AlignedSoxr,
+    pending: Vec<I>,
+    input_frames: u64,
+    output: Vec<O>,
+}
+
+impl<I: Sample, O: Sample> OfflineConverter<I, O> {
+    /// Creates a converter on top of `soxr` which should not have processed any data yet.
+    /// Only interleaved datatypes are supported, which have to match `I` and `O`.
+    pub fn new(soxr: Soxr) -> Result<OfflineConverter<I, O>> {
+        soxr.check_sample_types::<I, O>("OfflineConverter::new")?;
+        Ok(OfflineConverter {
+            aligned: AlignedSoxr::new(soxr)?,
+            pending: Vec::new(),
+            input_frames: 0,
+            output: Vec::new(),
+        })
+    }
+
+    /// Converts a complete signal in one go
+    ///
+    /// ```rust
+    /// use libsoxr::{OfflineConverter, Soxr};
+    ///
+    /// let soxr = Soxr::create(2.0, 1.0, 1, None, None, None).unwrap();
+    /// let target: Vec<f32> = OfflineConverter::convert(soxr, &[0.5f32; 101]).unwrap();
+    /// assert_eq!(51, target.len());
+    /// ```
+    pub fn convert(soxr: Soxr, input: &[I]) -> Result<Vec<O>> {
+        let mut converter = OfflineConverter::new(soxr)?;
+        converter.push(input)?;
+        converter.finish()
+    }
+
+    /// Converts a loop so that it stays seamless: the input is taken as circular, with its end
+    /// played before its start and its start after its end, so there is no start transient and
+    /// the tail wraps around. The output loop has exactly
+    /// `round(input_frames * output_rate / input_rate)` frames.
+    ///
+    /// To fit a whole number of output frames the loop is resampled at exactly that many output
+    /// frames per input frames, which can differ from `input_rate` and `output_rate` by up to half
+    /// a frame per loop. The parameters are those of [Soxr::create], which is used to create the
+    /// resampler for the loop.
+    ///
+    /// ```rust
+    /// use libsoxr::OfflineConverter;
+    ///
+    /// let source: Vec<f32> = (0..441).map(|n| (n as f32 / 441.0 * std::f32::consts::TAU).sin()).collect();
+    /// let target: Vec<f32> =
+    ///     OfflineConverter::convert_loop(44100.0, 48000.0, 1, None, None, None, &source).unwrap();
+    /// assert_eq!(480, target.len());
+    /// // no click where the loop wraps
+    /// assert!((target[479] - target[0]).abs() < 0.02);
+    /// ```
+    pub fn convert_loop(
+        input_rate: f64,
+        output_rate: f64,
+        num_channels: u32,
+        io_spec: Option<&IOSpec>,
+        quality_spec: Option<&QualitySpec>,
+        runtime_spec: Option<&RuntimeSpec>,
+        input: &[I],
+    ) -> Result<Vec<O>> {
+        let channels = num_channels as usize;
+        let frames = input.len() / channels.max(1);
+        let output_frames = (frames as f64 * output_rate / input_rate).round() as usize;
+        if frames == 0 || output_frames == 0 {
+            return Ok(Vec::new());
+        }
+
+        // a pre-roll of whole periods of `period` input frames lands on whole output frames
+        let divisor = gcd(frames, output_frames);
+        let period = frames / divisor;
+        let periods = LOOP_PRE_ROLL.div_ceil(period);
+        let pre_roll = periods * period;
+        let soxr = Soxr::create(
+            frames as f64,
+            output_frames as f64,
+            num_channels,
+            io_spec,
+            quality_spec,
+            runtime_spec,
+        )?;
+        let mut converter = OfflineConverter::new(soxr)?;
+        let mut position = (frames - pre_roll % frames) % frames;
+        let mut remaining = frames + 2 * pre_roll;
+        while remaining > 0 {
+            let count = (frames - position).min(remaining);
+            converter.push(&input[position * channels..(position + count) * channels])?;
+            remaining -= count;
+            position = (position + count) % frames;
+        }
+
+        let output = converter.finish()?;
+        let skip = periods * (output_frames / divisor);
+        Ok(output[skip * channels..(skip + output_frames) * channels].to_vec())
+    }
+
+    /// Adds the next chunk of interleaved input. Samples of an incomplete trailing frame are kept
+    /// until the rest of the frame is pushed.
+    pub fn push(&mut self, input: &[I]) -> Result<()> {
+        let channels = self.channels();
+
+        let input = if self.pending.is_empty() {
+            input
+        } else {
+            let missing = (channels - self.pending.len()).min(input.len());
+            self.pending.extend_from_slice(&input[..missing]);
+            if self.pending.len() < channels {
+                return Ok(());
+            }
+            let frame = std::mem::take(&mut self.pending);
+            self.resample(&frame)?;
+            &input[missing..]
+        };
+
+        let whole = input.len() - input.len() % channels;
+        self.pending.extend_from_slice(&input[whole..]);
+        self.resample(&input[..whole])
+    }
+
+    /// Flushes the resampler and returns the complete output of exactly
+    /// `round(input_frames * output_rate / input_rate)` frames. Samples of an incomplete
+    /// trailing frame are ignored.
+    pub fn finish(mut self) -> Result<Vec<O>> {
+        let channels = self.channels();
+        loop {
+            let length = self.output.len();
+            self.output
+                .resize(length + OUTPUT_BLOCK * channels, O::default());
+            let (_, odone) = self
+                .aligned
+                .process::<I, O>(None, &mut self.output[length..])?;
+            self.output.truncate(length + odone * channels);
+            if odone == 0 {
+                break;
+            }
+        }
+
+        let soxr = self.aligned.soxr();
+        let expected = (self.input_frames as f64 * soxr.output_rate() / soxr.input_rate()).round();
+        self.output
+            .resize(expected as usize * channels, O::default());
+        Ok(self.output)
+    }
+
+    fn channels(&self) -> usize {
+        self.aligned.soxr().num_channels() as usize
+    }
+
+    fn resample(&mut self, input: &[I]) -> Result<()> {
+        let channels = self.channels();
+        let soxr = self.aligned.soxr();
+        let ratio = soxr.output_rate() / soxr.input_rate();
+
+        let mut consumed = 0;
+        while consumed < input.len() {
+            let frames = (input.len() - consumed) / channels;
+            let room = (frames as f64 * ratio).ceil() as usize + OUTPUT_BLOCK;
+            let length = self.output.len();
+            self.output.resize(length + room * channels, O::default());
+
+            let (idone, odone) = self
+                .aligned
+                .process(Some(&input[consumed..]), &mut self.output[length..])?;
+            self.output.truncate(length + odone * channels);
+            if idone == 0 && odone == 0 {
+                return Err(Error::new(
+                    Some("OfflineConverter::push".into()),
+                    ErrorType::ProcessError("resampler did not accept input".into()),
+                ));
+            }
+            consumed += idone * channels;
+            self.input_frames += idone as u64;
+        }
+        Ok(())
+    }
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+#[cfg(test)]
+mod offline_tests {
+    use approx::assert_abs_diff_eq;
+
+    use super::OfflineConverter;
+    use crate::Soxr;
+
+    fn source(frames: usize, channels: usize) -> Vec<f32> {
+        (0..frames * channels)
+            .map(|n| ((n / channels) as f32 * 0.01 + (n % channels) as f32).sin())
+            .collect()
+    }
+
+    fn convert_in_chunks(input: &[f32], channels: u32, chunk: usize) -> Vec<f32> {
+        let soxr = Soxr::create(44100.0, 32000.0, channels, None, None, None).unwrap();
+        let mut converter = OfflineConverter::new(soxr).unwrap();
+        for part in input.chunks(chunk) {
+            converter.push(part).unwrap();
+        }
+        converter.finish().unwrap()
+    }
+
+    #[test]
+    fn test_exact_length() {
+        for frames in [0, 1, 2, 99, 1000, 44099, 44101].iter() {
+            let input = source(*frames, 2);
+            let output = convert_in_chunks(&input, 2, 1024);
+            let expected = (*frames as f64 * 32000.0 / 44100.0).round() as usize;
+            assert_eq!(expected * 2, output.len(), "{} input frames", frames);
+        }
+    }
+
+    #[test]
+    fn test_independent_of_chunking() {
+        let input = source(20000, 2);
+        let reference = convert_in_chunks(&input, 2, input.len());
+        // odd chunk sizes also split frames in half
+        for chunk in [1, 3, 64, 1001, 7777].iter() {
+            let output = convert_in_chunks(&input, 2, *chunk);
+            assert_eq!(reference.len(), output.len(), "chunk size {}", chunk);
+            for (expected, actual) in reference.iter().zip(output.iter()) {
+                assert_abs_diff_eq!(expected, actual, epsilon = 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn test_checks_sample_types() {
+        let soxr = Soxr::create(44100.0, 48000.0, 1, None, None, None).unwrap();
+        assert!(OfflineConverter::<i16, f64>::new(soxr).is_err());
+        let soxr = Soxr::create(44100.0, 48000.0, 1, None, None, None).unwrap();
+        assert!(OfflineConverter::<f32, f32>::new(soxr).is_ok());
+        let input = [0i16; 100];
+        let result: crate::error_handling::Result<Vec<f32>> =
+            OfflineConverter::convert_loop(44100.0, 48000.0, 1, None, None, None, &input);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_convert() {
+        let input = source(4410, 1);
+        let soxr = Soxr::create(44100.0, 48000.0, 1, None, None, None).unwrap();
+        let output: Vec<f32> = OfflineConverter::convert(soxr, &input).unwrap();
+        assert_eq!(4800, output.len());
+    }
+
+    // resampled loop of `cycles` periods of a sine on every channel, with the channels apart
+    // by a quarter period
+    fn convert_sine_loop(frames: usize, channels: u32, cycles: f64, output_rate: f64) -> Vec<f32> {
+        let channels = channels as usize;
+        let input: Vec<f32> = (0..frames * channels)
+            .map(|n| sine((n / channels) as f64 / frames as f64, cycles, n % channels))
+            .collect();
+        OfflineConverter::convert_loop(
+            44100.0,
+            output_rate,
+            channels as u32,
+            None,
+            None,
+            None,
+            &input,
+        )
+        .unwrap()
+    }
+
+    fn sine(phase: f64, cycles: f64, channel: usize) -> f32 {
+        let angle = 2.0 * std::f64::consts::PI * (phase * cycles + channel as f64 * 0.25);
+        angle.sin() as f32
+    }
+
+    #[test]
+    fn test_loop_exact_length() {
+        for frames in [1, 50, 1000, 4410, 44101].iter() {
+            let output = convert_sine_loop(*frames, 2, 1.0, 48000.0);
+            let expected = (*frames as f64 * 48000.0 / 44100.0).round() as usize;
+            assert_eq!(expected * 2, output.len(), "{} input frames", frames);
+        }
+        assert!(convert_sine_loop(0, 1, 1.0, 48000.0).is_empty());
+    }
+
+    #[test]
+    fn test_loop_is_seamless() {
+        // loops that are long, short, and shorter than the pre-roll, up and down
+        for (frames, cycles, output_rate) in [
+            (4410, 10.0, 48000.0),
+            (1000, 5.0, 48000.0),
+            (100, 1.0, 48000.0),
+            (4410, 3.0, 22050.0),
+        ]
+        .iter()
+        {
+            let output = convert_sine_loop(*frames, 2, *cycles, *output_rate);
+            let output_frames = output.len() / 2;
+            // the output is the same sine everywhere, including both ends
+            for (n, frame) in output.chunks_exact(2).enumerate() {
+                for (channel, sample) in frame.iter().enumerate() {
+                    let expected = sine(n as f64 / output_frames as f64, *cycles, channel);
+                    assert_abs_diff_eq!(expected, sample, epsilon = 2e-3);
+                }
+            }
+            // stepping over the wrap point is like stepping anywhere else
+            let step = |from: usize, to: usize| (output[2 * to] - output[2 * from]).abs();
+            let largest = (1..output_frames)
+                .map(|n| step(n - 1, n))
+                .fold(0.0, f32::max);
+            assert!(step(output_frames - 1, 0) <= largest * 1.01 + 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_loop_uses_specs() {
+        use crate::{
+            datatype::Datatype,
+            spec::{IOSpec, RuntimeSpec},
+        };
+
+        let input: Vec<i16> = (0..1000).map(|n| (n % 100) as i16 * 100).collect();
+        let io_spec = IOSpec::new(Datatype::Int16I, Datatype::Float32I);
+        let runtime_spec = RuntimeSpec::new(2);
+        let output: Vec<f32> = OfflineConverter::convert_loop(
+            44100.0,
+            48000.0,
+            1,
+            Some(&io_spec),
+            None,
+            Some(&runtime_spec),
+            &input,
+        )
+        .unwrap();
+        assert_eq!(1088, output.len());
+        assert!(output.iter().all(|sample| sample.abs() < 0.5));
+    }
+}
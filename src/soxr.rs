@@ -1,13 +1,18 @@
 //! Rust API for SOXR.
 
 use crate::{
+    datatype::{Datatype, Sample},
     error_handling::{Error, ErrorType, Result},
     spec::{IOSpec, QualitySpec, RuntimeSpec},
+    timeline::Timeline,
     wrapper_helpers::from_const,
 };
 use libsoxr_sys as soxr;
 use std::{
+    any::Any,
     ffi::CString,
+    marker::PhantomData,
+    mem::ManuallyDrop,
     os::raw::{c_char, c_void},
     ptr,
 };
@@ -35,22 +40,110 @@ use std::{
 ///     Ok(samples)
 ///   };
 ///
-/// let mut soxr = Soxr::create(1.0, 2.0, 1, None, None, None).unwrap();
-/// let mut state = State { value: 1.0 };
-/// assert!(soxr.set_input(input_fn, Some(&mut state), 100).is_ok());
+/// let soxr = Soxr::create(1.0, 2.0, 1, None, None, None).unwrap();
+/// let state = State { value: 1.0 };
+/// assert!(soxr.into_pull(input_fn, state, 100).is_ok());
 ///```
 pub type SoxrFunction<S, T> = fn(&mut S, &mut [T], usize) -> Result<usize>;
 
+/// Marks a resampler in push mode: input is passed to [Soxr::process]
+#[derive(Debug)]
+pub struct Push;
+
+/// Marks a resampler in pull mode: an input function supplies the input for [Soxr::output]
+#[derive(Debug)]
+pub struct Pull;
+
+/// Output frames made available at a time while priming with history or flushing
+const PRIMING_BLOCK: usize = 4096;
+/// Length in seconds of the crossfade after [Soxr::reconfigure]
+const CROSSFADE_SECS: f64 = 0.005;
+
+/// A resampler that is fed by calling [Soxr::process]
+pub type PushResampler = Soxr<Push>;
+
+/// A resampler that pulls its input from an input function when calling [Soxr::output]
+pub type PullResampler = Soxr<Pull>;
+
+/// A boundary in the input, like the start of a track, and the output frame it landed on. See
+/// [Soxr::mark_boundary].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Boundary {
+    /// Input frame that starts after the boundary, counted like [Soxr::input_position]
+    pub input_position: u64,
+    /// Output frame nearest to the boundary, counted like [Soxr::output_position]
+    pub output_position: u64,
+}
+
 /// This is the starting point for the Soxr algorithm.
+///
+/// # Push and pull mode
+///
+/// libsoxr either takes input through `soxr_process` or pulls it from an input function while
+/// producing output, and the two must not be mixed. [Soxr::create] returns a [PushResampler]
+/// which only offers [Soxr::process]. [Soxr::into_pull] turns it into a [PullResampler] which
+/// only offers [Soxr::output], and [Soxr::into_push] turns it back. Both conversions clear the
+/// resampler.
+///
+/// ```compile_fail
+/// use libsoxr::Soxr;
+///
+/// let input_fn = |_: &mut (), _: &mut [f32], _: usize| Ok(0);
+/// let soxr = Soxr::create(1.0, 2.0, 1, None, None, None).unwrap();
+/// let mut soxr = soxr.into_pull(input_fn, (), 100).unwrap();
+/// // a pull resampler can not be fed directly
+/// soxr.process(Some(&[0.0f32; 48]), &mut [0.0f32; 96]).unwrap();
+/// ```
+///
+/// # Thread safety
+///
+/// `Soxr` is [Send] but not [Sync]. libsoxr keeps no global or thread-local state per
+/// resampler, so a resampler can be created on one thread and used on another. A single
+/// `soxr_t` must however never be used from two threads at the same time, which is why
+/// `Soxr` is not `Sync`. When a [RuntimeSpec] asks for more than one thread, libsoxr runs its
+/// own worker threads inside a single call and joins them before returning.
+///
+/// A resampler is stateful: methods that feed or drain it, like [Soxr::process] and
+/// [Soxr::output], take `&mut self` so the borrow checker enforces exclusive use. Queries like
+/// [Soxr::delay] take `&self`.
+///
+/// The state of an input function registered with [Soxr::set_input] is owned by the resampler
+/// and used by whichever thread calls [Soxr::output], so it has to be `Send` and `'static` as
+/// well.
 #[derive(Debug)]
-pub struct Soxr {
+pub struct Soxr<M = Push> {
     soxr: soxr::soxr_t,
+    input_rate: f64,
+    output_rate: f64,
     channels: u32,
     io_spec: Option<IOSpec>,
+    quality_spec: Option<QualitySpec>,
+    runtime_spec: Option<RuntimeSpec>,
     error: CString,
-    last_trampoline_data: Option<*mut ::std::os::raw::c_void>,
+    last_trampoline_data: Option<*mut TrampolineHeader>,
+    timeline: Timeline,
+    discard: usize,
+    fade_len: usize,
+    faded: usize,
+    crossfade: Option<Crossfade>,
+    mode: PhantomData<M>,
 }
 
+// Output of the previous resampler after a `reconfigure`, played before the output of the new
+// one. Its last `overlap` frames are mixed with the first frames of the new output.
+#[derive(Debug)]
+struct Crossfade {
+    tail: Vec<u8>,
+    position: usize,
+    overlap: usize,
+}
+
+// SAFETY: `soxr_t` is owned exclusively by this struct and libsoxr does not tie it to the thread
+// that created it. The trampoline data is owned as well, including the state of the input
+// function, which `set_input` only accepts when it is `Send` and `'static`. `Sync` is not
+// implemented because libsoxr calls on the same `soxr_t` must not overlap.
+unsafe impl<M> Send for Soxr<M> {}
+
 impl Soxr {
     /// Create a new resampler. When `io_spec`, `quality_spec` or `runtime_spec` is `None` then SOXR will use it defaults:
     /// * Default io_spec      is per [IOSpec]([Datatype](crate::datatype::Datatype)::Float32I, [Datatype](crate::datatype::Datatype)::Float32I)
@@ -86,10 +179,20 @@ impl Soxr {
         if error.is_null() {
             Ok(Soxr {
                 soxr,
+                input_rate,
+                output_rate,
                 channels: num_channels,
                 io_spec: io_spec.cloned(),
+                quality_spec: quality_spec.cloned(),
+                runtime_spec: runtime_spec.cloned(),
                 error: CString::new("").unwrap(),
                 last_trampoline_data: None,
+                timeline: Timeline::new(input_rate / output_rate),
+                discard: 0,
+                fade_len: 0,
+                faded: 0,
+                crossfade: None,
+                mode: PhantomData,
             })
         } else {
             let error = unsafe { *error };
@@ -104,6 +207,33 @@ impl Soxr {
     pub fn version() -> &'static str {
         unsafe { from_const("Soxr::version", soxr::soxr_version()).unwrap() }
     }
+}
+
+impl<M> Soxr<M> {
+    /// Input rate this resampler was created with
+    pub fn input_rate(&self) -> f64 {
+        self.input_rate
+    }
+
+    /// Output rate this resampler was created with
+    pub fn output_rate(&self) -> f64 {
+        self.output_rate
+    }
+
+    /// Number of channels this resampler is currently configured for
+    pub fn num_channels(&self) -> u32 {
+        self.channels
+    }
+
+    /// The [IOSpec] this resampler was created with, if any
+    pub fn io_spec(&self) -> Option<&IOSpec> {
+        self.io_spec.as_ref()
+    }
+
+    /// The [QualitySpec] this resampler was created with, if any
+    pub fn quality_spec(&self) -> Option<&QualitySpec> {
+        self.quality_spec.as_ref()
+    }
 
     /// Set error of Soxr engine
     pub fn set_error(&mut self, msg: String) -> Result<()> {
@@ -120,11 +250,21 @@ impl Soxr {
         }
     }
 
-    /// Change number of channels after creating Soxr object
+    /// Change number of channels after creating Soxr object. libsoxr refuses a different number
+    /// of channels once its resamplers are set up, which `soxr_create` already does. When the
+    /// change is accepted, the buffer handed to an input function set with [Soxr::set_input] is
+    /// resized to hold `max_samples` frames of the new channel count, and a pending fade-in or
+    /// crossfade is dropped.
     pub fn set_num_channels(&mut self, num_channels: u32) -> Result<()> {
         let error = unsafe { soxr::soxr_set_num_channels(self.soxr, num_channels) };
         if error.is_null() {
             self.channels = num_channels;
+            self.fade_len = 0;
+            self.crossfade = None;
+            if let Some(header) = self.trampoline_header_mut() {
+                // the trampoline resizes the buffer, it knows its sample type
+                header.channels = num_channels as usize;
+            }
             Ok(())
         } else {
             Err(Error::new(
@@ -158,6 +298,85 @@ impl Soxr {
         unsafe { soxr::soxr_delay(self.soxr) }
     }
 
+    /// Number of input frames consumed since creation or the last [Soxr::clear]. In pull mode
+    /// these are the frames returned by the input functions.
+    pub fn input_position(&self) -> u64 {
+        self.timeline.consumed + self.trampoline_header().map_or(0, |header| header.consumed)
+    }
+
+    /// Number of output frames produced since creation or the last [Soxr::clear]
+    pub fn output_position(&self) -> u64 {
+        self.timeline.produced
+    }
+
+    /// Output position that corresponds to input position `position`, both in frames since
+    /// creation or the last [Soxr::clear]. Follows the io ratio changes of variable-rate
+    /// resampling. Returns `None` for negative positions and for positions before the oldest
+    /// io ratio change that is remembered.
+    ///
+    /// Input that was consumed but is not output yet is held in the [Soxr::delay], so the input
+    /// position shows up in the output once [Soxr::output_position] gets past the returned
+    /// position.
+    ///
+    /// ```rust
+    /// use libsoxr::Soxr;
+    ///
+    /// let mut soxr = Soxr::create(44100.0, 48000.0, 1, None, None, None).unwrap();
+    /// // a cue point at one second of input is at one second of output
+    /// assert_eq!(Some(48000.0), soxr.input_to_output_position(44100.0));
+    ///
+    /// let source = [0.0f32; 4410];
+    /// let mut target = [0.0f32; 4800];
+    /// let (idone, odone) = soxr.process(Some(&source), &mut target).unwrap();
+    /// assert_eq!(idone as u64, soxr.input_position());
+    /// assert_eq!(odone as u64, soxr.output_position());
+    /// ```
+    pub fn input_to_output_position(&self, position: f64) -> Option<f64> {
+        self.timeline.input_to_output(position)
+    }
+
+    /// Input position that corresponds to output position `position`, the inverse of
+    /// [Soxr::input_to_output_position]
+    pub fn output_to_input_position(&self, position: f64) -> Option<f64> {
+        self.timeline.output_to_input(position)
+    }
+
+    /// Marks a boundary at the current input position, between the input consumed so far and the
+    /// input that comes next, for instance between two tracks of an album that is resampled as
+    /// one gapless stream. Returns the input position of the boundary. Once the output gets
+    /// there, [Soxr::take_boundaries] reports the output frame it landed on.
+    ///
+    /// ```rust
+    /// use libsoxr::Soxr;
+    ///
+    /// let mut soxr = Soxr::create(44100.0, 48000.0, 1, None, None, None).unwrap();
+    /// let tracks = [vec![0.1f32; 44100], vec![0.2f32; 22050]];
+    /// let mut target = vec![0.0f32; 100000];
+    /// let mut produced = 0;
+    /// for track in tracks.iter() {
+    ///     let (_, odone) = soxr.process(Some(track), &mut target[produced..]).unwrap();
+    ///     produced += odone;
+    ///     soxr.mark_boundary();
+    /// }
+    /// soxr.process::<f32, _>(None, &mut target[produced..]).unwrap();
+    ///
+    /// let boundaries = soxr.take_boundaries();
+    /// assert_eq!(48000, boundaries[0].output_position);
+    /// assert_eq!(72000, boundaries[1].output_position);
+    /// ```
+    pub fn mark_boundary(&mut self) -> u64 {
+        let position = self.input_position();
+        self.timeline.mark_boundary(position);
+        position
+    }
+
+    /// Takes the marked boundaries that the output got past, in order. A boundary is reported
+    /// once [Soxr::output_position] reaches its output frame. [Soxr::clear] drops the boundaries
+    /// that were not reported yet.
+    pub fn take_boundaries(&mut self) -> Vec<Boundary> {
+        self.timeline.take_boundaries()
+    }
+
     /// Query resampling engine name.
     pub fn engine(&self) -> String {
         from_const("Soxr::engine", unsafe { soxr::soxr_engine(self.soxr) })
@@ -165,10 +384,18 @@ impl Soxr {
             .to_string()
     }
 
-    /// Ready for fresh signal, same config.
+    /// Ready for fresh signal, same config. This is a discontinuity: the positions start from
+    /// zero again, see [Soxr::input_position].
     pub fn clear(&mut self) -> Result<()> {
         let error = unsafe { soxr::soxr_clear(self.soxr) };
         if error.is_null() {
+            self.timeline.reset();
+            self.discard = 0;
+            self.fade_len = 0;
+            self.crossfade = None;
+            if let Some(header) = self.trampoline_header_mut() {
+                header.consumed = 0;
+            }
             Ok(())
         } else {
             Err(Error::new(
@@ -182,9 +409,20 @@ impl Soxr {
     /// See [example # 5](https://sourceforge.net/p/soxr/code/ci/master/tree/examples/5-variable-rate.c)
     /// of libsoxr repository for how to create a
     /// variable-rate resampler and how to use this function.
+    #[deprecated(
+        since = "0.2.10",
+        note = "use VariableRateResampler, which creates the resampler with the VR flag and keeps track of the io ratio"
+    )]
     pub fn set_io_ratio(&mut self, io_ratio: f64, slew_len: usize) -> Result<()> {
+        self.change_io_ratio(io_ratio, slew_len)
+    }
+
+    /// For variable-rate resampling, only works when created with [QualityFlags](crate::spec::QualityFlags)::VR.
+    /// Exposed through [VariableRateResampler](crate::variable::VariableRateResampler).
+    pub(crate) fn change_io_ratio(&mut self, io_ratio: f64, slew_len: usize) -> Result<()> {
         let error = unsafe { soxr::soxr_set_io_ratio(self.soxr, io_ratio, slew_len) };
         if error.is_null() {
+            self.timeline.set_io_ratio(io_ratio, slew_len);
             Ok(())
         } else {
             Err(Error::new(
@@ -196,18 +434,137 @@ impl Soxr {
         }
     }
 
+    /// Input and output datatype, taking the libsoxr defaults into account when there is no [IOSpec]
+    pub(crate) fn datatypes(&self) -> (Datatype, Datatype) {
+        self.io_spec
+            .as_ref()
+            .map_or((Datatype::Float32I, Datatype::Float32I), |spec| {
+                (spec.input_type(), spec.output_type())
+            })
+    }
+
+    /// Checks that both datatypes of the [IOSpec] are the interleaved datatype of sample type `S`
+    pub(crate) fn check_sample_type<S: Sample>(&self, func: &'static str) -> Result<()> {
+        self.check_sample_types::<S, S>(func)
+    }
+
+    /// Checks that the datatypes of the [IOSpec] are the interleaved datatypes of input sample
+    /// type `I` and output sample type `O`
+    pub(crate) fn check_sample_types<I: Sample, O: Sample>(
+        &self,
+        func: &'static str,
+    ) -> Result<()> {
+        let (input_type, output_type) = self.datatypes();
+        if input_type != I::DATATYPE || output_type != O::DATATYPE {
+            return Err(Error::new(
+                Some(func.into()),
+                ErrorType::CreateError(format!(
+                    "IOSpec does not match {:?} to {:?} samples",
+                    I::DATATYPE,
+                    O::DATATYPE
+                )),
+            ));
+        }
+        Ok(())
+    }
+
+    fn get_buf_in_ptr<I>(&self, buf_in: &[I], split_buf: &mut Vec<*const c_void>) -> *const c_void {
+        let Some(io_spec) = self.io_spec.as_ref() else {
+            // assume interleaved
+            return buf_in.as_ptr() as *const c_void;
+        };
+
+        if io_spec.input_type().is_interleaved() {
+            return buf_in.as_ptr() as *const c_void;
+        }
+
+        let samples_in_buf = buf_in.len() / self.channels as usize;
+        for channel in 0..self.channels as usize {
+            split_buf.push(buf_in[channel * samples_in_buf..].as_ptr() as *const c_void);
+        }
+        split_buf.as_ptr() as *const c_void
+    }
+
+    fn get_buf_out_ptr<O>(&self, buf_out: &[O], split_buf: &mut Vec<*mut c_void>) -> *mut c_void {
+        let Some(io_spec) = self.io_spec.as_ref() else {
+            // assume interleaved
+            return buf_out.as_ptr() as *mut c_void;
+        };
+
+        if io_spec.output_type().is_interleaved() {
+            return buf_out.as_ptr() as *mut c_void;
+        }
+
+        let samples_in_buf = buf_out.len() / self.channels as usize;
+        for channel in 0..self.channels as usize {
+            split_buf.push(buf_out[channel * samples_in_buf..].as_ptr() as *mut c_void);
+        }
+        split_buf.as_ptr() as *mut c_void
+    }
+
+    fn drop_last_trampoline(&mut self) {
+        if let Some(header) = self.last_trampoline_data.take() {
+            // SAFETY: the header was boxed as part of a `TrampolineData<S, T>` by `set_input`,
+            // which stored the matching drop function
+            unsafe {
+                self.timeline.consumed += (*header).consumed;
+                ((*header).drop)(header);
+            }
+        }
+    }
+
+    fn trampoline_header(&self) -> Option<&TrampolineHeader> {
+        // SAFETY: the header lives as long as `last_trampoline_data` points to it
+        self.last_trampoline_data.map(|header| unsafe { &*header })
+    }
+
+    fn trampoline_header_mut(&mut self) -> Option<&mut TrampolineHeader> {
+        // SAFETY: see `trampoline_header`
+        self.last_trampoline_data
+            .map(|header| unsafe { &mut *header })
+    }
+
+    // Changes the mode marker without touching the resampler. The caller resets libsoxr state.
+    fn into_mode<N>(self) -> Soxr<N> {
+        let this = ManuallyDrop::new(self);
+        // SAFETY: every field is moved out exactly once and `this` is never dropped
+        unsafe {
+            Soxr {
+                soxr: this.soxr,
+                input_rate: this.input_rate,
+                output_rate: this.output_rate,
+                channels: this.channels,
+                io_spec: ptr::read(&this.io_spec),
+                quality_spec: ptr::read(&this.quality_spec),
+                runtime_spec: ptr::read(&this.runtime_spec),
+                error: ptr::read(&this.error),
+                last_trampoline_data: this.last_trampoline_data,
+                timeline: ptr::read(&this.timeline),
+                discard: this.discard,
+                fade_len: this.fade_len,
+                faded: this.faded,
+                crossfade: ptr::read(&this.crossfade),
+                mode: PhantomData,
+            }
+        }
+    }
+}
+
+impl Soxr<Push> {
     /// Resamples `Some(buf_in)` into `buf_out`. Type is dependent on [IOSpec]. If you leave out
     /// [IOSpec] on create, it defaults to `f32`. Make sure that `buf_out` is large enough to hold
     /// the resampled data. Furthermore, to indicate end-of-input to the resampler, always end with
     /// a last call to process with `None` as `buf_in`. The result contains number of input samples
     /// used and number of output samples placed in 'buf_out'
     ///
+    /// The lengths of both buffers have to be a multiple of the number of channels.
+    ///
     /// ## Example
     ///
     /// ```rust
     /// # use libsoxr::Soxr;
     /// // upscale factor 2, one channel with all the defaults
-    /// let soxr = Soxr::create(1.0, 2.0, 1, None, None, None).unwrap();
+    /// let mut soxr = Soxr::create(1.0, 2.0, 1, None, None, None).unwrap();
     ///
     /// // source data, taken from 1-single-block.c of libsoxr examples.
     /// let source: [f32; 48] = [0.0, 1.0, 0.0, -1.0, 0.0, 1.0, 0.0, -1.0, 0.0, 1.0, 0.0, -1.0, 0.0,
@@ -223,7 +580,65 @@ impl Soxr {
     /// soxr.process(Some(&source), &mut target).unwrap();
     /// soxr.process::<f32,_>(None, &mut target[0..]).unwrap();
     /// ```
-    pub fn process<I, O>(&self, buf_in: Option<&[I]>, buf_out: &mut [O]) -> Result<(usize, usize)> {
+    pub fn process<I, O>(
+        &mut self,
+        buf_in: Option<&[I]>,
+        buf_out: &mut [O],
+    ) -> Result<(usize, usize)> {
+        let channels = self.channels as usize;
+        if buf_in.is_some_and(|buf_in| buf_in.len() % channels != 0)
+            || buf_out.len() % channels != 0
+        {
+            return Err(Error::new(
+                Some("Soxr::process".into()),
+                ErrorType::ProcessError(format!(
+                    "buffer lengths must be a multiple of the {} channels",
+                    channels
+                )),
+            ));
+        }
+        if self.crossfade.is_some() {
+            let (input_type, output_type) = self.datatypes();
+            if !input_type.is_interleaved()
+                || !output_type.is_interleaved()
+                || std::mem::size_of::<I>() != input_type.sample_size()
+                || std::mem::size_of::<O>() != output_type.sample_size()
+            {
+                return Err(Error::new(
+                    Some("Soxr::process".into()),
+                    ErrorType::ProcessError(
+                        "buffers must be interleaved samples of the datatypes after reconfigure"
+                            .into(),
+                    ),
+                ));
+            }
+            // SAFETY: the bytes of the buffers, which hold samples of the interleaved datatypes
+            let (buf_in, buf_out) = unsafe {
+                (
+                    buf_in.map(|buf_in| {
+                        std::slice::from_raw_parts(
+                            buf_in.as_ptr() as *const u8,
+                            std::mem::size_of_val(buf_in),
+                        )
+                    }),
+                    std::slice::from_raw_parts_mut(
+                        buf_out.as_mut_ptr() as *mut u8,
+                        std::mem::size_of_val(buf_out),
+                    ),
+                )
+            };
+            return self.process_crossfading(buf_in, buf_out);
+        }
+        // the fade-in of `reset_with_history` works on the bytes of `buf_out`
+        if self.is_declicking() && std::mem::size_of::<O>() != self.datatypes().1.sample_size() {
+            return Err(Error::new(
+                Some("Soxr::process".into()),
+                ErrorType::ProcessError(
+                    "output must be samples of the output datatype after reset_with_history".into(),
+                ),
+            ));
+        }
+
         let mut idone_in_samples = 0;
         let mut odone_in_samples = 0;
 
@@ -263,6 +678,18 @@ impl Soxr {
             },
         };
         if error.is_null() {
+            self.timeline.consumed += idone_in_samples as u64;
+            self.timeline.produced += odone_in_samples as u64;
+            if self.is_declicking() {
+                // SAFETY: the bytes of `buf_out`, which holds samples of the output datatype
+                let buf_out = unsafe {
+                    std::slice::from_raw_parts_mut(
+                        buf_out.as_mut_ptr() as *mut u8,
+                        std::mem::size_of_val(buf_out),
+                    )
+                };
+                odone_in_samples = self.declick(buf_out, odone_in_samples);
+            }
             Ok((idone_in_samples, odone_in_samples))
         } else {
             Err(Error::new(
@@ -272,50 +699,374 @@ impl Soxr {
         }
     }
 
-    fn get_buf_in_ptr<I>(&self, buf_in: &[I], split_buf: &mut Vec<*const c_void>) -> *const c_void {
-        let Some(io_spec) = self.io_spec.as_ref() else {
-            // assume interleaved
-            return buf_in.as_ptr() as *const c_void;
+    /// Resamples interleaved raw bytes laid out per the datatypes of the [IOSpec], for adapters
+    /// that work on byte streams. Buffers hold whole frames; the result is in frames like
+    /// [Soxr::process].
+    pub(crate) fn process_bytes(
+        &mut self,
+        buf_in: Option<&[u8]>,
+        buf_out: &mut [u8],
+    ) -> Result<(usize, usize)> {
+        if self.crossfade.is_some() {
+            return self.process_crossfading(buf_in, buf_out);
+        }
+        self.process_raw(buf_in, buf_out)
+    }
+
+    fn process_raw(&mut self, buf_in: Option<&[u8]>, buf_out: &mut [u8]) -> Result<(usize, usize)> {
+        let (input_type, output_type) = self.datatypes();
+        let channels = self.channels as usize;
+        let mut idone_in_samples = 0;
+        let mut odone_in_samples = 0;
+
+        let (buf_in_ptr, samples_in_buf_in) = buf_in.map_or((ptr::null(), 0), |buf_in| {
+            (
+                buf_in.as_ptr() as *const c_void,
+                buf_in.len() / (channels * input_type.sample_size()),
+            )
+        });
+        let error = unsafe {
+            soxr::soxr_process(
+                self.soxr,
+                buf_in_ptr,
+                samples_in_buf_in,
+                &mut idone_in_samples,
+                buf_out.as_mut_ptr() as *mut c_void,
+                buf_out.len() / (channels * output_type.sample_size()),
+                &mut odone_in_samples,
+            )
         };
-        
-        if io_spec.input_type().is_interleaved() {
-            return buf_in.as_ptr() as *const c_void;
+        if error.is_null() {
+            self.timeline.consumed += idone_in_samples as u64;
+            self.timeline.produced += odone_in_samples as u64;
+            if self.is_declicking() {
+                odone_in_samples = self.declick(buf_out, odone_in_samples);
+            }
+            Ok((idone_in_samples, odone_in_samples))
+        } else {
+            Err(Error::new(
+                Some("Soxr::process".into()),
+                ErrorType::ProcessError(from_const("Soxr::process", error).unwrap().to_string()),
+            ))
         }
-        
-        let samples_in_buf = buf_in.len() / self.channels as usize;
-        for channel in 0..self.channels as usize {
-            split_buf.push(buf_in[channel * samples_in_buf..].as_ptr() as *const c_void);
+    }
+
+    /// Clears the resampler like [Soxr::clear] and primes it with `history`, the input right
+    /// before the point to continue from, such as the frames before a seek position. The output
+    /// that belongs to `history` is dropped, so the next output starts at the output frame
+    /// nearest to the end of `history`, without the transient of an empty filter. The first
+    /// `fade_len` frames of that output fade in along a raised cosine, to hide the jump from the
+    /// output before the seek.
+    ///
+    /// [Soxr::input_position] and [Soxr::output_position] include `history` and its dropped
+    /// output. Only interleaved datatypes are supported, and until the fade-in is done
+    /// [Soxr::process] returns an error for output buffers of another sample size.
+    ///
+    /// ```rust
+    /// use libsoxr::Soxr;
+    ///
+    /// let source: Vec<f32> = (0..96000).map(|n| (n as f32 * 0.01).sin()).collect();
+    /// let mut soxr = Soxr::create(48000.0, 44100.0, 1, None, None, None).unwrap();
+    /// let mut target = vec![0.0f32; 4410];
+    /// soxr.process(Some(&source[..4800]), &mut target).unwrap();
+    ///
+    /// // seek to one second, with 10 ms of history and a 1 ms fade
+    /// soxr.reset_with_history(&source[48000 - 480..48000], 44).unwrap();
+    /// soxr.process(Some(&source[48000..52800]), &mut target).unwrap();
+    /// ```
+    pub fn reset_with_history<I>(&mut self, history: &[I], fade_len: usize) -> Result<()> {
+        let (input_type, output_type) = self.datatypes();
+        if !input_type.is_interleaved()
+            || !output_type.is_interleaved()
+            || std::mem::size_of::<I>() != input_type.sample_size()
+        {
+            return Err(Error::new(
+                Some("Soxr::reset_with_history".into()),
+                ErrorType::ChangeError(
+                    "history must be interleaved samples of the input datatype".into(),
+                ),
+            ));
         }
-        split_buf.as_ptr() as *const c_void        
+        self.clear()?;
+
+        let channels = self.channels as usize;
+        let frames = history.len() / channels;
+        let frame_size = channels * input_type.sample_size();
+        // SAFETY: the bytes of the whole frames in `history`, whose samples have the input size
+        let history = unsafe {
+            std::slice::from_raw_parts(history.as_ptr() as *const u8, frames * frame_size)
+        };
+        let mut scratch = vec![0u8; PRIMING_BLOCK * channels * output_type.sample_size()];
+        let mut consumed = 0;
+        while consumed < frames {
+            let (idone, odone) =
+                self.process_bytes(Some(&history[consumed * frame_size..]), &mut scratch)?;
+            if idone == 0 && odone == 0 {
+                return Err(Error::new(
+                    Some("Soxr::reset_with_history".into()),
+                    ErrorType::ProcessError("resampler did not accept input".into()),
+                ));
+            }
+            consumed += idone;
+        }
+
+        let end = self
+            .input_to_output_position(frames as f64)
+            .unwrap_or(0.0)
+            .round() as u64;
+        self.discard = end.saturating_sub(self.output_position()) as usize;
+        self.fade_len = fade_len;
+        self.faded = 0;
+        Ok(())
     }
 
-    fn get_buf_out_ptr<O>(&self, buf_out: &[O], split_buf: &mut Vec<*mut c_void>) -> *mut c_void {
-        let Some(io_spec) = self.io_spec.as_ref() else {
-            // assume interleaved
-            return buf_out.as_ptr() as *mut c_void;
+    /// Switches to `input_rate` and `output_rate` while the stream goes on. libsoxr can not
+    /// change the rates of a resampler, so a new one is created with the [IOSpec],
+    /// [QualitySpec] and [RuntimeSpec] of this one. The old resampler is flushed and its
+    /// remaining output comes first from [Soxr::process]; its last 5 ms are crossfaded with the
+    /// start of the output of the new resampler, to cover the edges of both filters.
+    ///
+    /// The crossfade overlaps the two outputs, which makes the output that much shorter. The
+    /// positions start over from zero, like after [Soxr::clear], with the output position
+    /// counting the remaining output of the old resampler too. Boundaries that were not reported
+    /// yet are kept, with the input position they were marked at. Only interleaved datatypes are
+    /// supported, and until the crossfade is done [Soxr::process] returns an error for buffers
+    /// of another sample size.
+    ///
+    /// ```rust
+    /// use libsoxr::Soxr;
+    ///
+    /// let mut soxr = Soxr::create(44100.0, 48000.0, 1, None, None, None).unwrap();
+    /// let mut target = vec![0.0f32; 10000];
+    /// let (_, done) = soxr.process(Some(&[0.5f32; 4410]), &mut target).unwrap();
+    ///
+    /// // the source switches to 48 kHz
+    /// soxr.reconfigure(48000.0, 48000.0).unwrap();
+    /// assert_eq!(48000.0, soxr.input_rate());
+    /// soxr.process(Some(&[0.5f32; 4800]), &mut target[done..]).unwrap();
+    /// ```
+    pub fn reconfigure(&mut self, input_rate: f64, output_rate: f64) -> Result<()> {
+        let (input_type, output_type) = self.datatypes();
+        if !input_type.is_interleaved() || !output_type.is_interleaved() {
+            return Err(Error::new(
+                Some("Soxr::reconfigure".into()),
+                ErrorType::ChangeError("only interleaved datatypes are supported".into()),
+            ));
+        }
+        let mut resampler = Soxr::create(
+            input_rate,
+            output_rate,
+            self.channels,
+            self.io_spec.as_ref(),
+            self.quality_spec.as_ref(),
+            self.runtime_spec.as_ref(),
+        )?;
+
+        // what is left of an earlier crossfade is played before the flushed output
+        let returned = self.timeline.produced;
+        let frame_size = self.channels as usize * output_type.sample_size();
+        let mut tail = match self.crossfade.take() {
+            Some(crossfade) => crossfade.tail[crossfade.position * frame_size..].to_vec(),
+            None => Vec::new(),
         };
-        
-        if io_spec.input_type().is_interleaved() {
-            return buf_out.as_ptr() as *mut c_void;
+        loop {
+            let length = tail.len();
+            tail.resize(length + PRIMING_BLOCK * frame_size, 0);
+            let (_, odone) = self.process_bytes(None, &mut tail[length..])?;
+            tail.truncate(length + odone * frame_size);
+            if odone == 0 {
+                break;
+            }
         }
-        
-        let samples_in_buf = buf_out.len() / self.channels as usize;
-        for channel in 0..self.channels as usize {
-            split_buf.push(buf_out[channel * samples_in_buf..].as_ptr() as *mut c_void);
+
+        // the old libsoxr resampler goes with `resampler`
+        std::mem::swap(&mut self.soxr, &mut resampler.soxr);
+        self.input_rate = input_rate;
+        self.output_rate = output_rate;
+        let tail_frames = tail.len() / frame_size;
+        let overlap = ((CROSSFADE_SECS * output_rate).round() as usize).min(tail_frames);
+        // the output of the new resampler starts where it overlaps the tail
+        let mut timeline =
+            Timeline::starting_at(input_rate / output_rate, (tail_frames - overlap) as u64);
+        timeline.carry_boundaries(&self.timeline, returned);
+        self.timeline = timeline;
+        self.discard = 0;
+        self.fade_len = 0;
+        if !tail.is_empty() {
+            self.crossfade = Some(Crossfade {
+                tail,
+                position: 0,
+                overlap,
+            });
+        }
+        Ok(())
+    }
+
+    // Plays the tail of the previous resampler, crossfading its end with the new output
+    fn process_crossfading(
+        &mut self,
+        buf_in: Option<&[u8]>,
+        buf_out: &mut [u8],
+    ) -> Result<(usize, usize)> {
+        let (_, output_type) = self.datatypes();
+        let sample_size = output_type.sample_size();
+        let frame_size = self.channels as usize * sample_size;
+        let room = buf_out.len() / frame_size;
+        let mut crossfade = match self.crossfade.take() {
+            Some(crossfade) => crossfade,
+            None => return Ok((0, 0)),
+        };
+        let tail_frames = crossfade.tail.len() / frame_size;
+        let overlap_start = tail_frames - crossfade.overlap;
+
+        // the tail up to the overlap as it is
+        let mut written = 0;
+        if crossfade.position < overlap_start {
+            written = (overlap_start - crossfade.position).min(room);
+            let start = crossfade.position * frame_size;
+            buf_out[..written * frame_size]
+                .copy_from_slice(&crossfade.tail[start..start + written * frame_size]);
+            crossfade.position += written;
+            self.timeline.produced += written as u64;
+            if written == room {
+                self.crossfade = Some(crossfade);
+                return Ok((0, written));
+            }
+        }
+
+        let (idone, odone) = match self.process_raw(buf_in, &mut buf_out[written * frame_size..]) {
+            Ok(done) => done,
+            Err(error) => {
+                // the rest of the tail is played on the next call
+                self.crossfade = Some(crossfade);
+                return Err(error);
+            }
+        };
+        // when the new resampler is flushed, the rest of the tail fades out on its own
+        let produced = if buf_in.is_none() && written + odone < room {
+            let remaining = tail_frames - crossfade.position;
+            let extra = remaining.saturating_sub(odone).min(room - written - odone);
+            let start = (written + odone) * frame_size;
+            buf_out[start..start + extra * frame_size].fill(0);
+            self.timeline.produced += extra as u64;
+            odone + extra
+        } else {
+            odone
+        };
+
+        let mixed = produced.min(tail_frames - crossfade.position);
+        for frame in 0..mixed {
+            let progress =
+                ((crossfade.position - overlap_start) as f64 + 0.5) / crossfade.overlap as f64;
+            let gain = 0.5 - 0.5 * (std::f64::consts::PI * progress).cos();
+            let new = (written + frame) * frame_size;
+            let old = crossfade.position * frame_size;
+            for sample in (0..frame_size).step_by(sample_size) {
+                let value = output_type.read(&buf_out[new + sample..]) * gain
+                    + output_type.read(&crossfade.tail[old + sample..]) * (1.0 - gain);
+                output_type.write(&mut buf_out[new + sample..], value);
+            }
+            crossfade.position += 1;
+        }
+        if crossfade.position < tail_frames {
+            self.crossfade = Some(crossfade);
+        }
+        Ok((idone, written + produced))
+    }
+
+    fn is_declicking(&self) -> bool {
+        self.discard > 0 || self.faded < self.fade_len
+    }
+
+    // Drops output that belongs to the history of `reset_with_history` and fades in what follows.
+    // `buf_out` holds `odone` interleaved frames of the output datatype; returns the number of
+    // frames left at its start.
+    fn declick(&mut self, buf_out: &mut [u8], odone: usize) -> usize {
+        let (_, output_type) = self.datatypes();
+        let sample_size = output_type.sample_size();
+        let frame_size = self.channels as usize * sample_size;
+
+        let skip = self.discard.min(odone);
+        buf_out[..odone * frame_size].rotate_left(skip * frame_size);
+        self.discard -= skip;
+        let odone = odone - skip;
+
+        for frame in buf_out[..odone * frame_size].chunks_exact_mut(frame_size) {
+            if self.faded >= self.fade_len {
+                break;
+            }
+            let progress = (self.faded as f64 + 0.5) / self.fade_len as f64;
+            let gain = 0.5 - 0.5 * (std::f64::consts::PI * progress).cos();
+            for sample in frame.chunks_exact_mut(sample_size) {
+                output_type.scale(sample, gain);
+            }
+            self.faded += 1;
         }
-        split_buf.as_ptr() as *mut c_void        
+        odone
     }
 
-    /// Sets the input function of type [SoxrFunction].
+    /// Switches to pull mode with `input_fn` as input function, see [Soxr::set_input]. The
+    /// resampler is cleared first, so any input that was still buffered is dropped.
+    ///
+    /// ```rust
+    /// use libsoxr::{PullResampler, Soxr};
+    ///
+    /// let input_fn = |value: &mut f32, buffer: &mut [f32], samples: usize| {
+    ///     buffer[..samples].iter_mut().for_each(|sample| *sample = *value);
+    ///     Ok(samples)
+    /// };
+    ///
+    /// let soxr = Soxr::create(1.0, 2.0, 1, None, None, None).unwrap();
+    /// let mut soxr: PullResampler = soxr.into_pull(input_fn, 0.5f32, 100).unwrap();
+    /// let mut target = [0.0f32; 96];
+    /// assert_eq!(96, soxr.output(&mut target, 96));
+    /// ```
+    ///
+    /// The returned resampler owns `state`, so it can not hold on to a borrow:
+    ///
+    /// ```compile_fail
+    /// use libsoxr::Soxr;
+    ///
+    /// fn input_fn(value: &mut &f32, buffer: &mut [f32], samples: usize) -> libsoxr::Result<usize> {
+    ///     buffer[..samples].iter_mut().for_each(|sample| *sample = **value);
+    ///     Ok(samples)
+    /// }
+    ///
+    /// let value = 0.5f32;
+    /// let soxr = Soxr::create(1.0, 2.0, 1, None, None, None).unwrap();
+    /// let mut soxr = soxr.into_pull(input_fn, &value, 100).unwrap();
+    /// drop(value);
+    /// let mut target = [0.0f32; 96];
+    /// soxr.output(&mut target, 96);
+    /// ```
+    pub fn into_pull<S: Send + 'static, T: Sample>(
+        mut self,
+        input_fn: SoxrFunction<S, T>,
+        state: S,
+        max_samples: usize,
+    ) -> Result<PullResampler> {
+        self.clear()?;
+        let mut pull = self.into_mode::<Pull>();
+        pull.set_input(input_fn, state, max_samples)?;
+        Ok(pull)
+    }
+}
+
+impl Soxr<Pull> {
+    /// Replaces the input function of type [SoxrFunction]. The first input function is set when switching to pull
+    /// mode with [Soxr::into_pull].
     ///
     /// Please note that SoxrFunction gets a buffer as parameter which the function should fill.
     /// This is different from native `libsoxr` where you need to return the used input buffer from the input function.
     ///
-    /// The input buffer is allocated for you using a Vec<T> with `initial_capacity` set to `max_samples * channels`
-    /// that you supplied.
+    /// The input buffer is allocated for you and holds `max_samples * channels` samples. It follows
+    /// [Soxr::set_num_channels]. `T` must be the sample type of the input datatype of the [IOSpec].
+    ///
+    /// The resampler takes ownership of `state` and drops it together with the input function. Use
+    /// [Soxr::input_state] and [Soxr::input_state_mut] to reach it in between calls to `output`.
     ///
-    /// Please note that the state you pass into `set_input` may not be moved in memory. Thus it is wise to keep the
-    /// state in a Box (heap) and not on the stack. TODO: check if `Pin`is an option here.
+    /// As `Soxr` is [Send], the input function may be called on another thread than the one that registered it.
+    /// Therefore the state must be `Send` and `'static`.
     ///
     /// ## Example for 'happy flow'
     ///```rust
@@ -334,9 +1085,9 @@ impl Soxr {
     ///     return Ok(samples);
     ///  };
     ///
-    /// let mut soxr = Soxr::create(1.0, 2.0, 1, None, None, None).unwrap();
-    /// let mut state = Box::new(State { value: 1.0 });
-    /// assert!(soxr.set_input(input_fn, Some(&mut state), 100).is_ok());
+    /// let soxr = Soxr::create(1.0, 2.0, 1, None, None, None).unwrap();
+    /// let mut soxr = soxr.into_pull(input_fn, State { value: 1.0 }, 100).unwrap();
+    /// assert!(soxr.set_input(input_fn, State { value: 0.5 }, 100).is_ok());
     ///
     /// let source: [f32; 48] = [0.0, 1.0, 0.0, -1.0, 0.0, 1.0, 0.0, -1.0, 0.0, 1.0, 0.0, -1.0, 0.0,
     ///                          1.0, 0.0, -1.0, 0.0, 1.0, 0.0, -1.0, 0.0, 1.0, 0.0, -1.0, 0.0, 1.0,
@@ -366,9 +1117,9 @@ impl Soxr {
     ///     Err(Error::new(Some("input_fn".into()), ErrorType::ProcessError("Unexpected end of input".into())))
     ///  };
     ///
-    /// let mut soxr = Soxr::create(1.0, 2.0, 1, None, None, None).unwrap();
-    /// let mut state = Box::new(State { value: 1.0, state_error: None });
-    /// assert!(soxr.set_input(input_fn, Some(&mut state), 100).is_ok());
+    /// let soxr = Soxr::create(1.0, 2.0, 1, None, None, None).unwrap();
+    /// let state = State { value: 1.0, state_error: None };
+    /// let mut soxr = soxr.into_pull(input_fn, state, 100).unwrap();
     ///
     /// let source: [f32; 48] = [0.0, 1.0, 0.0, -1.0, 0.0, 1.0, 0.0, -1.0, 0.0, 1.0, 0.0, -1.0, 0.0,
     ///                          1.0, 0.0, -1.0, 0.0, 1.0, 0.0, -1.0, 0.0, 1.0, 0.0, -1.0, 0.0, 1.0,
@@ -382,32 +1133,50 @@ impl Soxr {
     /// // Please note that the ProcessError is not passed through into `error()`
     /// assert_eq!(soxr.error().unwrap(), "input function reported failure");
     /// // But you can use the State struct to pass specific errors which you can query on `soxr.error().is_some()`
+    /// let state = soxr.input_state::<State>().unwrap();
     /// assert_eq!(state.state_error, Some("Some Error"));
     ///```
-    pub fn set_input<'a, S, T>(
-        &'a mut self,
+    pub fn set_input<S: Send + 'static, T: Sample>(
+        &mut self,
         input_fn: SoxrFunction<S, T>,
-        state: Option<&'a mut S>,
+        state: S,
         max_samples: usize,
     ) -> Result<()> {
+        let (input_type, _) = self.datatypes();
+        if input_type != T::DATATYPE {
+            return Err(Error::new(
+                Some("Soxr::set_input".into()),
+                ErrorType::ProcessError(format!(
+                    "input function supplies {:?} samples, but the input is {:?}",
+                    T::DATATYPE,
+                    input_type
+                )),
+            ));
+        }
         self.drop_last_trampoline();
 
-        self.last_trampoline_data = state
-            .map(|s| TrampolineData {
+        let channels = self.channels as usize;
+        let trampoline_data = Box::new(TrampolineData {
+            header: TrampolineHeader {
                 check: "trampoline",
-                input_state: s,
-                input_fn,
+                consumed: 0,
                 last_error: None,
-                input_buffer_size: max_samples * self.channels as usize,
-                input_buffer: Vec::<T>::with_capacity(max_samples * self.channels as usize),
-            })
-            .map(|s| Box::into_raw(Box::new(s)) as soxr::soxr_fn_state_t_mut);
+                max_samples,
+                channels,
+                input_state: Box::new(state),
+                drop: drop_trampoline::<S, T>,
+            },
+            input_fn,
+            input_buffer: vec![T::default(); max_samples * channels],
+        });
+        let header = Box::into_raw(trampoline_data) as *mut TrampolineHeader;
+        self.last_trampoline_data = Some(header);
 
         let error = unsafe {
             soxr::soxr_set_input_fn(
                 self.soxr,
                 Some(input_trampoline::<S, T>),
-                self.last_trampoline_data.unwrap_or(ptr::null_mut()) as *mut ::std::os::raw::c_void,
+                header as *mut ::std::os::raw::c_void,
                 max_samples,
             )
         };
@@ -422,12 +1191,25 @@ impl Soxr {
         }
     }
 
+    /// State of the input function, when it is of type `S`
+    pub fn input_state<S: 'static>(&self) -> Option<&S> {
+        self.trampoline_header()
+            .and_then(|header| header.input_state.downcast_ref())
+    }
+
+    /// Mutable state of the input function, when it is of type `S`
+    pub fn input_state_mut<S: 'static>(&mut self) -> Option<&mut S> {
+        self.trampoline_header_mut()
+            .and_then(|header| header.input_state.downcast_mut())
+    }
+
     /// Resample and output a block of data using an app-supplied input function.
     /// This function must look and behave like `soxr_input_fn_t` and be registered with a
     /// previously created stream resampler using `set_input` then repeatedly call `output`.
     ///
     /// * data - App-supplied buffer(s) for resampled data.
     /// * samples - number of samples in buffer per channel, i.e. data.len() / number_of_channels
+    ///
     /// returns number of samples in buffer
     ///
     /// ```ignore
@@ -436,58 +1218,72 @@ impl Soxr {
     /// let mut buffer = [0.0f32; 100];
     /// assert!(s.output(&mut buffer[..], 100) > 0);
     /// ```
-    pub fn output<S>(&self, data: &mut [S], samples: usize) -> usize {
+    pub fn output<S>(&mut self, data: &mut [S], samples: usize) -> usize {
         assert!(
             data.len() >= samples * self.channels as usize,
             "the data buffer does not contain enough space to hold requested samples"
         );
-        unsafe { soxr::soxr_output(self.soxr, data.as_mut_ptr() as *mut c_void, samples) }
+        let produced =
+            unsafe { soxr::soxr_output(self.soxr, data.as_mut_ptr() as *mut c_void, samples) };
+        self.timeline.produced += produced as u64;
+        produced
     }
 
-    fn drop_last_trampoline(&mut self) {
-        if let Some(last_trampoline_data) = self.last_trampoline_data {
-            // unsafe assumption: we assume here that dropping TrampolineData<S,T> always equals TrampolineData<i32,f32>
-            // this is assumed because TrampolineData only contains references to S or T, but not instances of S or T
-            // This assumption is unit tested below
-            let data: Box<TrampolineData<i32, f32>> =
-                unsafe { Box::from_raw(last_trampoline_data as *mut TrampolineData<i32, f32>) };
-            drop(data);
-
-            self.last_trampoline_data = None;
-        }
+    /// Switches back to push mode. The resampler is cleared and the input function and its
+    /// state are released.
+    pub fn into_push(mut self) -> Result<PushResampler> {
+        self.clear()?;
+        self.drop_last_trampoline();
+        Ok(self.into_mode())
     }
 }
 
 // this function is called from Soxr and uses the closure inside TrampolineData
 // to get the input samples. All unsafe pointer magic happens inside this
 // function, not inside the passed closure.
-extern "C" fn input_trampoline<S, T>(
+extern "C" fn input_trampoline<S: 'static, T: Sample>(
     input_fn_state: *mut ::std::os::raw::c_void,
     data: *mut soxr::soxr_in_t,
     requested_number_of_samples: usize,
 ) -> usize {
     unsafe {
         let trampoline_data = &mut *(input_fn_state as *mut TrampolineData<S, T>);
-        assert_eq!(trampoline_data.check, "trampoline");
-
-        let input_data: &mut [T] = std::slice::from_raw_parts_mut(
-            trampoline_data.input_buffer.as_mut_slice().as_mut_ptr(),
-            trampoline_data.input_buffer_size,
-        );
-
+        let header = &mut trampoline_data.header;
+        assert_eq!(header.check, "trampoline");
+
+        // the channel count may have changed since the buffer was allocated
+        let max_samples = header.max_samples;
+        trampoline_data
+            .input_buffer
+            .resize(max_samples * header.channels, T::default());
+
+        let input_state = header
+            .input_state
+            .downcast_mut::<S>()
+            .expect("state of the input function");
         let result = (trampoline_data.input_fn)(
-            trampoline_data.input_state,
-            input_data,
+            input_state,
+            &mut trampoline_data.input_buffer[..],
             requested_number_of_samples,
         );
 
+        let header = &mut trampoline_data.header;
         match result {
+            Ok(samples_or_zero) if samples_or_zero > max_samples => {
+                header.last_error = Some(ErrorType::ProcessError(format!(
+                    "input function returned {} samples, more than the maximum of {}",
+                    samples_or_zero, max_samples
+                )));
+                *data = ptr::null_mut();
+                0
+            }
             Ok(samples_or_zero) => {
-                *data = trampoline_data.input_buffer.as_slice().as_ptr() as soxr::soxr_in_t;
+                *data = trampoline_data.input_buffer.as_ptr() as soxr::soxr_in_t;
+                header.consumed += samples_or_zero as u64;
                 samples_or_zero
             }
             Err(Error(_, e)) => {
-                trampoline_data.last_error = Some(e);
+                header.last_error = Some(e);
                 *data = ptr::null_mut();
                 0
             }
@@ -495,20 +1291,37 @@ extern "C" fn input_trampoline<S, T>(
     }
 }
 
-// This struct is passed to the input_trampoline function
-// which uses it to call the closure `input_fn` with `input_state`
+// Drops the boxed `TrampolineData<S, T>` that `header` is the first field of
+unsafe fn drop_trampoline<S, T>(header: *mut TrampolineHeader) {
+    drop(Box::from_raw(header as *mut TrampolineData<S, T>));
+}
+
+// The part of the trampoline data that does not depend on S or T. `Soxr` only keeps a pointer
+// to this header, which is the first field of the `#[repr(C)]` TrampolineData<S, T>, and
+// reaches the rest through the functions that were instantiated for S and T.
 // last_error is used to record the error that input_fn returns.
 // TODO: figure out how to pass this error to calling soxr_output which does not have access to this trampoline struct
-struct TrampolineData<'a, S, T> {
+#[repr(C)]
+struct TrampolineHeader {
     check: &'static str,
-    input_state: &'a mut S,
-    input_fn: SoxrFunction<S, T>,
+    consumed: u64,
     last_error: Option<ErrorType>,
-    input_buffer_size: usize,
+    max_samples: usize,
+    channels: usize,
+    input_state: Box<dyn Any + Send>,
+    drop: unsafe fn(*mut TrampolineHeader),
+}
+
+// This struct is passed to the input_trampoline function
+// which uses it to call the closure `input_fn` with the `input_state` of the header
+#[repr(C)]
+struct TrampolineData<S, T> {
+    header: TrampolineHeader,
+    input_fn: SoxrFunction<S, T>,
     input_buffer: Vec<T>,
 }
 
-impl Drop for Soxr {
+impl<M> Drop for Soxr<M> {
     fn drop(&mut self) {
         // clean up memory used for trampoline data
         self.drop_last_trampoline();
@@ -523,6 +1336,7 @@ mod soxr_tests {
     use approx::assert_abs_diff_eq;
 
     use super::{Soxr, TrampolineData};
+    use crate::datatype::Datatype;
     use crate::spec::{IOSpec, QualitySpec, RuntimeSpec};
 
     #[test]
@@ -578,16 +1392,29 @@ mod soxr_tests {
     }
 
     #[test]
+    #[allow(deprecated)]
     fn test_set_io_ratio() {
         let mut s = Soxr::create(96000.0, 44100.0, 2, None, None, None).unwrap();
         let result = s.set_io_ratio(0.1, 1);
         assert!(result.is_err());
     }
 
+    #[test]
+    #[allow(deprecated)]
+    fn test_deprecated_set_io_ratio_moves_positions() {
+        use crate::spec::{QualityFlags, QualityRecipe, QualitySpec};
+
+        let quality_spec = QualitySpec::new(&QualityRecipe::High, QualityFlags::VR);
+        let mut soxr = Soxr::create(2.0, 1.0, 1, None, Some(&quality_spec), None).unwrap();
+        soxr.set_io_ratio(1.5, 0).unwrap();
+        assert_abs_diff_eq!(150.0, soxr.output_to_input_position(100.0).unwrap());
+        assert!(soxr.set_io_ratio(2.5, 0).is_err());
+    }
+
     #[test]
     fn test_process_mono() {
         // upscale factor 2, one channel with all the defaults
-        let soxr = Soxr::create(1.0, 2.0, 1, None, None, None).unwrap();
+        let mut soxr = Soxr::create(1.0, 2.0, 1, None, None, None).unwrap();
 
         // source data, taken from 1-single-block.c of libsoxr examples.
         let source: [f32; 48] = [
@@ -613,7 +1440,7 @@ mod soxr_tests {
     #[test]
     fn test_process_stereo() {
         // upscale factor 2, one channel with all the defaults
-        let soxr = Soxr::create(1.0, 2.0, 2, None, None, None).unwrap();
+        let mut soxr = Soxr::create(1.0, 2.0, 2, None, None, None).unwrap();
 
         // source data, taken from 1-single-block.c of libsoxr examples.
         let source: [f32; 48] = [
@@ -637,10 +1464,11 @@ mod soxr_tests {
     }
 
     #[test]
+    #[allow(clippy::needless_range_loop)]
     fn test_process_stereo_2() {
         // Example from https://github.com/lrbalt/libsoxr-rs/issues/4
 
-        let soxr = Soxr::create(1.0, 2.0, 2, None, None, None).unwrap();
+        let mut soxr = Soxr::create(1.0, 2.0, 2, None, None, None).unwrap();
         let mut in_buf: [f32; 2000] = [1.0; 2000];
         for n in 1000..2000 {
             in_buf[n] = -1.0
@@ -679,18 +1507,23 @@ mod soxr_tests {
 
     #[test]
     fn test_drop_assumption() {
-        // Drop assumes the following two concrete types of TrampolineData have same size
-        // so dropping TrampolineData<S,T> without knowing S or T is same as dropping
-        // TrampolineData<i32, f32> (or any type for that matter)
+        // Soxr keeps a pointer to the TrampolineHeader without knowing S or T, which works
+        // because the header is the first field of TrampolineData<S, T> for any S and T
         struct MyData {
             _v1: f32,
             _v2: Vec<f32>,
         }
 
-        let s1 = std::mem::size_of::<TrampolineData<i32, f32>>();
-        let s2 = std::mem::size_of::<TrampolineData<MyData, f64>>();
+        fn header_offset<S, T>() -> usize {
+            let data = std::mem::MaybeUninit::<TrampolineData<S, T>>::uninit();
+            let base = data.as_ptr();
+            // SAFETY: only the address of the field is taken, nothing is read
+            let header = unsafe { std::ptr::addr_of!((*base).header) };
+            header as usize - base as usize
+        }
 
-        assert_eq!(s1, s2);
+        assert_eq!(0, header_offset::<i32, f32>());
+        assert_eq!(0, header_offset::<MyData, f64>());
     }
 
     #[test]
@@ -716,11 +1549,10 @@ mod soxr_tests {
         let mut i = 0;
         while i < 100 {
             {
-                let mut soxr = Soxr::create(1.0, 1.0, 2, None, None, None).unwrap();
-                let mut state = MyState { check: "test" };
+                let soxr = Soxr::create(1.0, 1.0, 2, None, None, None).unwrap();
+                let state = MyState { check: "test" };
                 let mut buffer = [0.0f32; 5000];
-                soxr.set_input(test_input_fn, Some(&mut state), 500)
-                    .unwrap();
+                let mut soxr = soxr.into_pull(test_input_fn, state, 500).unwrap();
                 let mut j = 0;
                 while j < 1_000 {
                     let out = soxr.output(&mut buffer, 2500);
@@ -742,7 +1574,7 @@ mod soxr_tests {
         let io_spec = IOSpec::new(Float32S, Float64S);
 
         // upscale factor 2, one channel with all the defaults
-        let soxr = Soxr::create(1.0, 2.0, 2, Some(&io_spec), None, None).unwrap();
+        let mut soxr = Soxr::create(1.0, 2.0, 2, Some(&io_spec), None, None).unwrap();
 
         // source data, taken from 1-single-block.c of libsoxr examples.
         let source: [f32; 96] = [
@@ -774,7 +1606,7 @@ mod soxr_tests {
         let io_spec = IOSpec::new(Float32I, Float64I);
 
         // upscale factor 2, one channel with all the defaults
-        let soxr = Soxr::create(1.0, 2.0, 2, Some(&io_spec), None, None).unwrap();
+        let mut soxr = Soxr::create(1.0, 2.0, 2, Some(&io_spec), None, None).unwrap();
 
         // source data, taken from 1-single-block.c of libsoxr examples.
         let source: [f32; 96] = [
@@ -798,4 +1630,573 @@ mod soxr_tests {
         println!("{:?}", target);
         println!("{:?}", target.len());
     }
+
+    #[test]
+    fn test_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Soxr>();
+
+        let mut soxr = Soxr::create(1.0, 2.0, 1, None, None, None).unwrap();
+        let source = [0.5f32; 48];
+        let worker = std::thread::spawn(move || {
+            let mut target = [0.0f32; 96];
+            let (_, done) = soxr.process(Some(&source), &mut target).unwrap();
+            let (_, flushed) = soxr.process::<f32, _>(None, &mut target[done..]).unwrap();
+            done + flushed
+        });
+        assert_eq!(96, worker.join().unwrap());
+    }
+
+    #[test]
+    fn test_send_with_input_fn() {
+        struct Counter {
+            thread: Option<std::thread::ThreadId>,
+            samples: usize,
+        }
+
+        fn count_input_fn(
+            state: &mut Counter,
+            buf: &mut [f32],
+            samples: usize,
+        ) -> crate::Result<usize> {
+            state.thread = Some(std::thread::current().id());
+            state.samples += samples;
+            for value in buf.iter_mut().take(samples) {
+                *value = 0.5;
+            }
+            Ok(samples)
+        }
+
+        let soxr = Soxr::create(1.0, 2.0, 1, None, None, None).unwrap();
+        let state = Counter {
+            thread: None,
+            samples: 0,
+        };
+        let mut soxr = soxr.into_pull(count_input_fn, state, 100).unwrap();
+
+        // the state is owned by the resampler and moves along with it
+        let worker = std::thread::spawn(move || {
+            let mut target = [0.0f32; 1000];
+            assert_eq!(1000, soxr.output(&mut target, 1000));
+            (soxr, std::thread::current().id())
+        });
+        let (soxr, worker_id) = worker.join().unwrap();
+        let state = soxr.input_state::<Counter>().unwrap();
+        assert_eq!(Some(worker_id), state.thread);
+        assert!(state.samples >= 500);
+        assert!(soxr.input_state::<()>().is_none());
+    }
+
+    #[test]
+    fn test_pull_resampler_owns_state() {
+        use std::sync::Arc;
+
+        fn shared(value: &mut Arc<f32>, buf: &mut [f32], samples: usize) -> crate::Result<usize> {
+            buf[..samples]
+                .iter_mut()
+                .for_each(|sample| *sample = **value);
+            Ok(samples)
+        }
+
+        // the state outlives the scope it was created in
+        fn pull(value: &Arc<f32>) -> crate::PullResampler {
+            let soxr = Soxr::create(1.0, 1.0, 1, None, None, None).unwrap();
+            soxr.into_pull(shared, Arc::clone(value), 100).unwrap()
+        }
+
+        let value = Arc::new(0.5f32);
+        let mut soxr = pull(&value);
+        assert_eq!(2, Arc::strong_count(&value));
+        let mut target = [0.0f32; 1000];
+        assert_eq!(1000, soxr.output(&mut target, 1000));
+        assert_abs_diff_eq!(0.5, target[999], epsilon = 1e-3);
+
+        // and is released when switching back to push mode
+        let soxr = soxr.into_push().unwrap();
+        assert_eq!(1, Arc::strong_count(&value));
+        drop(soxr);
+    }
+
+    #[test]
+    fn test_switch_modes() {
+        fn silence(_: &mut (), buf: &mut [f32], samples: usize) -> crate::Result<usize> {
+            buf[..samples].iter_mut().for_each(|sample| *sample = 0.0);
+            Ok(samples)
+        }
+
+        let source = [1.0f32; 48];
+        let mut expected = [0.0f32; 96];
+        let mut soxr = Soxr::create(1.0, 2.0, 1, None, None, None).unwrap();
+        let (_, done) = soxr.process(Some(&source), &mut expected).unwrap();
+        soxr.process::<f32, _>(None, &mut expected[done..]).unwrap();
+
+        let mut pull = soxr.into_pull(silence, (), 100).unwrap();
+        let mut target = [1.0f32; 96];
+        assert_eq!(96, pull.output(&mut target, 96));
+        assert!(target.iter().all(|sample| *sample == 0.0));
+
+        // back in push mode the resampler starts from a clean state
+        let mut soxr = pull.into_push().unwrap();
+        let mut target = [0.0f32; 96];
+        let (_, done) = soxr.process(Some(&source), &mut target).unwrap();
+        soxr.process::<f32, _>(None, &mut target[done..]).unwrap();
+        assert_eq!(expected, target);
+    }
+
+    #[test]
+    fn test_positions() {
+        let mut soxr = Soxr::create(44100.0, 48000.0, 1, None, None, None).unwrap();
+        let ratio = 44100.0 / 48000.0;
+        let source = [0.0f32; 1000];
+        let mut target = [0.0f32; 1000];
+        let (mut consumed, mut produced) = (0, 0);
+        for _ in 0..5 {
+            let (idone, odone) = soxr.process(Some(&source), &mut target).unwrap();
+            consumed += idone as u64;
+            produced += odone as u64;
+            assert_eq!(consumed, soxr.input_position());
+            assert_eq!(produced, soxr.output_position());
+            // consumed input that is not output yet is in the delay
+            let output_end = soxr.input_to_output_position(consumed as f64).unwrap();
+            assert_abs_diff_eq!(soxr.delay(), output_end - produced as f64, epsilon = 1e-9);
+        }
+        assert_abs_diff_eq!(
+            1000.0,
+            soxr.input_to_output_position(1000.0 * ratio).unwrap()
+        );
+        assert_abs_diff_eq!(
+            1000.0 * ratio,
+            soxr.output_to_input_position(1000.0).unwrap()
+        );
+        assert_eq!(None, soxr.input_to_output_position(-1.0));
+
+        soxr.clear().unwrap();
+        assert_eq!(0, soxr.input_position());
+        assert_eq!(0, soxr.output_position());
+    }
+
+    #[test]
+    fn test_positions_in_pull_mode() {
+        fn silence(_: &mut (), buf: &mut [f32], samples: usize) -> crate::Result<usize> {
+            buf[..samples].iter_mut().for_each(|sample| *sample = 0.0);
+            Ok(samples)
+        }
+
+        let soxr = Soxr::create(1.0, 2.0, 1, None, None, None).unwrap();
+        let mut soxr = soxr.into_pull(silence, (), 100).unwrap();
+        let mut target = [0.0f32; 1000];
+        assert_eq!(1000, soxr.output(&mut target, 1000));
+        assert_eq!(1000, soxr.output_position());
+        // the input function hands out blocks of 100 frames
+        let consumed = soxr.input_position();
+        assert_eq!(0, consumed % 100);
+        assert_abs_diff_eq!(
+            soxr.delay(),
+            soxr.input_to_output_position(consumed as f64).unwrap() - 1000.0,
+            epsilon = 1e-9
+        );
+
+        // the frames of a replaced input function still count
+        soxr.set_input(silence, (), 100).unwrap();
+        assert_eq!(consumed, soxr.input_position());
+        soxr.clear().unwrap();
+        assert_eq!(0, soxr.input_position());
+    }
+
+    #[test]
+    fn test_boundaries() {
+        use super::Boundary;
+
+        // three tracks at different levels, resampled as one stream in small blocks
+        let tracks = [vec![0.5f32; 1000], vec![-0.5f32; 2345], vec![0.25f32; 777]];
+        let mut soxr = Soxr::create(44100.0, 48000.0, 1, None, None, None).unwrap();
+        let mut output = Vec::new();
+        let mut block = [0.0f32; 100];
+        let mut boundaries = Vec::new();
+        for track in tracks.iter() {
+            let mut consumed = 0;
+            while consumed < track.len() {
+                let (idone, odone) = soxr.process(Some(&track[consumed..]), &mut block).unwrap();
+                consumed += idone;
+                output.extend_from_slice(&block[..odone]);
+                for boundary in soxr.take_boundaries() {
+                    // reported as soon as the output got there
+                    assert!(boundary.output_position as usize <= output.len());
+                    assert!(boundary.output_position as usize + 100 > output.len());
+                    boundaries.push(boundary);
+                }
+            }
+            soxr.mark_boundary();
+        }
+        loop {
+            let (_, odone) = soxr.process::<f32, _>(None, &mut block).unwrap();
+            output.extend_from_slice(&block[..odone]);
+            boundaries.extend(soxr.take_boundaries());
+            if odone == 0 {
+                break;
+            }
+        }
+
+        let at = |input_position: u64| Boundary {
+            input_position,
+            output_position: (input_position as f64 * 48000.0 / 44100.0).round() as u64,
+        };
+        assert_eq!(vec![at(1000), at(3345), at(4122)], boundaries);
+        assert_eq!(at(4122).output_position as usize, output.len());
+        // the level changes where the boundaries landed
+        for boundary in boundaries[..2].iter() {
+            let frame = boundary.output_position as usize;
+            assert!(output[frame - 40] * output[frame + 40] < 0.0);
+        }
+    }
+
+    #[test]
+    fn test_clear_drops_boundaries() {
+        let mut soxr = Soxr::create(1.0, 2.0, 1, None, None, None).unwrap();
+        let mut target = [0.0f32; 100];
+        soxr.process(Some(&[0.0f32; 40]), &mut target).unwrap();
+        assert_eq!(40, soxr.mark_boundary());
+        assert!(soxr.take_boundaries().is_empty());
+        soxr.clear().unwrap();
+        soxr.process(Some(&[0.0f32; 40]), &mut target).unwrap();
+        soxr.process::<f32, _>(None, &mut target).unwrap();
+        assert!(soxr.take_boundaries().is_empty());
+    }
+
+    fn resample_all(soxr: &mut Soxr, input: &[f32]) -> Vec<f32> {
+        let mut output = vec![0.0f32; input.len() * 2 + 1024];
+        let (mut consumed, mut produced) = (0, 0);
+        while consumed < input.len() {
+            let (idone, odone) = soxr
+                .process(Some(&input[consumed..]), &mut output[produced..])
+                .unwrap();
+            consumed += idone;
+            produced += odone;
+        }
+        output.truncate(produced);
+        output
+    }
+
+    #[test]
+    fn test_reset_with_history() {
+        let source: Vec<f32> = (0..20000).map(|n| (n as f32 * 0.03).sin()).collect();
+        let mut soxr = Soxr::create(48000.0, 44100.0, 1, None, None, None).unwrap();
+        let reference = resample_all(&mut soxr, &source);
+
+        // seek from 2000 to 9600 with a history of 480 frames
+        soxr.clear().unwrap();
+        resample_all(&mut soxr, &source[..2000]);
+        soxr.reset_with_history(&source[9600 - 480..9600], 0)
+            .unwrap();
+        let output = resample_all(&mut soxr, &source[9600..]);
+        // continues as if the resampler had played everything before
+        let start = 8820;
+        for (n, sample) in output.iter().take(5000).enumerate() {
+            assert_abs_diff_eq!(reference[start + n], sample, epsilon = 1e-4);
+        }
+
+        // after a plain clear the output starts from an empty filter
+        soxr.clear().unwrap();
+        let output = resample_all(&mut soxr, &source[9600..]);
+        let transient = (0..10)
+            .map(|n| (reference[start + n] - output[n]).abs())
+            .fold(0.0, f32::max);
+        assert!(transient > 0.01);
+    }
+
+    #[test]
+    fn test_reset_with_fade_in() {
+        let io_spec = IOSpec::new(Datatype::Int16I, Datatype::Int16I);
+        let mut soxr = Soxr::create(1.0, 1.0, 2, Some(&io_spec), None, None).unwrap();
+        let source = [16384i16; 2 * 2000];
+        soxr.reset_with_history(&source[..2 * 500], 100).unwrap();
+        let mut output = [0i16; 2 * 1500];
+        let mut produced = 0;
+        while produced < 1000 {
+            let (_, odone) = soxr
+                .process(Some(&source[2 * 500..]), &mut output[2 * produced..])
+                .unwrap();
+            produced += odone;
+        }
+        // rises along the raised cosine, then stays at the level of the input
+        for (n, frame) in output[..2 * 1000].chunks_exact(2).enumerate() {
+            let progress = ((n as f64 + 0.5) / 100.0).min(1.0);
+            let gain = 0.5 - 0.5 * (std::f64::consts::PI * progress).cos();
+            assert!((frame[0] as f64 - 16384.0 * gain).abs() <= 2.0, "at {}", n);
+            assert_eq!(frame[0], frame[1]);
+        }
+    }
+
+    #[test]
+    fn test_reset_with_history_checks_types() {
+        let io_spec = IOSpec::new(Datatype::Float32S, Datatype::Float32S);
+        let mut soxr = Soxr::create(1.0, 2.0, 1, Some(&io_spec), None, None).unwrap();
+        assert!(soxr.reset_with_history(&[0.0f32; 10], 0).is_err());
+        let mut soxr = Soxr::create(1.0, 2.0, 1, None, None, None).unwrap();
+        assert!(soxr.reset_with_history(&[0.0f64; 10], 0).is_err());
+        assert!(soxr.reset_with_history(&[0.0f32; 10], 0).is_ok());
+    }
+
+    #[test]
+    fn test_fade_in_checks_output_type() {
+        let mut soxr = Soxr::create(1.0, 2.0, 1, None, None, None).unwrap();
+        soxr.reset_with_history(&[0.5f32; 100], 10).unwrap();
+        let mut wrong = [0.0f64; 100];
+        assert!(soxr.process(Some(&[0.5f32; 50]), &mut wrong).is_err());
+        let mut target = [0.0f32; 100];
+        assert!(soxr.process(Some(&[0.5f32; 50]), &mut target).is_ok());
+    }
+
+    #[test]
+    fn test_reconfigure() {
+        // a 440 Hz tone that switches from 44.1 kHz to 48 kHz after half a second
+        let tone = |rate: f64, from: usize, to: usize| -> Vec<f32> {
+            let start = from as f64 / 44100.0;
+            (0..to - from)
+                .map(|n| {
+                    (2.0 * std::f64::consts::PI * 440.0 * (start + n as f64 / rate)).sin() as f32
+                })
+                .collect()
+        };
+        let mut soxr = Soxr::create(44100.0, 48000.0, 1, None, None, None).unwrap();
+        let mut output = resample_all(&mut soxr, &tone(44100.0, 0, 22050));
+        soxr.reconfigure(48000.0, 48000.0).unwrap();
+        assert_eq!(48000.0, soxr.input_rate());
+        let mut rest = resample_all(&mut soxr, &tone(48000.0, 22050, 22050 + 24000));
+        output.append(&mut rest);
+        let mut block = [0.0f32; 100];
+        loop {
+            let (_, odone) = soxr.process::<f32, _>(None, &mut block).unwrap();
+            if odone == 0 {
+                break;
+            }
+            output.extend_from_slice(&block[..odone]);
+        }
+
+        // both halves are there, less the 5 ms of overlap
+        assert_eq!(24000 + 24000 - 240, output.len());
+        // no clicks: steps stay within what a 440 Hz tone does at 48 kHz
+        let largest_step = 2.0 * std::f32::consts::PI * 440.0 / 48000.0;
+        for (n, pair) in output[..output.len() - 100].windows(2).enumerate() {
+            assert!((pair[1] - pair[0]).abs() < largest_step * 1.5, "at {}", n);
+        }
+        // and no dropouts: every period reaches a peak
+        for period in output[..output.len() - 200].chunks(110) {
+            assert!(
+                period
+                    .iter()
+                    .fold(0.0f32, |peak, sample| peak.max(sample.abs()))
+                    > 0.5
+            );
+        }
+    }
+
+    #[test]
+    fn test_reconfigure_in_small_blocks() {
+        let mut soxr = Soxr::create(16000.0, 8000.0, 2, None, None, None).unwrap();
+        let mut target = [0.0f32; 2 * 1000];
+        soxr.process(Some(&[0.25f32; 2 * 4000]), &mut target)
+            .unwrap();
+        soxr.reconfigure(8000.0, 8000.0).unwrap();
+        // the tail of the old resampler comes out first, also a few frames at a time
+        let mut output = Vec::new();
+        let mut block = [0.0f32; 2 * 7];
+        let source = [0.25f32; 2 * 4000];
+        let mut consumed = 0;
+        while output.len() < 2 * 3000 {
+            let (idone, odone) = soxr
+                .process(Some(&source[2 * consumed..]), &mut block)
+                .unwrap();
+            consumed += idone;
+            output.extend_from_slice(&block[..2 * odone]);
+        }
+        for sample in output[2 * 100..].iter() {
+            assert_abs_diff_eq!(0.25, *sample, epsilon = 1e-3);
+        }
+
+        let io_spec = IOSpec::new(Datatype::Float32S, Datatype::Float32S);
+        let mut soxr = Soxr::create(1.0, 2.0, 1, Some(&io_spec), None, None).unwrap();
+        assert!(soxr.reconfigure(2.0, 1.0).is_err());
+    }
+
+    #[test]
+    fn test_boundary_across_reconfigure() {
+        use super::Boundary;
+
+        let mut soxr = Soxr::create(16000.0, 8000.0, 1, None, None, None).unwrap();
+        let mut target = [0.0f32; 1000];
+        let (_, returned) = soxr.process(Some(&[0.25f32; 1600]), &mut target).unwrap();
+        assert_eq!(1600, soxr.mark_boundary());
+        // the filter delay keeps the output from getting to the boundary
+        assert!(soxr.take_boundaries().is_empty());
+        soxr.reconfigure(8000.0, 8000.0).unwrap();
+
+        let (_, odone) = soxr.process(Some(&[0.25f32; 800]), &mut target).unwrap();
+        let expected = Boundary {
+            input_position: 1600,
+            output_position: 800 - returned as u64,
+        };
+        assert!(odone as u64 >= expected.output_position);
+        assert_eq!(vec![expected], soxr.take_boundaries());
+    }
+
+    #[test]
+    fn test_crossfade_checks_buffer_types() {
+        let mut soxr = Soxr::create(16000.0, 8000.0, 1, None, None, None).unwrap();
+        let mut target = [0.0f32; 1000];
+        soxr.process(Some(&[0.25f32; 1600]), &mut target).unwrap();
+        soxr.reconfigure(8000.0, 8000.0).unwrap();
+        let mut wrong = [0.0f64; 100];
+        assert!(soxr.process(Some(&[0.25f32; 100]), &mut wrong).is_err());
+        assert!(soxr.process(Some(&[0.25f64; 100]), &mut target).is_err());
+        assert!(soxr.process(Some(&[0.25f32; 100]), &mut target).is_ok());
+    }
+
+    #[test]
+    fn test_positions_across_reconfigure() {
+        let mut soxr = Soxr::create(16000.0, 8000.0, 1, None, None, None).unwrap();
+        let mut target = [0.0f32; 1000];
+        soxr.process(Some(&[0.25f32; 1600]), &mut target).unwrap();
+        soxr.reconfigure(8000.0, 8000.0).unwrap();
+
+        // a step in the level at a marked boundary, resampled in small blocks
+        let source: Vec<f32> = (0..3000)
+            .map(|n| if n < 1000 { 0.25 } else { -0.25 })
+            .collect();
+        let mut output = Vec::new();
+        let mut block = [0.0f32; 7];
+        let mut boundaries = Vec::new();
+        let mut consumed = 0;
+        loop {
+            let buf_in = if consumed < source.len() {
+                let end = if consumed < 1000 { 1000 } else { source.len() };
+                Some(&source[consumed..end])
+            } else {
+                None
+            };
+            let (idone, odone) = soxr.process(buf_in, &mut block).unwrap();
+            consumed += idone;
+            if consumed == 1000 && boundaries.is_empty() {
+                assert_eq!(1000, soxr.mark_boundary());
+            }
+            output.extend_from_slice(&block[..odone]);
+            boundaries.extend(soxr.take_boundaries());
+            // the output of the old resampler counts too
+            assert_eq!(output.len() as u64, soxr.output_position());
+            if buf_in.is_none() && odone == 0 {
+                break;
+            }
+        }
+        assert_eq!(3000, soxr.input_position());
+
+        // the tail of the old resampler comes first, the boundary lands on the step
+        assert_eq!(1, boundaries.len());
+        let frame = boundaries[0].output_position as usize;
+        assert!(frame > 1000);
+        assert!(output[frame - 20] > 0.2 && output[frame + 20] < -0.2);
+        assert_abs_diff_eq!(
+            frame as f64,
+            soxr.input_to_output_position(1000.0).unwrap(),
+            epsilon = 0.5
+        );
+    }
+
+    #[test]
+    fn test_channel_change_in_pull_mode() {
+        struct State {
+            buffer_len: usize,
+        }
+
+        fn dc(state: &mut State, buf: &mut [f32], samples: usize) -> crate::Result<usize> {
+            state.buffer_len = buf.len();
+            for frame in buf[..samples * 2].chunks_mut(2) {
+                frame[0] = 0.25;
+                frame[1] = -0.25;
+            }
+            Ok(samples)
+        }
+
+        let soxr = Soxr::create(1.0, 1.0, 2, None, None, None).unwrap();
+        let mut soxr = soxr.into_pull(dc, State { buffer_len: 0 }, 100).unwrap();
+        let mut target = [0.0f32; 2000];
+        assert_eq!(1000, soxr.output(&mut target, 1000));
+
+        // libsoxr sets up its resamplers on create, so the number of channels is fixed
+        assert!(soxr.set_num_channels(1).is_err());
+        assert_eq!(2, soxr.num_channels());
+        soxr.set_num_channels(2).unwrap();
+        assert_eq!(1000, soxr.output(&mut target, 1000));
+        assert!(soxr.error().is_none());
+        assert_eq!(200, soxr.input_state::<State>().unwrap().buffer_len);
+        for frame in target[2 * 500..].chunks(2) {
+            assert_abs_diff_eq!(0.25, frame[0], epsilon = 1e-3);
+            assert_abs_diff_eq!(-0.25, frame[1], epsilon = 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_input_fn_returning_too_many_samples() {
+        fn too_many(_: &mut (), buf: &mut [f32], _samples: usize) -> crate::Result<usize> {
+            buf.iter_mut().for_each(|sample| *sample = 0.0);
+            Ok(buf.len() + 1)
+        }
+
+        let soxr = Soxr::create(1.0, 1.0, 1, None, None, None).unwrap();
+        let mut soxr = soxr.into_pull(too_many, (), 100).unwrap();
+        let mut target = [0.0f32; 100];
+        assert_eq!(0, soxr.output(&mut target, 100));
+        assert!(soxr.error().is_some());
+    }
+
+    #[test]
+    fn test_set_input_checks_sample_type() {
+        fn silence(_: &mut (), buf: &mut [i16], samples: usize) -> crate::Result<usize> {
+            buf[..samples].iter_mut().for_each(|sample| *sample = 0);
+            Ok(samples)
+        }
+
+        let soxr = Soxr::create(1.0, 1.0, 1, None, None, None).unwrap();
+        assert!(soxr.into_pull(silence, (), 100).is_err());
+
+        let io_spec = IOSpec::new(Datatype::Int16I, Datatype::Float32I);
+        let soxr = Soxr::create(1.0, 1.0, 1, Some(&io_spec), None, None).unwrap();
+        let mut soxr = soxr.into_pull(silence, (), 100).unwrap();
+        let mut target = [1.0f32; 100];
+        assert_eq!(100, soxr.output(&mut target, 100));
+    }
+
+    #[test]
+    fn test_process_checks_buffer_lengths() {
+        let mut soxr = Soxr::create(1.0, 1.0, 2, None, None, None).unwrap();
+        let source = [0.0f32; 3];
+        let mut target = [0.0f32; 4];
+        assert!(soxr.process(Some(&source), &mut target).is_err());
+        assert!(soxr.process(Some(&source[..2]), &mut target[..3]).is_err());
+        assert!(soxr.process::<f32, _>(None, &mut target[..3]).is_err());
+        assert!(soxr.process(Some(&source[..2]), &mut target).is_ok());
+    }
+
+    #[test]
+    fn test_interleaved_to_split() {
+        use crate::Datatype::{Float32I, Float32S};
+
+        let io_spec = IOSpec::new(Float32I, Float32S);
+        let mut soxr = Soxr::create(1.0, 1.0, 2, Some(&io_spec), None, None).unwrap();
+        let source: Vec<f32> = [0.25f32, -0.25]
+            .iter()
+            .copied()
+            .cycle()
+            .take(2000)
+            .collect();
+        let mut target = [0.0f32; 2000];
+        let (_, odone) = soxr.process(Some(&source), &mut target).unwrap();
+        assert!(odone > 500);
+        // one plane per channel
+        for sample in target[100..odone].iter() {
+            assert_abs_diff_eq!(0.25, *sample, epsilon = 1e-3);
+        }
+        for sample in target[1000 + 100..1000 + odone].iter() {
+            assert_abs_diff_eq!(-0.25, *sample, epsilon = 1e-3);
+        }
+    }
 }
@@ -4,6 +4,7 @@ use crate::datatype::Datatype;
 use libsoxr_sys as soxr;
 
 /// Runtime parameters for resampler. Can be used to control number of threads the resampler uses. Wrapper for `soxr_runtime_spec_t`
+#[derive(Debug, Clone)]
 pub struct RuntimeSpec {
     runtime_spec: soxr::soxr_runtime_spec_t,
 }
@@ -114,7 +115,7 @@ impl QualityRecipe {
 }
 
 /// QualitySpec controls the quality of the resampling. Wrapper for `soxr_quality_spec_t`
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct QualitySpec {
     quality_spec: soxr::soxr_quality_spec_t,
 }
@@ -140,6 +141,13 @@ impl QualitySpec {
         }
     }
 
+    /// returns a copy of this spec with `flags` set as well
+    pub(crate) fn with_flags(&self, flags: QualityFlags) -> QualitySpec {
+        let mut spec = self.clone();
+        spec.quality_spec.flags |= flags.bits();
+        spec
+    }
+
     /// returns inner soxr struct
     pub(crate) fn soxr_spec(&self) -> &soxr::soxr_quality_spec_t {
         &self.quality_spec
@@ -0,0 +1,379 @@
+//! Asynchronous resampling of [Stream]s and [Sink]s of sample chunks. Requires the `futures`
+//! feature.
+use crate::{
+    datatype::Sample,
+    error_handling::{Error, ErrorType, Result},
+    soxr::Soxr,
+};
+use futures::{
+    sink::Sink,
+    stream::Stream,
+    task::{Context, Poll},
+};
+use std::{collections::VecDeque, fmt, pin::Pin};
+
+/// Extra room in output frames made available for each call to the resampler
+const OUTPUT_MARGIN: usize = 1024;
+
+/// Resamples a [Stream] of chunks of interleaved samples. Every call to the resampler handles at
+/// most `block_size` frames and yields its output as one chunk, so chunks do not line up with
+/// the chunks of the inner stream. When the inner stream ends the resampler is flushed.
+///
+/// The [IOSpec](crate::spec::IOSpec) of the resampler must use the interleaved
+/// [Datatype](crate::datatype::Datatype) of `T` for both input and output.
+///
+/// ```rust
+/// use futures::{executor::block_on, stream, StreamExt, TryStreamExt};
+/// use libsoxr::{ResampleStream, Soxr};
+///
+/// let soxr = Soxr::create(1.0, 2.0, 1, None, None, None).unwrap();
+/// let source = stream::iter(vec![vec![0.5f32; 100], vec![0.25f32; 100]]);
+/// let resampled = ResampleStream::new(source, soxr, 64).unwrap();
+/// let chunks: Vec<Vec<f32>> = block_on(resampled.try_collect()).unwrap();
+/// assert_eq!(400, chunks.iter().map(Vec::len).sum::<usize>());
+/// ```
+pub struct ResampleStream<S, T> {
+    stream: S,
+    resampler: ChunkResampler<T>,
+    ready: VecDeque<Vec<T>>,
+    ended: bool,
+    finished: bool,
+}
+
+impl<S, T> ResampleStream<S, T>
+where
+    S: Stream<Item = Vec<T>> + Unpin,
+    T: Sample,
+{
+    /// Wraps `stream` which is resampled by `soxr` in blocks of at most `block_size` frames
+    pub fn new(stream: S, soxr: Soxr, block_size: usize) -> Result<ResampleStream<S, T>> {
+        Ok(ResampleStream {
+            stream,
+            resampler: ChunkResampler::new("ResampleStream::new", soxr, block_size)?,
+            ready: VecDeque::new(),
+            ended: false,
+            finished: false,
+        })
+    }
+
+    /// Returns the resampler and the inner stream
+    pub fn into_inner(self) -> (Soxr, S) {
+        (self.resampler.soxr, self.stream)
+    }
+}
+
+impl<S, T> Stream for ResampleStream<S, T>
+where
+    S: Stream<Item = Vec<T>> + Unpin,
+    T: Sample,
+{
+    type Item = Result<Vec<T>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            if let Some(chunk) = this.ready.pop_front() {
+                return Poll::Ready(Some(Ok(chunk)));
+            }
+            if this.finished {
+                return Poll::Ready(None);
+            }
+            if this.ended {
+                match this.resampler.drain() {
+                    Ok(Some(chunk)) => this.ready.push_back(chunk),
+                    Ok(None) => this.finished = true,
+                    Err(error) => {
+                        this.finished = true;
+                        return Poll::Ready(Some(Err(error)));
+                    }
+                }
+                continue;
+            }
+            match Pin::new(&mut this.stream).poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(None) => this.ended = true,
+                Poll::Ready(Some(chunk)) => {
+                    if let Err(error) = this.resampler.resample(&chunk, &mut this.ready) {
+                        this.finished = true;
+                        return Poll::Ready(Some(Err(error)));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Error of a [ResampleSink]: either the resampler or the inner sink failed
+#[derive(Debug)]
+pub enum SinkError<E> {
+    /// The resampler failed
+    Resample(Error),
+    /// The inner sink failed
+    Sink(E),
+}
+
+impl<E: fmt::Display> fmt::Display for SinkError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SinkError::Resample(error) => write!(f, "{}", error),
+            SinkError::Sink(error) => write!(f, "Inner sink error: {}", error),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> ::std::error::Error for SinkError<E> {}
+
+/// Resamples chunks of interleaved samples sent to it and forwards the resampled chunks to an
+/// inner [Sink]. Every call to the resampler handles at most `block_size` frames and its output
+/// is forwarded as one chunk. Closing the sink drains the tail of the resampler into the inner
+/// sink before closing it.
+///
+/// The [IOSpec](crate::spec::IOSpec) of the resampler must use the interleaved
+/// [Datatype](crate::datatype::Datatype) of `T` for both input and output.
+///
+/// ```rust
+/// use futures::{channel::mpsc, executor::block_on, SinkExt, StreamExt};
+/// use libsoxr::{ResampleSink, Soxr};
+///
+/// let (sender, receiver) = mpsc::unbounded();
+/// let soxr = Soxr::create(2.0, 1.0, 1, None, None, None).unwrap();
+/// let mut sink = ResampleSink::new(sender, soxr, 64).unwrap();
+///
+/// block_on(async {
+///     sink.send(vec![0.5f32; 100]).await.unwrap();
+///     sink.close().await.unwrap();
+/// });
+/// let chunks: Vec<Vec<f32>> = block_on(receiver.collect());
+/// assert_eq!(50, chunks.iter().map(Vec::len).sum::<usize>());
+/// ```
+pub struct ResampleSink<Si, T> {
+    sink: Si,
+    resampler: ChunkResampler<T>,
+    pending: VecDeque<Vec<T>>,
+    drained: bool,
+}
+
+impl<Si, T> ResampleSink<Si, T>
+where
+    Si: Sink<Vec<T>> + Unpin,
+    T: Sample,
+{
+    /// Forwards to `sink` what `soxr` resamples in blocks of at most `block_size` frames
+    pub fn new(sink: Si, soxr: Soxr, block_size: usize) -> Result<ResampleSink<Si, T>> {
+        Ok(ResampleSink {
+            sink,
+            resampler: ChunkResampler::new("ResampleSink::new", soxr, block_size)?,
+            pending: VecDeque::new(),
+            drained: false,
+        })
+    }
+
+    /// Returns the resampler and the inner sink. Chunks that have not been forwarded yet are
+    /// lost.
+    pub fn into_inner(self) -> (Soxr, Si) {
+        (self.resampler.soxr, self.sink)
+    }
+
+    fn poll_forward(&mut self, cx: &mut Context<'_>) -> Poll<::std::result::Result<(), Si::Error>> {
+        while !self.pending.is_empty() {
+            match Pin::new(&mut self.sink).poll_ready(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(error)) => return Poll::Ready(Err(error)),
+                Poll::Ready(Ok(())) => {
+                    let chunk = self.pending.pop_front().unwrap();
+                    Pin::new(&mut self.sink).start_send(chunk)?;
+                }
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<Si, T> Sink<Vec<T>> for ResampleSink<Si, T>
+where
+    Si: Sink<Vec<T>> + Unpin,
+    T: Sample,
+{
+    type Error = SinkError<Si::Error>;
+
+    fn poll_ready(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<::std::result::Result<(), Self::Error>> {
+        self.poll_forward(cx).map_err(SinkError::Sink)
+    }
+
+    fn start_send(
+        mut self: Pin<&mut Self>,
+        item: Vec<T>,
+    ) -> ::std::result::Result<(), Self::Error> {
+        let this = &mut *self;
+        this.resampler
+            .resample(&item, &mut this.pending)
+            .map_err(SinkError::Resample)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<::std::result::Result<(), Self::Error>> {
+        futures::ready!(self.poll_forward(cx)).map_err(SinkError::Sink)?;
+        Pin::new(&mut self.sink)
+            .poll_flush(cx)
+            .map_err(SinkError::Sink)
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<::std::result::Result<(), Self::Error>> {
+        while !self.drained {
+            futures::ready!(self.poll_forward(cx)).map_err(SinkError::Sink)?;
+            match self.resampler.drain().map_err(SinkError::Resample)? {
+                Some(chunk) => self.pending.push_back(chunk),
+                None => self.drained = true,
+            }
+        }
+        futures::ready!(self.poll_forward(cx)).map_err(SinkError::Sink)?;
+        Pin::new(&mut self.sink)
+            .poll_close(cx)
+            .map_err(SinkError::Sink)
+    }
+}
+
+// Resamples chunks of interleaved samples in blocks, carrying incomplete frames over
+struct ChunkResampler<T> {
+    soxr: Soxr,
+    block_size: usize,
+    partial: Vec<T>,
+}
+
+impl<T: Sample> ChunkResampler<T> {
+    fn new(func: &'static str, soxr: Soxr, block_size: usize) -> Result<ChunkResampler<T>> {
+        soxr.check_sample_type::<T>(func)?;
+        Ok(ChunkResampler {
+            soxr,
+            block_size: block_size.max(1),
+            partial: Vec::new(),
+        })
+    }
+
+    // resamples `chunk`, appending non-empty output chunks to `ready`
+    fn resample(&mut self, chunk: &[T], ready: &mut VecDeque<Vec<T>>) -> Result<()> {
+        let channels = self.soxr.num_channels() as usize;
+        let mut input = std::mem::take(&mut self.partial);
+        input.extend_from_slice(chunk);
+        let whole = input.len() - input.len() % channels;
+        self.partial.extend_from_slice(&input[whole..]);
+
+        let mut consumed = 0;
+        while consumed < whole {
+            let end = whole.min(consumed + self.block_size * channels);
+            let (idone, output) = self.process(Some(&input[consumed..end]))?;
+            if idone == 0 && output.is_empty() {
+                return Err(Error::new(
+                    Some("Soxr::process".into()),
+                    ErrorType::ProcessError("resampler did not accept input".into()),
+                ));
+            }
+            if !output.is_empty() {
+                ready.push_back(output);
+            }
+            consumed += idone * channels;
+        }
+        Ok(())
+    }
+
+    // flushes at most one block of the tail of the resampler, `None` when it is drained
+    fn drain(&mut self) -> Result<Option<Vec<T>>> {
+        self.partial.clear();
+        let (_, output) = self.process(None)?;
+        Ok(if output.is_empty() {
+            None
+        } else {
+            Some(output)
+        })
+    }
+
+    fn process(&mut self, buf_in: Option<&[T]>) -> Result<(usize, Vec<T>)> {
+        let channels = self.soxr.num_channels() as usize;
+        let ratio = self.soxr.output_rate() / self.soxr.input_rate();
+        let frames = buf_in.map_or(self.block_size, |buf_in| buf_in.len() / channels);
+        let room = (frames as f64 * ratio).ceil() as usize + OUTPUT_MARGIN;
+
+        let mut output = vec![T::default(); room * channels];
+        let (idone, odone) = self.soxr.process(buf_in, &mut output)?;
+        output.truncate(odone * channels);
+        Ok((idone, output))
+    }
+}
+
+#[cfg(test)]
+mod stream_tests {
+    use futures::{channel::mpsc, executor::block_on, stream, SinkExt, StreamExt, TryStreamExt};
+
+    use super::{ResampleSink, ResampleStream};
+    use crate::{datatype::Datatype, spec::IOSpec, Soxr};
+
+    fn source() -> Vec<Vec<f32>> {
+        (0..10)
+            .map(|chunk| {
+                (0..2 * 441)
+                    .map(|n| ((chunk * 441 + n / 2) as f32 * 0.01).sin())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_stream() {
+        let soxr = Soxr::create(44100.0, 48000.0, 2, None, None, None).unwrap();
+        let resampled = ResampleStream::new(stream::iter(source()), soxr, 100).unwrap();
+        let chunks: Vec<Vec<f32>> = block_on(resampled.try_collect()).unwrap();
+        assert_eq!(2 * 4800, chunks.iter().map(Vec::len).sum::<usize>());
+        // every chunk holds whole stereo frames
+        assert!(chunks.iter().all(|chunk| chunk.len() % 2 == 0));
+    }
+
+    #[test]
+    fn test_stream_partial_frames() {
+        let soxr = Soxr::create(44100.0, 48000.0, 2, None, None, None).unwrap();
+        let whole = ResampleStream::new(stream::iter(source()), soxr, 100).unwrap();
+        let whole: Vec<Vec<f32>> = block_on(whole.try_collect()).unwrap();
+
+        // chunks of 3 samples split frames in half
+        let samples: Vec<f32> = source().concat();
+        let split: Vec<Vec<f32>> = samples.chunks(3).map(|chunk| chunk.to_vec()).collect();
+        let soxr = Soxr::create(44100.0, 48000.0, 2, None, None, None).unwrap();
+        let split = ResampleStream::new(stream::iter(split), soxr, 100).unwrap();
+        let split: Vec<Vec<f32>> = block_on(split.try_collect()).unwrap();
+
+        assert_eq!(whole.concat(), split.concat());
+    }
+
+    #[test]
+    fn test_sample_type_mismatch() {
+        let io_spec = IOSpec::new(Datatype::Int16I, Datatype::Int16I);
+        let soxr = Soxr::create(44100.0, 48000.0, 2, Some(&io_spec), None, None).unwrap();
+        assert!(ResampleStream::new(stream::iter(source()), soxr, 100).is_err());
+    }
+
+    #[test]
+    fn test_sink() {
+        let (sender, receiver) = mpsc::unbounded();
+        let soxr = Soxr::create(44100.0, 48000.0, 2, None, None, None).unwrap();
+        let mut sink = ResampleSink::new(sender, soxr, 256).unwrap();
+        block_on(async {
+            for chunk in source() {
+                sink.send(chunk).await.unwrap();
+            }
+            sink.close().await.unwrap();
+        });
+        let sent: Vec<Vec<f32>> = block_on(receiver.collect());
+
+        let soxr = Soxr::create(44100.0, 48000.0, 2, None, None, None).unwrap();
+        let streamed = ResampleStream::new(stream::iter(source()), soxr, 256).unwrap();
+        let streamed: Vec<Vec<f32>> = block_on(streamed.try_collect()).unwrap();
+        assert_eq!(streamed.concat(), sent.concat());
+    }
+}
@@ -0,0 +1,507 @@
+//! Time stretching and pitch shifting
+use crate::{
+    aligned::AlignedSoxr,
+    error_handling::{Error, ErrorType, Result},
+    soxr::Soxr,
+    spec::{QualitySpec, RuntimeSpec},
+};
+
+/// Length in seconds of the frames that are overlapped and added
+const FRAME_SECS: f64 = 0.03;
+/// How far in seconds a frame may be moved to match the previous one
+const TOLERANCE_SECS: f64 = 0.008;
+
+/// Changes the duration and the pitch of a signal independently. The duration is changed by
+/// WSOLA, waveform similarity overlap-add: frames of 30 ms are cut from the input at the
+/// stretched rate, each shifted by up to 8 ms to line up with the previous one, and overlapped
+/// into the output with a Hann window. A pitch shift by `p` is made by stretching `p` times more
+/// and then resampling by `p` with a [Soxr] built from the given [QualitySpec], so that the
+/// duration comes out right again.
+///
+/// Works on interleaved `f32` samples, streaming with [TimeStretcher::process] and
+/// [TimeStretcher::finish], or offline with [TimeStretcher::process_offline].
+///
+/// ```rust
+/// use libsoxr::TimeStretcher;
+///
+/// let source = [0.0f32; 2 * 44100];
+///
+/// // half speed, same pitch
+/// let mut stretcher = TimeStretcher::time_stretch(44100.0, 2, 2.0).unwrap();
+/// assert_eq!(2 * 88200, stretcher.process_offline(&source).unwrap().len());
+///
+/// // a fifth up, same duration
+/// let mut shifter = TimeStretcher::pitch_shift(44100.0, 2, 7.0, None).unwrap();
+/// assert_eq!(2 * 44100, shifter.process_offline(&source).unwrap().len());
+/// ```
+#[derive(Debug)]
+pub struct TimeStretcher {
+    channels: usize,
+    stretch: f64,
+    pitch: f64,
+    wsola: Wsola,
+    resampler: Option<AlignedSoxr>,
+    stretched: Vec<f32>,
+}
+
+impl TimeStretcher {
+    /// Creates a stretcher for `num_channels` channels at `sample_rate` that makes the signal
+    /// `stretch` times as long and shifts its pitch by `semitones`. The resampler for the pitch
+    /// shift uses `quality_spec` and `runtime_spec`, or the libsoxr defaults when `None`.
+    pub fn new(
+        sample_rate: f64,
+        num_channels: u32,
+        stretch: f64,
+        semitones: f64,
+        quality_spec: Option<&QualitySpec>,
+        runtime_spec: Option<&RuntimeSpec>,
+    ) -> Result<TimeStretcher> {
+        if num_channels == 0 {
+            return Err(Error::new(
+                Some("TimeStretcher::new".into()),
+                ErrorType::CreateError("at least one channel is needed".into()),
+            ));
+        }
+        let pitch = (semitones / 12.0).exp2();
+        if !(stretch > 0.0 && stretch.is_finite() && pitch.is_finite() && pitch > 0.0) {
+            return Err(Error::new(
+                Some("TimeStretcher::new".into()),
+                ErrorType::CreateError(format!(
+                    "stretch {} and {} semitones are out of range",
+                    stretch, semitones
+                )),
+            ));
+        }
+        let resampler = if semitones == 0.0 {
+            None
+        } else {
+            let soxr = Soxr::create(
+                sample_rate * pitch,
+                sample_rate,
+                num_channels,
+                None,
+                quality_spec,
+                runtime_spec,
+            )?;
+            Some(AlignedSoxr::new(soxr)?)
+        };
+        let channels = num_channels as usize;
+        Ok(TimeStretcher {
+            channels,
+            stretch,
+            pitch,
+            wsola: Wsola::new(channels, sample_rate, stretch * pitch),
+            resampler,
+            stretched: Vec::new(),
+        })
+    }
+
+    /// Creates a stretcher that makes the signal `stretch` times as long at the same pitch
+    pub fn time_stretch(
+        sample_rate: f64,
+        num_channels: u32,
+        stretch: f64,
+    ) -> Result<TimeStretcher> {
+        TimeStretcher::new(sample_rate, num_channels, stretch, 0.0, None, None)
+    }
+
+    /// Creates a stretcher that shifts the pitch by `semitones` at the same duration
+    pub fn pitch_shift(
+        sample_rate: f64,
+        num_channels: u32,
+        semitones: f64,
+        quality_spec: Option<&QualitySpec>,
+    ) -> Result<TimeStretcher> {
+        TimeStretcher::new(
+            sample_rate,
+            num_channels,
+            1.0,
+            semitones,
+            quality_spec,
+            None,
+        )
+    }
+
+    /// Factor by which the duration changes
+    pub fn stretch(&self) -> f64 {
+        self.stretch
+    }
+
+    /// Shift of the pitch in semitones
+    pub fn semitones(&self) -> f64 {
+        12.0 * self.pitch.log2()
+    }
+
+    /// Stretches the interleaved frames in `buf_in` and appends what is ready of the result to
+    /// `buf_out`
+    pub fn process(&mut self, buf_in: &[f32], buf_out: &mut Vec<f32>) -> Result<()> {
+        if buf_in.len() % self.channels != 0 {
+            return Err(Error::new(
+                Some("TimeStretcher::process".into()),
+                ErrorType::ProcessError("input does not hold whole frames".into()),
+            ));
+        }
+        self.wsola.push(buf_in);
+        self.wsola.run(&mut self.stretched, false);
+        self.resample(buf_out, false)
+    }
+
+    /// Ends the stream: appends the rest of the result to `buf_out`, after which the stretcher
+    /// is ready for a new stream
+    pub fn finish(&mut self, buf_out: &mut Vec<f32>) -> Result<()> {
+        self.wsola.run(&mut self.stretched, true);
+        self.resample(buf_out, true)?;
+        self.clear()
+    }
+
+    /// Stretches a complete signal into exactly `round(frames * stretch)` frames
+    pub fn process_offline(&mut self, input: &[f32]) -> Result<Vec<f32>> {
+        let frames = input.len() / self.channels;
+        let mut output = Vec::with_capacity(
+            ((frames as f64 * self.stretch).round() as usize + 1) * self.channels,
+        );
+        self.process(&input[..frames * self.channels], &mut output)?;
+        self.finish(&mut output)?;
+        output.resize(
+            (frames as f64 * self.stretch).round() as usize * self.channels,
+            0.0,
+        );
+        Ok(output)
+    }
+
+    /// Drops all input and output of the current stream
+    pub fn clear(&mut self) -> Result<()> {
+        self.wsola.clear();
+        self.stretched.clear();
+        match self.resampler.as_mut() {
+            Some(resampler) => resampler.clear(),
+            None => Ok(()),
+        }
+    }
+
+    fn resample(&mut self, buf_out: &mut Vec<f32>, flush: bool) -> Result<()> {
+        let channels = self.channels;
+        let resampler = match self.resampler.as_mut() {
+            Some(resampler) => resampler,
+            None => {
+                buf_out.append(&mut self.stretched);
+                return Ok(());
+            }
+        };
+
+        let frames = self.stretched.len() / channels;
+        let mut consumed = 0;
+        while consumed < frames {
+            let room = ((frames - consumed) as f64 / self.pitch).ceil() as usize + 64;
+            let start = buf_out.len();
+            buf_out.resize(start + room * channels, 0.0);
+            let (idone, odone) = resampler.process(
+                Some(&self.stretched[consumed * channels..]),
+                &mut buf_out[start..],
+            )?;
+            buf_out.truncate(start + odone * channels);
+            if idone == 0 && odone == 0 {
+                return Err(Error::new(
+                    Some("TimeStretcher::process".into()),
+                    ErrorType::ProcessError("resampler did not accept input".into()),
+                ));
+            }
+            consumed += idone;
+        }
+        self.stretched.clear();
+
+        if !flush {
+            return Ok(());
+        }
+        loop {
+            let start = buf_out.len();
+            buf_out.resize(start + 4096 * channels, 0.0);
+            let (_, odone) = resampler.process::<f32, _>(None, &mut buf_out[start..])?;
+            buf_out.truncate(start + odone * channels);
+            if odone == 0 {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Streaming WSOLA. Positions are frames counted from the start of the input, which is preceded
+// by `hop` frames of silence so that the output does not fade in.
+#[derive(Debug)]
+struct Wsola {
+    channels: usize,
+    factor: f64,
+    frame: usize,
+    hop: usize,
+    tolerance: usize,
+    window: Vec<f32>,
+    input: Vec<f32>,
+    input_start: usize,
+    consumed: u64,
+    analysis: f64,
+    previous: Option<usize>,
+    overlap: Vec<f32>,
+    emitted: u64,
+}
+
+impl Wsola {
+    fn new(channels: usize, sample_rate: f64, factor: f64) -> Wsola {
+        let hop = ((FRAME_SECS * sample_rate / 2.0).round() as usize).max(1);
+        let frame = 2 * hop;
+        // periodic Hann windows at half overlap add up to one
+        let window = (0..frame)
+            .map(|n| {
+                let phase = 2.0 * std::f64::consts::PI * n as f64 / frame as f64;
+                (0.5 - 0.5 * phase.cos()) as f32
+            })
+            .collect();
+        let mut wsola = Wsola {
+            channels,
+            factor,
+            frame,
+            hop,
+            tolerance: (TOLERANCE_SECS * sample_rate).round() as usize,
+            window,
+            input: Vec::new(),
+            input_start: 0,
+            consumed: 0,
+            analysis: 0.0,
+            previous: None,
+            overlap: Vec::new(),
+            emitted: 0,
+        };
+        wsola.clear();
+        wsola
+    }
+
+    fn clear(&mut self) {
+        self.input.clear();
+        self.input.resize(self.hop * self.channels, 0.0);
+        self.input_start = 0;
+        self.consumed = 0;
+        self.analysis = 0.0;
+        self.previous = None;
+        self.overlap.clear();
+        self.overlap.resize(self.frame * self.channels, 0.0);
+        self.emitted = 0;
+    }
+
+    fn push(&mut self, input: &[f32]) {
+        self.input.extend_from_slice(input);
+        self.consumed += (input.len() / self.channels) as u64;
+    }
+
+    fn input_end(&self) -> usize {
+        self.input_start + self.input.len() / self.channels
+    }
+
+    fn sample(&self, frame: usize, channel: usize) -> f32 {
+        self.input[(frame - self.input_start) * self.channels + channel]
+    }
+
+    // Adds frames to the output while there is enough input. When flushing, the input is padded
+    // with silence until the output has its full length.
+    fn run(&mut self, output: &mut Vec<f32>, flush: bool) {
+        let total = (self.consumed as f64 * self.factor).round() as u64 + self.hop as u64;
+        loop {
+            if flush && self.emitted >= total {
+                break;
+            }
+            let nominal = self.analysis.round() as usize;
+            let (low, high) = match self.previous {
+                Some(_) => (
+                    nominal.saturating_sub(self.tolerance),
+                    nominal + self.tolerance,
+                ),
+                None => (nominal, nominal),
+            };
+            let mut needed = high + self.frame;
+            if let Some(previous) = self.previous {
+                needed = needed.max(previous + self.hop + self.frame);
+            }
+            if needed > self.input_end() {
+                if !flush {
+                    break;
+                }
+                self.input
+                    .resize((needed - self.input_start) * self.channels, 0.0);
+            }
+
+            let start = match self.previous {
+                Some(previous) => self.best_match(previous + self.hop, nominal, low, high),
+                None => nominal,
+            };
+            for n in 0..self.frame {
+                for channel in 0..self.channels {
+                    self.overlap[n * self.channels + channel] +=
+                        self.window[n] * self.sample(start + n, channel);
+                }
+            }
+            for frame in self.overlap.chunks_exact(self.channels).take(self.hop) {
+                if self.emitted >= self.hop as u64 && (!flush || self.emitted < total) {
+                    output.extend_from_slice(frame);
+                }
+                self.emitted += 1;
+            }
+            self.overlap.drain(..self.hop * self.channels);
+            self.overlap.resize(self.frame * self.channels, 0.0);
+            self.previous = Some(start);
+            self.analysis += self.hop as f64 / self.factor;
+
+            // keep the input the next frame may need
+            let keep = (start + self.hop)
+                .min((self.analysis.round() as usize).saturating_sub(self.tolerance));
+            if keep > self.input_start {
+                self.input
+                    .drain(..(keep - self.input_start) * self.channels);
+                self.input_start = keep;
+            }
+        }
+    }
+
+    // Start in `low..=high` of the frame whose overlapping part correlates best with the
+    // natural continuation of the previous frame at `template`, preferring `nominal`
+    fn best_match(&self, template: usize, nominal: usize, low: usize, high: usize) -> usize {
+        let overlap = self.frame - self.hop;
+        let correlation = |candidate: usize| -> f32 {
+            let mut sum = 0.0;
+            for n in 0..overlap {
+                for channel in 0..self.channels {
+                    sum += self.sample(template + n, channel) * self.sample(candidate + n, channel);
+                }
+            }
+            sum
+        };
+        let (mut best, mut best_correlation) = (nominal, correlation(nominal));
+        for candidate in low..=high {
+            let correlation = correlation(candidate);
+            if correlation > best_correlation {
+                best = candidate;
+                best_correlation = correlation;
+            }
+        }
+        best
+    }
+}
+
+#[cfg(test)]
+mod stretch_tests {
+    use super::TimeStretcher;
+    use crate::spec::{QualityFlags, QualityRecipe, QualitySpec};
+
+    const RATE: f64 = 8000.0;
+
+    fn sine(frequency: f64, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .map(|n| (2.0 * std::f64::consts::PI * frequency * n as f64 / RATE).sin() as f32)
+            .collect()
+    }
+
+    // frequency from the zero crossings of one channel, leaving out the edges
+    fn frequency(signal: &[f32], channels: usize, channel: usize) -> f64 {
+        let samples: Vec<f32> = signal
+            .iter()
+            .skip(channel)
+            .step_by(channels)
+            .copied()
+            .collect();
+        let middle = &samples[samples.len() / 10..samples.len() * 9 / 10];
+        let crossings = middle
+            .windows(2)
+            .filter(|pair| (pair[0] < 0.0) != (pair[1] < 0.0))
+            .count();
+        crossings as f64 / 2.0 / (middle.len() as f64 / RATE)
+    }
+
+    #[test]
+    fn test_identity() {
+        let source = sine(440.0, 8000);
+        let mut stretcher = TimeStretcher::time_stretch(RATE, 1, 1.0).unwrap();
+        let target = stretcher.process_offline(&source).unwrap();
+        assert_eq!(source.len(), target.len());
+        for (s, t) in source.iter().zip(target.iter()).take(7000) {
+            assert!((s - t).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_time_stretch_keeps_pitch() {
+        for stretch in [0.5, 1.5, 2.5].iter() {
+            let source = sine(440.0, 8000);
+            let mut stretcher = TimeStretcher::time_stretch(RATE, 1, *stretch).unwrap();
+            let target = stretcher.process_offline(&source).unwrap();
+            assert_eq!((8000.0 * stretch) as usize, target.len());
+            let frequency = frequency(&target, 1, 0);
+            assert!(
+                (frequency - 440.0).abs() < 10.0,
+                "{}: {}",
+                stretch,
+                frequency
+            );
+        }
+    }
+
+    #[test]
+    fn test_pitch_shift_keeps_duration() {
+        let quality_spec = QualitySpec::new(&QualityRecipe::Low, QualityFlags::empty());
+        for semitones in [12.0, -5.0, 3.5].iter() {
+            let source = sine(440.0, 8000);
+            let mut shifter =
+                TimeStretcher::pitch_shift(RATE, 1, *semitones, Some(&quality_spec)).unwrap();
+            let target = shifter.process_offline(&source).unwrap();
+            assert_eq!(8000, target.len());
+            let expected = 440.0 * (semitones / 12.0f64).exp2();
+            let frequency = frequency(&target, 1, 0);
+            assert!(
+                (frequency - expected).abs() < expected * 0.02,
+                "{}: {}",
+                semitones,
+                frequency
+            );
+        }
+    }
+
+    #[test]
+    fn test_stretch_and_shift_stereo() {
+        let (left, right) = (sine(300.0, 8000), sine(500.0, 8000));
+        let source: Vec<f32> = left
+            .iter()
+            .zip(right.iter())
+            .flat_map(|(l, r)| vec![*l, *r])
+            .collect();
+        let mut stretcher = TimeStretcher::new(RATE, 2, 1.25, -12.0, None, None).unwrap();
+        let target = stretcher.process_offline(&source).unwrap();
+        assert_eq!(2 * 10000, target.len());
+        assert!((frequency(&target, 2, 0) - 150.0).abs() < 5.0);
+        assert!((frequency(&target, 2, 1) - 250.0).abs() < 5.0);
+    }
+
+    #[test]
+    fn test_streaming_matches_offline() {
+        let source = sine(440.0, 8000);
+        let offline = TimeStretcher::new(RATE, 1, 1.3, 2.0, None, None)
+            .unwrap()
+            .process_offline(&source)
+            .unwrap();
+
+        let mut stretcher = TimeStretcher::new(RATE, 1, 1.3, 2.0, None, None).unwrap();
+        let mut streamed = Vec::new();
+        for chunk in source.chunks(123) {
+            stretcher.process(chunk, &mut streamed).unwrap();
+        }
+        stretcher.finish(&mut streamed).unwrap();
+        assert!((streamed.len() as i64 - offline.len() as i64).abs() <= 1);
+        let len = streamed.len().min(offline.len());
+        assert_eq!(offline[..len], streamed[..len]);
+    }
+
+    #[test]
+    fn test_invalid_parameters() {
+        assert!(TimeStretcher::time_stretch(RATE, 1, 0.0).is_err());
+        assert!(TimeStretcher::time_stretch(RATE, 1, f64::NAN).is_err());
+        assert!(TimeStretcher::time_stretch(RATE, 0, 1.0).is_err());
+        let mut stretcher = TimeStretcher::time_stretch(RATE, 2, 1.0).unwrap();
+        assert!(stretcher.process(&[0.0; 3], &mut Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,45 @@
+//! Helpers shared by the unit tests
+
+/// Tiny xorshift generator, so noise is the same on every run
+pub(crate) struct Random(u64);
+
+impl Random {
+    pub(crate) fn new(seed: u64) -> Random {
+        Random(seed)
+    }
+
+    /// Uniform in `[0, 1)`
+    pub(crate) fn next(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// An event sent by a remote clock and the local time it arrived at
+pub(crate) struct Arrival {
+    pub(crate) index: u64,
+    pub(crate) sent: f64,
+    pub(crate) arrival: f64,
+}
+
+/// Events sent every `period` seconds of a remote clock running `ppm` fast. Each arrives after a
+/// uniformly random delay of up to `max_delay(sent)` seconds, drawn from a [Random] seeded with
+/// `seed`.
+pub(crate) fn noisy_arrivals<F: Fn(f64) -> f64>(
+    seed: u64,
+    period: f64,
+    ppm: f64,
+    max_delay: F,
+) -> impl Iterator<Item = Arrival> {
+    let mut random = Random::new(seed);
+    (0..).map(move |index| {
+        let sent = index as f64 * period / (1.0 + ppm * 1e-6);
+        Arrival {
+            index,
+            sent,
+            arrival: sent + max_delay(sent) * random.next(),
+        }
+    })
+}
@@ -0,0 +1,250 @@
+//! Mapping between input and output positions of a resampler
+use crate::soxr::Boundary;
+use std::collections::VecDeque;
+
+/// Number of io ratio changes that are remembered for mapping positions
+const MAX_SEGMENTS: usize = 4096;
+
+/// Counts the frames a resampler consumed and produced and remembers the io ratio changes, so
+/// that input positions can be mapped to output positions and back. Output frame `n` sits at
+/// input position `sum(io_ratio)` over the frames before it, so with a fixed ratio input position
+/// `p` maps to output position `p / io_ratio`.
+#[derive(Debug, Clone)]
+pub(crate) struct Timeline {
+    pub(crate) consumed: u64,
+    pub(crate) produced: u64,
+    segments: VecDeque<Segment>,
+    boundaries: VecDeque<Mark>,
+}
+
+// A marked boundary at input position `input_position`. The output frame is fixed once the
+// segment it falls in is dropped, so that it can still be reported.
+#[derive(Debug, Clone, Copy)]
+struct Mark {
+    input_position: u64,
+    output_position: Option<u64>,
+}
+
+// From output frame `output_start` on, which sits at `input_start`, the io ratio moves linearly
+// from `from` to `to` over `slew_len` frames
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    output_start: u64,
+    input_start: f64,
+    from: f64,
+    to: f64,
+    slew_len: u64,
+}
+
+impl Segment {
+    fn io_ratio_at(&self, offset: f64) -> f64 {
+        if offset >= self.slew_len as f64 {
+            return self.to;
+        }
+        self.from + (self.to - self.from) * offset / self.slew_len as f64
+    }
+
+    // input frames consumed by the `offset` output frames from the start of the segment
+    fn input_offset(&self, offset: f64) -> f64 {
+        let slew_len = self.slew_len as f64;
+        if offset <= slew_len {
+            let step = (self.to - self.from) / slew_len.max(1.0);
+            self.from * offset + step * offset * (offset - 1.0) / 2.0
+        } else {
+            self.input_offset(slew_len) + (offset - slew_len) * self.to
+        }
+    }
+
+    fn output_offset(&self, input_offset: f64) -> f64 {
+        let slew_len = self.slew_len as f64;
+        let slewed = self.input_offset(slew_len);
+        if input_offset > slewed {
+            return slew_len + (input_offset - slewed) / self.to;
+        }
+        // solve from * m + step * m * (m - 1) / 2 = input_offset
+        let a = (self.to - self.from) / slew_len.max(1.0) / 2.0;
+        let b = self.from - a;
+        if a.abs() < 1e-15 {
+            input_offset / b
+        } else {
+            (-b + (b * b + 4.0 * a * input_offset).sqrt()) / (2.0 * a)
+        }
+    }
+}
+
+impl Timeline {
+    pub(crate) fn new(io_ratio: f64) -> Timeline {
+        Timeline::starting_at(io_ratio, 0)
+    }
+
+    /// Timeline whose input starts at output frame `output_start`, for output that comes first
+    /// from elsewhere
+    pub(crate) fn starting_at(io_ratio: f64, output_start: u64) -> Timeline {
+        Timeline {
+            consumed: 0,
+            produced: 0,
+            segments: VecDeque::from(vec![Segment {
+                output_start,
+                input_start: 0.0,
+                from: io_ratio,
+                to: io_ratio,
+                slew_len: 0,
+            }]),
+            boundaries: VecDeque::new(),
+        }
+    }
+
+    /// Starts over at position zero, keeping the io ratio that was last set
+    pub(crate) fn reset(&mut self) {
+        let io_ratio = self.last().to;
+        *self = Timeline::new(io_ratio);
+    }
+
+    /// Records a change of the io ratio at the current output position, or at the start of the
+    /// input when the output has not got there yet
+    pub(crate) fn set_io_ratio(&mut self, io_ratio: f64, slew_len: usize) {
+        let last = *self.last();
+        let output_start = self.produced.max(last.output_start);
+        let offset = (output_start - last.output_start) as f64;
+        let segment = Segment {
+            output_start,
+            input_start: last.input_start + last.input_offset(offset),
+            from: last.io_ratio_at(offset),
+            to: io_ratio,
+            slew_len: slew_len as u64,
+        };
+        // a change at the same frame replaces the previous one
+        if last.output_start == output_start {
+            self.segments.pop_back();
+        } else if self.segments.len() == MAX_SEGMENTS {
+            let dropped = self.segments.pop_front().unwrap();
+            let next_start = self.segments[0].input_start;
+            for mark in self.boundaries.iter_mut() {
+                if mark.input_position as f64 >= next_start {
+                    break;
+                }
+                if mark.output_position.is_none() {
+                    let offset =
+                        dropped.output_offset(mark.input_position as f64 - dropped.input_start);
+                    mark.output_position =
+                        Some((dropped.output_start as f64 + offset).round() as u64);
+                }
+            }
+        }
+        self.segments.push_back(segment);
+    }
+
+    pub(crate) fn input_to_output(&self, position: f64) -> Option<f64> {
+        let first = self.segments[0];
+        if position < first.input_start || !position.is_finite() {
+            return None;
+        }
+        let segment = self
+            .segments
+            .iter()
+            .rev()
+            .find(|segment| segment.input_start <= position)?;
+        Some(segment.output_start as f64 + segment.output_offset(position - segment.input_start))
+    }
+
+    pub(crate) fn output_to_input(&self, position: f64) -> Option<f64> {
+        let first = self.segments[0];
+        if position < first.output_start as f64 || !position.is_finite() {
+            return None;
+        }
+        let segment = self
+            .segments
+            .iter()
+            .rev()
+            .find(|segment| segment.output_start as f64 <= position)?;
+        Some(segment.input_start + segment.input_offset(position - segment.output_start as f64))
+    }
+
+    /// Takes over the boundaries that `from` did not report yet, for a timeline that follows it
+    /// after output frame `returned` of `from`. Their output frames are fixed, so all input of
+    /// `from` has to be produced.
+    pub(crate) fn carry_boundaries(&mut self, from: &Timeline, returned: u64) {
+        for mark in from.boundaries.iter() {
+            let output_position = mark.output_position.or_else(|| {
+                from.input_to_output(mark.input_position as f64)
+                    .map(|position| position.round() as u64)
+            });
+            if let Some(output_position) = output_position {
+                self.boundaries.push_back(Mark {
+                    input_position: mark.input_position,
+                    output_position: Some(output_position.saturating_sub(returned)),
+                });
+            }
+        }
+    }
+
+    /// Marks a boundary at input position `position`, which is not before earlier boundaries
+    pub(crate) fn mark_boundary(&mut self, position: u64) {
+        let last = self
+            .boundaries
+            .back()
+            .filter(|mark| mark.output_position.is_none());
+        if last.map(|mark| mark.input_position) != Some(position) {
+            self.boundaries.push_back(Mark {
+                input_position: position,
+                output_position: None,
+            });
+        }
+    }
+
+    /// Takes the marked boundaries the output got to. The output frame of a boundary depends on
+    /// the io ratio up to it, so it is only final once it is produced.
+    pub(crate) fn take_boundaries(&mut self) -> Vec<Boundary> {
+        let mut boundaries = Vec::new();
+        while let Some(mark) = self.boundaries.front() {
+            let output_position = match mark.output_position {
+                Some(output_position) => output_position,
+                // marks before the remembered segments had their output frame fixed
+                None => match self.input_to_output(mark.input_position as f64) {
+                    Some(output_position) => output_position.round() as u64,
+                    None => break,
+                },
+            };
+            if output_position > self.produced {
+                break;
+            }
+            boundaries.push(Boundary {
+                input_position: mark.input_position,
+                output_position,
+            });
+            self.boundaries.pop_front();
+        }
+        boundaries
+    }
+
+    fn last(&self) -> &Segment {
+        self.segments.back().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod timeline_tests {
+    use super::{Timeline, MAX_SEGMENTS};
+    use crate::soxr::Boundary;
+
+    #[test]
+    fn test_boundary_outlives_segments() {
+        let mut timeline = Timeline::new(1.0);
+        timeline.consumed = 1000;
+        timeline.mark_boundary(1000);
+        timeline.produced = 500;
+        // alternating ratios move one input frame per output frame on average, so the boundary
+        // lands on output frame 1000, long before the last change
+        for change in 0..2 * MAX_SEGMENTS {
+            timeline.set_io_ratio(if change % 2 == 0 { 1.5 } else { 0.5 }, 0);
+            timeline.produced += 1;
+        }
+        assert!(timeline.input_to_output(1000.0).is_none());
+        let boundary = Boundary {
+            input_position: 1000,
+            output_position: 1000,
+        };
+        assert_eq!(vec![boundary], timeline.take_boundaries());
+        assert!(timeline.take_boundaries().is_empty());
+    }
+}
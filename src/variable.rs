@@ -0,0 +1,471 @@
+//! Variable-rate resampling
+use crate::{
+    envelope::{RatioChange, RatioEnvelope},
+    error_handling::{Error, ErrorType, Result},
+    soxr::Soxr,
+    spec::{IOSpec, QualityFlags, QualityRecipe, QualitySpec, RuntimeSpec},
+};
+use std::collections::VecDeque;
+
+/// A resampler whose rate ratio can change while it runs, for varispeed playback or to follow a
+/// drifting clock. The ratio is libsoxr's io ratio `input_rate / output_rate`: the output rate is
+/// fixed and the io ratio sets how many input frames are consumed per output frame.
+///
+/// libsoxr needs to know the largest io ratio up front, and the
+/// [QualityFlags](crate::spec::QualityFlags)::VR flag is set automatically.
+///
+/// ```rust
+/// use libsoxr::VariableRateResampler;
+///
+/// // 44.1 kHz to 48 kHz, able to speed up to twice the nominal input rate
+/// let mut resampler =
+///     VariableRateResampler::create(44100.0, 48000.0, 2.0 * 44100.0 / 48000.0, 1, None, None, None)
+///         .unwrap();
+///
+/// // glide to 1.5 times the speed over 10 ms of output
+/// resampler.set_io_ratio_secs(1.5 * 44100.0 / 48000.0, 0.01).unwrap();
+///
+/// let source = [0.0f32; 4410];
+/// let mut target = [0.0f32; 4800];
+/// resampler.process(Some(&source), &mut target).unwrap();
+/// assert_eq!(1.5 * 44100.0 / 48000.0, resampler.io_ratio());
+/// ```
+#[derive(Debug)]
+pub struct VariableRateResampler {
+    soxr: Soxr,
+    output_rate: f64,
+    max_io_ratio: f64,
+    slew_from: f64,
+    io_ratio: f64,
+    slew_len: usize,
+    slewed: usize,
+    output_frames: u64,
+    changes: VecDeque<RatioChange>,
+}
+
+impl VariableRateResampler {
+    /// Creates a variable-rate resampler starting at `input_rate / output_rate` that can go up to
+    /// `max_io_ratio`. When `quality_spec` is `None` the default of [Soxr::create] is used:
+    /// [QualityRecipe::High] with [QualityFlags::ROLLOFF_SMALL]. The [QualityFlags::VR] flag is
+    /// always added. See [Soxr::create] for the other parameters.
+    pub fn create(
+        input_rate: f64,
+        output_rate: f64,
+        max_io_ratio: f64,
+        num_channels: u32,
+        io_spec: Option<&IOSpec>,
+        quality_spec: Option<&QualitySpec>,
+        runtime_spec: Option<&RuntimeSpec>,
+    ) -> Result<VariableRateResampler> {
+        let io_ratio = input_rate / output_rate;
+        check_io_ratio("VariableRateResampler::create", io_ratio, max_io_ratio)?;
+
+        let quality_spec = quality_spec
+            .cloned()
+            .unwrap_or_else(|| QualitySpec::new(&QualityRecipe::High, QualityFlags::ROLLOFF_SMALL))
+            .with_flags(QualityFlags::VR);
+        let mut soxr = Soxr::create(
+            max_io_ratio * output_rate,
+            output_rate,
+            num_channels,
+            io_spec,
+            Some(&quality_spec),
+            runtime_spec,
+        )?;
+        soxr.change_io_ratio(io_ratio, 0)?;
+
+        Ok(VariableRateResampler {
+            soxr,
+            output_rate,
+            max_io_ratio,
+            slew_from: io_ratio,
+            io_ratio,
+            slew_len: 0,
+            slewed: 0,
+            output_frames: 0,
+            changes: VecDeque::new(),
+        })
+    }
+
+    /// Largest io ratio this resampler was created for
+    pub fn max_io_ratio(&self) -> f64 {
+        self.max_io_ratio
+    }
+
+    /// Output rate this resampler was created with
+    pub fn output_rate(&self) -> f64 {
+        self.output_rate
+    }
+
+    /// Input rate that corresponds to the current io ratio
+    pub fn input_rate(&self) -> f64 {
+        self.io_ratio() * self.output_rate
+    }
+
+    /// Number of channels this resampler is configured for
+    pub fn num_channels(&self) -> u32 {
+        self.soxr.num_channels()
+    }
+
+    /// Effective io ratio at this point of the output, following an ongoing slew
+    pub fn io_ratio(&self) -> f64 {
+        if self.slewed >= self.slew_len {
+            return self.io_ratio;
+        }
+        let progress = self.slewed as f64 / self.slew_len as f64;
+        self.slew_from + (self.io_ratio - self.slew_from) * progress
+    }
+
+    /// Io ratio this resampler is heading for; equals [VariableRateResampler::io_ratio] when no
+    /// slew is going on
+    pub fn target_io_ratio(&self) -> f64 {
+        self.io_ratio
+    }
+
+    /// Changes the io ratio linearly over the next `slew_len` output frames, or immediately when
+    /// `slew_len` is zero. A slew that is still going on starts from the ratio reached so far. A
+    /// scheduled [RatioEnvelope] is cancelled.
+    pub fn set_io_ratio(&mut self, io_ratio: f64, slew_len: usize) -> Result<()> {
+        check_io_ratio(
+            "VariableRateResampler::set_io_ratio",
+            io_ratio,
+            self.max_io_ratio,
+        )?;
+        self.changes.clear();
+        self.change_io_ratio(io_ratio, slew_len)
+    }
+
+    /// Changes the io ratio linearly over the next `slew` seconds of output
+    pub fn set_io_ratio_secs(&mut self, io_ratio: f64, slew: f64) -> Result<()> {
+        let slew_len = (slew.max(0.0) * self.output_rate).round() as usize;
+        self.set_io_ratio(io_ratio, slew_len)
+    }
+
+    /// Schedules `envelope`, starting from the current output position and io ratio. Ratio
+    /// changes happen at the exact output frames of the envelope regardless of the sizes of the
+    /// buffers passed to [VariableRateResampler::process]. Replaces an envelope that was
+    /// scheduled before. Only interleaved datatypes are supported.
+    ///
+    /// ```rust
+    /// use libsoxr::{RatioEnvelope, VariableRateResampler};
+    ///
+    /// let mut resampler = VariableRateResampler::create(1.0, 1.0, 2.0, 1, None, None, None).unwrap();
+    /// resampler.schedule(&RatioEnvelope::new().linear_to(100, 2.0)).unwrap();
+    ///
+    /// let source = [0.0f32; 1000];
+    /// let mut target = [0.0f32; 150];
+    /// resampler.process(Some(&source), &mut target).unwrap();
+    /// assert_eq!(2.0, resampler.io_ratio());
+    /// ```
+    pub fn schedule(&mut self, envelope: &RatioEnvelope) -> Result<()> {
+        let (input_type, output_type) = self.soxr.datatypes();
+        if !input_type.is_interleaved() || !output_type.is_interleaved() {
+            return Err(Error::new(
+                Some("VariableRateResampler::schedule".into()),
+                ErrorType::ChangeError("only interleaved datatypes are supported".into()),
+            ));
+        }
+        self.changes = envelope.changes(self.output_frames, self.io_ratio(), self.max_io_ratio)?;
+        self.apply_due_changes()
+    }
+
+    /// Number of output frames produced since creation or the last [VariableRateResampler::clear]
+    pub fn output_position(&self) -> u64 {
+        self.output_frames
+    }
+
+    /// Resamples `Some(buf_in)` into `buf_out` like [Soxr::process] at the current io ratio. Call
+    /// with `None` as `buf_in` to flush at the end of input. The result contains number of input
+    /// frames used and number of output frames placed in `buf_out`.
+    ///
+    /// With a [RatioEnvelope] scheduled, the call is split at the output frames where the io
+    /// ratio changes.
+    pub fn process<I, O>(
+        &mut self,
+        buf_in: Option<&[I]>,
+        buf_out: &mut [O],
+    ) -> Result<(usize, usize)> {
+        if self.changes.is_empty() {
+            let (idone, odone) = self.soxr.process(buf_in, buf_out)?;
+            self.advance(odone);
+            return Ok((idone, odone));
+        }
+
+        let channels = self.soxr.num_channels() as usize;
+        let room = buf_out.len() / channels;
+        let (mut consumed, mut produced) = (0, 0);
+        while produced < room {
+            let limit = match self.changes.front() {
+                Some(change) => (room - produced).min((change.frame - self.output_frames) as usize),
+                None => room - produced,
+            };
+            let (idone, odone) = self.soxr.process(
+                buf_in.map(|buf_in| &buf_in[consumed * channels..]),
+                &mut buf_out[produced * channels..(produced + limit) * channels],
+            )?;
+            consumed += idone;
+            produced += odone;
+            self.advance(odone);
+            self.apply_due_changes()?;
+            if odone < limit {
+                break;
+            }
+        }
+        Ok((consumed, produced))
+    }
+
+    /// Query current delay in output samples
+    pub fn delay(&self) -> f64 {
+        self.soxr.delay()
+    }
+
+    /// Ready for fresh signal, keeping the target io ratio without slew. Cancels a scheduled
+    /// [RatioEnvelope] and resets the output position.
+    pub fn clear(&mut self) -> Result<()> {
+        self.soxr.clear()?;
+        self.changes.clear();
+        self.output_frames = 0;
+        self.change_io_ratio(self.io_ratio, 0)
+    }
+
+    /// Gets a reference to the underlying resampler. Its input rate is the maximum input rate.
+    pub fn soxr(&self) -> &Soxr {
+        &self.soxr
+    }
+
+    fn change_io_ratio(&mut self, io_ratio: f64, slew_len: usize) -> Result<()> {
+        self.soxr.change_io_ratio(io_ratio, slew_len)?;
+        self.slew_from = self.io_ratio();
+        self.io_ratio = io_ratio;
+        self.slew_len = slew_len;
+        self.slewed = 0;
+        Ok(())
+    }
+
+    fn advance(&mut self, odone: usize) {
+        self.output_frames += odone as u64;
+        self.slewed = (self.slewed + odone).min(self.slew_len);
+    }
+
+    fn apply_due_changes(&mut self) -> Result<()> {
+        while let Some(change) = self.changes.front().copied() {
+            if change.frame > self.output_frames {
+                break;
+            }
+            self.changes.pop_front();
+            self.change_io_ratio(change.io_ratio, change.slew_len)?;
+        }
+        Ok(())
+    }
+}
+
+fn check_io_ratio(func: &'static str, io_ratio: f64, max_io_ratio: f64) -> Result<()> {
+    if io_ratio > 0.0 && io_ratio <= max_io_ratio {
+        Ok(())
+    } else {
+        Err(Error::new(
+            Some(func.into()),
+            ErrorType::ChangeError(format!(
+                "io ratio {} outside of (0, {}]",
+                io_ratio, max_io_ratio
+            )),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod variable_tests {
+    use approx::assert_abs_diff_eq;
+
+    use super::VariableRateResampler;
+    use crate::envelope::RatioEnvelope;
+
+    fn resample(resampler: &mut VariableRateResampler, frames: usize) -> usize {
+        let input = vec![0.5f32; frames];
+        let mut output = vec![0.0f32; 4 * frames + 4096];
+        let (mut consumed, mut produced) = (0, 0);
+        while consumed < input.len() {
+            let (idone, odone) = resampler
+                .process(Some(&input[consumed..]), &mut output[produced..])
+                .unwrap();
+            consumed += idone;
+            produced += odone;
+        }
+        loop {
+            let (_, odone) = resampler
+                .process::<f32, _>(None, &mut output[produced..])
+                .unwrap();
+            if odone == 0 {
+                return produced;
+            }
+            produced += odone;
+        }
+    }
+
+    #[test]
+    fn test_vr_flag_set() {
+        let mut resampler =
+            VariableRateResampler::create(1.0, 1.0, 2.0, 1, None, None, None).unwrap();
+        resampler.set_io_ratio(0.5, 0).unwrap();
+        // half the io ratio doubles the output length
+        let produced = resample(&mut resampler, 10000);
+        assert!((produced as i64 - 20000).abs() < 100, "{}", produced);
+    }
+
+    #[test]
+    fn test_io_ratio_out_of_range() {
+        assert!(VariableRateResampler::create(3.0, 1.0, 2.0, 1, None, None, None).is_err());
+        let mut resampler =
+            VariableRateResampler::create(1.0, 1.0, 2.0, 1, None, None, None).unwrap();
+        assert!(resampler.set_io_ratio(2.5, 0).is_err());
+        assert!(resampler.set_io_ratio(0.0, 0).is_err());
+        assert_eq!(1.0, resampler.io_ratio());
+    }
+
+    #[test]
+    fn test_slew_in_seconds() {
+        let mut resampler =
+            VariableRateResampler::create(1000.0, 1000.0, 2.0, 1, None, None, None).unwrap();
+        // 0.5 seconds at 1000 Hz output is a slew of 500 frames
+        resampler.set_io_ratio_secs(2.0, 0.5).unwrap();
+        assert_eq!(1.0, resampler.io_ratio());
+        assert_eq!(2.0, resampler.target_io_ratio());
+
+        let input = [0.0f32; 1000];
+        let mut output = [0.0f32; 100];
+        let (_, odone) = resampler.process(Some(&input), &mut output).unwrap();
+        assert_abs_diff_eq!(1.0 + odone as f64 / 500.0, resampler.io_ratio());
+        assert_abs_diff_eq!(1000.0 * resampler.io_ratio(), resampler.input_rate());
+
+        let mut output = [0.0f32; 1000];
+        resampler.process(Some(&input), &mut output).unwrap();
+        resampler.process(Some(&input), &mut output).unwrap();
+        assert_eq!(2.0, resampler.io_ratio());
+    }
+
+    #[test]
+    fn test_clear_keeps_ratio() {
+        let mut resampler =
+            VariableRateResampler::create(1.0, 1.0, 2.0, 1, None, None, None).unwrap();
+        resampler.set_io_ratio(2.0, 0).unwrap();
+        resampler.clear().unwrap();
+        assert_eq!(2.0, resampler.io_ratio());
+        let produced = resample(&mut resampler, 10000);
+        assert!((produced as i64 - 5000).abs() < 100, "{}", produced);
+    }
+
+    fn envelope() -> RatioEnvelope {
+        RatioEnvelope::new()
+            .linear_to(4000, 2.0)
+            .exponential_to(8000, 0.5)
+            .linear_to(8000, 0.75)
+    }
+
+    // resamples a sine in output blocks of `block` frames
+    fn resample_in_blocks(block: usize) -> Vec<f32> {
+        let mut resampler =
+            VariableRateResampler::create(1.0, 1.0, 2.0, 1, None, None, None).unwrap();
+        resampler.schedule(&envelope()).unwrap();
+        let input: Vec<f32> = (0..12000).map(|n| (n as f32 * 0.01).sin()).collect();
+        let mut output = Vec::new();
+        let mut buffer = vec![0.0f32; block];
+        let mut consumed = 0;
+        loop {
+            let buf_in = if consumed < input.len() {
+                Some(&input[consumed..])
+            } else {
+                None
+            };
+            let (idone, odone) = resampler.process(buf_in, &mut buffer).unwrap();
+            consumed += idone;
+            output.extend_from_slice(&buffer[..odone]);
+            if buf_in.is_none() && odone == 0 {
+                return output;
+            }
+        }
+    }
+
+    #[test]
+    fn test_envelope_output_length() {
+        let mut resampler =
+            VariableRateResampler::create(1.0, 1.0, 2.0, 1, None, None, None).unwrap();
+        resampler.schedule(&envelope()).unwrap();
+        let produced = resample(&mut resampler, 12000);
+        assert_eq!(0.75, resampler.io_ratio());
+        assert_eq!(produced as u64, resampler.output_position());
+
+        // every output frame consumes the io ratio at that frame in input frames
+        let (mut expected, mut integrated) = (0, 0.0);
+        while integrated < 12000.0 {
+            integrated += envelope().io_ratio_at(0, 1.0, expected);
+            expected += 1;
+        }
+        assert!(
+            (produced as i64 - expected as i64).abs() <= 16,
+            "{} != {}",
+            produced,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_envelope_independent_of_blocks() {
+        let reference = resample_in_blocks(100_000);
+        for block in [1, 7, 1000].iter() {
+            let output = resample_in_blocks(*block);
+            assert_eq!(reference.len(), output.len(), "block size {}", block);
+            for (expected, actual) in reference.iter().zip(output.iter()) {
+                assert_abs_diff_eq!(expected, actual, epsilon = 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn test_set_io_ratio_cancels_envelope() {
+        let mut resampler =
+            VariableRateResampler::create(1.0, 1.0, 2.0, 1, None, None, None).unwrap();
+        resampler.schedule(&envelope()).unwrap();
+        resampler.set_io_ratio(0.5, 0).unwrap();
+        resample(&mut resampler, 1000);
+        assert_eq!(0.5, resampler.io_ratio());
+    }
+
+    #[test]
+    fn test_positions_follow_ratio_changes() {
+        let mut resampler =
+            VariableRateResampler::create(1.0, 1.0, 2.0, 1, None, None, None).unwrap();
+        let input = vec![0.0f32; 10000];
+        let mut output = vec![0.0f32; 500];
+        let mut consumed = 0;
+        for (io_ratio, slew_len) in [(1.5, 0), (0.5, 700), (1.25, 100), (0.75, 0)].iter() {
+            resampler.set_io_ratio(*io_ratio, *slew_len).unwrap();
+            for _ in 0..3 {
+                let (idone, _) = resampler
+                    .process(Some(&input[consumed..]), &mut output)
+                    .unwrap();
+                consumed += idone;
+            }
+        }
+        let soxr = resampler.soxr();
+        assert_eq!(consumed as u64, soxr.input_position());
+        assert_eq!(6000, soxr.output_position());
+        // the frames of input that are not output yet are in the delay
+        let position = soxr.output_to_input_position(6000.0).unwrap();
+        assert_abs_diff_eq!(
+            resampler.delay() * resampler.io_ratio(),
+            consumed as f64 - position,
+            epsilon = 1e-6
+        );
+        for output_position in [0.0, 250.5, 1500.0, 2100.0, 2599.25, 4000.0, 5999.0].iter() {
+            let input_position = soxr.output_to_input_position(*output_position).unwrap();
+            let back = soxr.input_to_output_position(input_position).unwrap();
+            assert_abs_diff_eq!(*output_position, back, epsilon = 1e-6);
+        }
+        // 1500 frames at 1.5 take 2250 input frames
+        assert_abs_diff_eq!(
+            2250.0,
+            soxr.output_to_input_position(1500.0).unwrap(),
+            epsilon = 1e-9
+        );
+    }
+}
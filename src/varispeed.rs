@@ -0,0 +1,275 @@
+//! Varispeed playback
+use crate::{
+    envelope::RatioEnvelope,
+    error_handling::{Error, ErrorType, Result},
+    spec::{IOSpec, QualitySpec, RuntimeSpec},
+    variable::VariableRateResampler,
+};
+
+/// Plays a source faster or slower, like a tape machine or a turntable: speed and pitch change
+/// together. Built on a [VariableRateResampler] whose io ratio is the speed times the nominal
+/// ratio `input_rate / output_rate`.
+///
+/// The speed goes from [Varispeed::MIN_SPEED] to [Varispeed::MAX_SPEED] and can be set as a
+/// factor, in semitones or in cents. Changes can glide linearly, as a pitch fader does, or
+/// exponentially, so that the pitch moves evenly, as in a tape stop. The playhead is tracked in
+/// source time.
+///
+/// ```rust
+/// use libsoxr::Varispeed;
+///
+/// let mut varispeed = Varispeed::create(44100.0, 44100.0, 2, None, None, None).unwrap();
+/// // a tape stop: down two octaves in half a second
+/// varispeed.glide_to_semitones(-24.0, 0.5).unwrap();
+///
+/// let source = [0.0f32; 2 * 4410];
+/// let mut target = [0.0f32; 2 * 4410];
+/// varispeed.process(Some(&source), &mut target).unwrap();
+/// assert!(varispeed.speed() < 1.0);
+/// ```
+#[derive(Debug)]
+pub struct Varispeed {
+    resampler: VariableRateResampler,
+    input_rate: f64,
+    nominal_io_ratio: f64,
+    consumed: u64,
+}
+
+impl Varispeed {
+    /// Slowest speed
+    pub const MIN_SPEED: f64 = 0.25;
+    /// Fastest speed
+    pub const MAX_SPEED: f64 = 4.0;
+
+    /// Creates a varispeed resampler from `input_rate` to `output_rate` playing at normal speed.
+    /// See [VariableRateResampler::create] for the other parameters.
+    pub fn create(
+        input_rate: f64,
+        output_rate: f64,
+        num_channels: u32,
+        io_spec: Option<&IOSpec>,
+        quality_spec: Option<&QualitySpec>,
+        runtime_spec: Option<&RuntimeSpec>,
+    ) -> Result<Varispeed> {
+        let nominal_io_ratio = input_rate / output_rate;
+        let resampler = VariableRateResampler::create(
+            input_rate,
+            output_rate,
+            Varispeed::MAX_SPEED * nominal_io_ratio,
+            num_channels,
+            io_spec,
+            quality_spec,
+            runtime_spec,
+        )?;
+        Ok(Varispeed {
+            resampler,
+            input_rate,
+            nominal_io_ratio,
+            consumed: 0,
+        })
+    }
+
+    /// Speed factor that corresponds to `semitones`
+    pub fn semitones_to_speed(semitones: f64) -> f64 {
+        (semitones / 12.0).exp2()
+    }
+
+    /// Semitones that correspond to speed factor `speed`
+    pub fn speed_to_semitones(speed: f64) -> f64 {
+        12.0 * speed.log2()
+    }
+
+    /// Effective speed at this point of the output, following an ongoing glide
+    pub fn speed(&self) -> f64 {
+        self.resampler.io_ratio() / self.nominal_io_ratio
+    }
+
+    /// Speed this varispeed is heading for
+    pub fn target_speed(&self) -> f64 {
+        self.resampler.target_io_ratio() / self.nominal_io_ratio
+    }
+
+    /// Effective speed in semitones
+    pub fn semitones(&self) -> f64 {
+        Varispeed::speed_to_semitones(self.speed())
+    }
+
+    /// Effective speed in cents
+    pub fn cents(&self) -> f64 {
+        100.0 * self.semitones()
+    }
+
+    /// Changes the speed linearly over the next `glide` seconds of output, or immediately when
+    /// `glide` is zero
+    pub fn set_speed(&mut self, speed: f64, glide: f64) -> Result<()> {
+        check_speed("Varispeed::set_speed", speed)?;
+        self.resampler
+            .set_io_ratio_secs(speed * self.nominal_io_ratio, glide)
+    }
+
+    /// Changes the speed to `semitones` linearly over the next `glide` seconds of output
+    pub fn set_semitones(&mut self, semitones: f64, glide: f64) -> Result<()> {
+        self.set_speed(Varispeed::semitones_to_speed(semitones), glide)
+    }
+
+    /// Changes the speed to `cents` linearly over the next `glide` seconds of output
+    pub fn set_cents(&mut self, cents: f64, glide: f64) -> Result<()> {
+        self.set_semitones(cents / 100.0, glide)
+    }
+
+    /// Changes the speed over the next `glide` seconds of output by the same factor every
+    /// frame, so that the pitch moves by the same number of cents every frame
+    pub fn glide_to(&mut self, speed: f64, glide: f64) -> Result<()> {
+        check_speed("Varispeed::glide_to", speed)?;
+        let frames = (glide.max(0.0) * self.resampler.output_rate()).round() as u64;
+        let envelope = RatioEnvelope::new().exponential_to(
+            self.resampler.output_position() + frames,
+            speed * self.nominal_io_ratio,
+        );
+        self.resampler.schedule(&envelope)
+    }
+
+    /// Glides to `semitones` like [Varispeed::glide_to]
+    pub fn glide_to_semitones(&mut self, semitones: f64, glide: f64) -> Result<()> {
+        self.glide_to(Varispeed::semitones_to_speed(semitones), glide)
+    }
+
+    /// Playhead in source frames: the source position of the next output frame
+    pub fn source_position(&self) -> f64 {
+        self.consumed as f64 - self.resampler.delay() * self.resampler.io_ratio()
+    }
+
+    /// Playhead in seconds of source time
+    pub fn source_time(&self) -> f64 {
+        self.source_position() / self.input_rate
+    }
+
+    /// Resamples `Some(buf_in)` into `buf_out` at the current speed. Call with `None` as
+    /// `buf_in` to flush at the end of input. The result contains number of input frames used
+    /// and number of output frames placed in `buf_out`.
+    pub fn process<I, O>(
+        &mut self,
+        buf_in: Option<&[I]>,
+        buf_out: &mut [O],
+    ) -> Result<(usize, usize)> {
+        let (idone, odone) = self.resampler.process(buf_in, buf_out)?;
+        self.consumed += idone as u64;
+        Ok((idone, odone))
+    }
+
+    /// Ready for fresh signal at the target speed, with the playhead back at the start
+    pub fn clear(&mut self) -> Result<()> {
+        self.consumed = 0;
+        self.resampler.clear()
+    }
+
+    /// Gets a reference to the underlying resampler
+    pub fn resampler(&self) -> &VariableRateResampler {
+        &self.resampler
+    }
+}
+
+fn check_speed(func: &'static str, speed: f64) -> Result<()> {
+    if !(Varispeed::MIN_SPEED..=Varispeed::MAX_SPEED).contains(&speed) {
+        return Err(Error::new(
+            Some(func.into()),
+            ErrorType::ChangeError(format!(
+                "speed {} outside of [{}, {}]",
+                speed,
+                Varispeed::MIN_SPEED,
+                Varispeed::MAX_SPEED
+            )),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod varispeed_tests {
+    use approx::assert_abs_diff_eq;
+
+    use super::Varispeed;
+
+    fn varispeed() -> Varispeed {
+        Varispeed::create(8000.0, 8000.0, 1, None, None, None).unwrap()
+    }
+
+    #[test]
+    fn test_musical_units() {
+        let mut varispeed = varispeed();
+        varispeed.set_semitones(12.0, 0.0).unwrap();
+        assert_abs_diff_eq!(2.0, varispeed.speed(), epsilon = 1e-12);
+        varispeed.set_cents(-1200.0, 0.0).unwrap();
+        assert_abs_diff_eq!(0.5, varispeed.speed(), epsilon = 1e-12);
+        assert_abs_diff_eq!(-1200.0, varispeed.cents(), epsilon = 1e-9);
+        varispeed.set_semitones(7.0, 0.0).unwrap();
+        assert_abs_diff_eq!(7.0, varispeed.semitones(), epsilon = 1e-9);
+    }
+
+    #[test]
+    fn test_speed_range() {
+        let mut varispeed = varispeed();
+        assert!(varispeed.set_speed(0.2, 0.0).is_err());
+        assert!(varispeed.set_speed(4.5, 0.0).is_err());
+        assert!(varispeed.set_semitones(-25.0, 0.0).is_err());
+        varispeed.set_speed(0.25, 0.0).unwrap();
+        varispeed.set_speed(4.0, 0.0).unwrap();
+    }
+
+    #[test]
+    fn test_playhead_at_double_speed() {
+        let mut varispeed = varispeed();
+        varispeed.set_speed(2.0, 0.0).unwrap();
+        let source = vec![0.0f32; 16000];
+        let mut target = vec![0.0f32; 4000];
+        let (_, odone) = varispeed.process(Some(&source), &mut target).unwrap();
+        assert_abs_diff_eq!(
+            2.0 * odone as f64,
+            varispeed.source_position(),
+            epsilon = 1.0
+        );
+        assert_abs_diff_eq!(
+            varispeed.source_position() / 8000.0,
+            varispeed.source_time()
+        );
+
+        varispeed.clear().unwrap();
+        assert_eq!(0.0, varispeed.source_position());
+        assert_eq!(2.0, varispeed.speed());
+    }
+
+    #[test]
+    fn test_exponential_glide() {
+        let mut varispeed = varispeed();
+        // two octaves down in one second
+        varispeed.glide_to(0.25, 1.0).unwrap();
+        let source = vec![0.0f32; 16000];
+        let mut target = vec![0.0f32; 80];
+        let mut previous_cents = 0.0;
+        for block in 1..=100 {
+            varispeed.process(Some(&source), &mut target).unwrap();
+            let cents = varispeed.cents();
+            assert!(cents < previous_cents);
+            // evenly, give or take the pieces the exponential is approximated with
+            assert_abs_diff_eq!(-2400.0 * block as f64 / 100.0, cents, epsilon = 10.0);
+            previous_cents = cents;
+        }
+        // the playhead follows the integral of the speed: (1 - 0.25) / ln(4) seconds
+        let expected = 8000.0 * 0.75 / 4.0f64.ln();
+        assert_abs_diff_eq!(expected, varispeed.source_position(), epsilon = 10.0);
+        assert_eq!(0.25, varispeed.target_speed());
+    }
+
+    #[test]
+    fn test_linear_glide() {
+        let mut varispeed = varispeed();
+        varispeed.set_speed(3.0, 0.5).unwrap();
+        let source = vec![0.0f32; 16000];
+        let mut target = vec![0.0f32; 2000];
+        varispeed.process(Some(&source), &mut target).unwrap();
+        assert_abs_diff_eq!(2.0, varispeed.speed(), epsilon = 1e-9);
+        assert_abs_diff_eq!(3.0, varispeed.target_speed());
+        // halfway the glide from 1 to 3 the playhead is at 1.5 times the output position
+        assert_abs_diff_eq!(3000.0, varispeed.source_position(), epsilon = 1.0);
+    }
+}
@@ -33,3 +33,14 @@ pub fn _from_alloc(func: &'static str, s: *const c_char) -> Result<String> {
     }
     Ok(rust_string)
 }
+
+/// Views a buffer of `u64` words as bytes. Buffers for raw sample bytes are allocated as words so
+/// that libsoxr gets correctly aligned samples of any datatype.
+pub fn as_bytes(words: &[u64]) -> &[u8] {
+    unsafe { ::std::slice::from_raw_parts(words.as_ptr() as *const u8, words.len() * 8) }
+}
+
+/// Views a buffer of `u64` words as mutable bytes, see [as_bytes]
+pub fn as_bytes_mut(words: &mut [u64]) -> &mut [u8] {
+    unsafe { ::std::slice::from_raw_parts_mut(words.as_mut_ptr() as *mut u8, words.len() * 8) }
+}
@@ -40,10 +40,10 @@ fn test_input_fn(state: &mut MyState, buf: &mut [f32], req_samples: usize) -> Re
 #[test]
 fn test_data_fn() {
     println!("Creating Soxr");
-    let mut soxr = Soxr::create(1.0, 2.0, 2, None, None, None).unwrap();
+    let soxr = Soxr::create(1.0, 2.0, 2, None, None, None).unwrap();
 
     // create state for input_fn
-    let mut state = MyState {
+    let state = MyState {
         check: "libsoxr",
         command: 0,
         value: 2.3,
@@ -52,7 +52,7 @@ fn test_data_fn() {
     };
 
     println!("Setting input function");
-    assert!(soxr.set_input(test_input_fn, Some(&mut state), 75).is_ok());
+    let mut soxr = soxr.into_pull(test_input_fn, state, 75).unwrap();
 
     // create buffer for resampled data
     let mut data = [1.1f32; 300];
@@ -61,7 +61,7 @@ fn test_data_fn() {
     assert_abs_diff_ne!(data[0], 1.1);
 
     // tell test_input_fn to return end-of-input (0)
-    state.command = 1;
+    soxr.input_state_mut::<MyState>().unwrap().command = 1;
     // other buffer for resampled data
     let mut buffer = [1.1f32; 200];
     println!("Second");
@@ -77,10 +77,10 @@ fn test_data_fn() {
 fn test_with_custom_specs() {
     println!("Creating Soxr");
     let spec = QualitySpec::new(&QualityRecipe::VeryHigh, QualityFlags::HI_PREC_CLOCK);
-    let mut soxr = Soxr::create(100.0, 200.0, 2, None, Some(&spec), None).unwrap();
+    let soxr = Soxr::create(100.0, 200.0, 2, None, Some(&spec), None).unwrap();
 
     // create state for input_fn
-    let mut state = MyState {
+    let state = MyState {
         check: "libsoxr",
         command: 0,
         samples_created: 0,
@@ -89,18 +89,18 @@ fn test_with_custom_specs() {
     };
 
     println!("Setting input function");
-    assert!(soxr.set_input(test_input_fn, Some(&mut state), 500).is_ok());
+    let mut soxr = soxr.into_pull(test_input_fn, state, 500).unwrap();
 
     // create buffer for resampled data
     let mut data = [1.1f32; 2000];
     println!("First call");
     assert_eq!(1000, soxr.output(&mut data, 1000));
     println!("First call done");
-    assert_eq!(1000, state.samples_created);
+    assert_eq!(1000, soxr.input_state::<MyState>().unwrap().samples_created);
     assert_abs_diff_ne!(data[0], 1.1);
 
     // tell test_input_fn to return end-of-input (0)
-    state.command = 1;
+    soxr.input_state_mut::<MyState>().unwrap().command = 1;
     // other buffer for resampled data
     let mut buffer = [1.1f32; 200];
     println!("Second");
@@ -119,7 +119,7 @@ fn test_with_two_soxrs() {
     soxr1.clear().unwrap();
 
     // create state for input_fn
-    let mut state = MyState {
+    let state = || MyState {
         check: "libsoxr",
         command: 0,
         value: 2.3,
@@ -128,18 +128,14 @@ fn test_with_two_soxrs() {
     };
 
     println!("Setting input function 1");
-    assert!(soxr1
-        .set_input(test_input_fn, Some(&mut state), 2500)
-        .is_ok());
+    let mut soxr1 = soxr1.into_pull(test_input_fn, state(), 2500).unwrap();
 
     println!("Creating Soxr 2");
     let mut soxr2 = Soxr::create(1.0, 2.0, 2, None, None, None).unwrap();
     soxr2.clear().unwrap();
 
     println!("Setting input function 2");
-    assert!(soxr2
-        .set_input(test_input_fn, Some(&mut state), 2500)
-        .is_ok());
+    let mut soxr2 = soxr2.into_pull(test_input_fn, state(), 2500).unwrap();
 
     // create buffer for resampled data; 5000 * 2 channels
     let mut data1 = [1.1f32; 10000];
@@ -165,7 +161,8 @@ fn test_with_two_soxrs() {
             break;
         }
         // tell test_input_fn to return end-of-input (0)
-        state.command = 1;
+        soxr1.input_state_mut::<MyState>().unwrap().command = 1;
+        soxr2.input_state_mut::<MyState>().unwrap().command = 1;
     }
     println!();
 }